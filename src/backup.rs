@@ -1,4 +1,3 @@
-use flate2::read::GzDecoder;
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
@@ -6,32 +5,254 @@ use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs;
 use std::io;
-use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use threadpool::ThreadPool;
-
+use std::io::{BufRead, Write};
+use std::ops::ControlFlow;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use time::macros::format_description;
+use time::PrimitiveDateTime;
+
+use crate::checksum::{calc_digest_buffered, ChecksumAlgo, DEFAULT_BUFFER_SIZE};
+use crate::client::CloneOptions;
+use crate::compression::{Compression, CompressionSpec};
 use crate::manifest;
+use crate::storage::StorageBackend;
+
+/// Name of the checksum file optionally written into cloned backups
+pub const CHECKSUM_FILE: &str = "bdup.checksums";
 
-enum VerifyResult {
+/// Names of the manifest in the order they are looked for: burp compresses it by default, but
+/// can be configured to leave it uncompressed
+pub const MANIFEST_FILES: [&str; 2] = ["manifest.gz", "manifest"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyResult {
     Ok,
-    FilesizeMismatch(usize),
+    FilesizeMismatch(u64),
     ChecksumMismatch(String),
+    Missing,
+    /// The stored data cannot be decompressed. Holds the offset in the compressed file at which
+    /// decompression failed.
+    CorruptCompression(u64),
     Error(String),
 }
 
+impl fmt::Display for VerifyResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyResult::Ok => write!(f, "ok"),
+            VerifyResult::FilesizeMismatch(size) => write!(f, "size mismatch, read {} bytes", size),
+            VerifyResult::ChecksumMismatch(md5) => write!(f, "checksum mismatch, computed {}", md5),
+            VerifyResult::Missing => write!(f, "missing"),
+            VerifyResult::CorruptCompression(offset) => {
+                write!(f, "corrupt compression at byte {}", offset)
+            }
+            VerifyResult::Error(err) => write!(f, "error: {}", err),
+        }
+    }
+}
+
+/// Settings and callbacks for `Backup::verify_with_options`. Both callbacks run on the thread
+/// collecting the results of all workers, so they should return quickly.
+pub struct VerifyOptions<'a> {
+    pub worker_threads: usize,
+    pub algo: ChecksumAlgo,
+    /// Called for each data file that did not verify
+    pub on_corruption: &'a dyn Fn(&Path, &VerifyResult),
+    /// Called with the number of verified files and the total number of files, which is 0 if it
+    /// is not known
+    pub on_progress: &'a dyn Fn(u64, u64),
+    /// Size of the reads of each worker thread
+    pub buffer_size: usize,
+}
+
+impl VerifyOptions<'_> {
+    /// MD5 verification without callbacks
+    pub fn new(worker_threads: usize) -> Self {
+        Self {
+            worker_threads,
+            algo: ChecksumAlgo::Md5,
+            on_corruption: &|_, _| {},
+            on_progress: &|_, _| {},
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+/// Opens a file of a backup, given its path relative to the backup directory
+pub type ReadFileFn<'a> = dyn Fn(&Path) -> Result<Box<dyn io::Read>, Box<dyn Error>> + Sync + 'a;
+
+/// Like `ReadFileFn`, but only called by the thread that uses it
+pub type SequentialReadFileFn<'a> = dyn Fn(&Path) -> Result<Box<dyn io::Read>, Box<dyn Error>> + 'a;
+
+/// Transfers a file of the source backup (path relative to the backup directory) to the given
+/// destination path and sends the result. Data files come with their manifest entry.
+pub type FetchFn<'a> =
+    dyn Fn(&OsStr, &Path, Option<&DataFileInfo>, &SyncSender<TransferResult>) + 'a;
+
+/// Settings for `Backup::restore_to`
+#[derive(Debug, Default)]
+pub struct RestoreOptions {
+    /// Apply mode, modification time and ownership stored in the manifest
+    pub apply_metadata: bool,
+    /// Write regular files compressed with this codec, with its extension appended to their
+    /// names (e.g. "a.txt.gz"). Files are written uncompressed if unset.
+    pub compression: Option<CompressionSpec>,
+}
+
+/// Result of verifying a whole backup
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of data files that matched their checksum
+    pub ok: u64,
+    /// Data files that did not verify, with the reason
+    pub failed: Vec<(PathBuf, VerifyResult)>,
+    /// Files in the data directory that are not referenced by the manifest
+    pub unwanted: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    /// Number of data files referenced by the manifest that do not exist
+    pub fn missing(&self) -> usize {
+        self.failed
+            .iter()
+            .filter(|(_, result)| *result == VerifyResult::Missing)
+            .count()
+    }
+
+    /// Paths of all data files that did not verify
+    pub fn failed_paths(&self) -> Vec<&Path> {
+        self.failed.iter().map(|(path, _)| path.as_path()).collect()
+    }
+
+    /// Failures and unwanted files as one line per path, sorted by path. The output is stable
+    /// across runs, so listings of different runs can be compared with `diff`.
+    pub fn sorted_listing(&self) -> Vec<String> {
+        let mut entries = self
+            .failed
+            .iter()
+            .map(|(path, result)| (path, result.to_string()))
+            .chain(
+                self.unwanted
+                    .iter()
+                    .map(|path| (path, "unwanted".to_string())),
+            )
+            .collect::<Vec<(&PathBuf, String)>>();
+        entries.sort();
+        entries
+            .into_iter()
+            .map(|(path, reason)| format!("{}: {}", path.display(), reason))
+            .collect()
+    }
+}
+
+/// Numbers of a finished `Backup::clone_from`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CloneStats {
+    /// Metadata and data files of the backup
+    pub files_total: u64,
+    /// Data files that were unchanged since the base backup and not transferred
+    pub files_from_base: u64,
+    /// Files that could not be transferred. The backup is not finished if there are any.
+    pub files_failed: u64,
+    /// Size of the transferred files, as stored (i.e. compressed)
+    pub bytes_transferred: u64,
+    /// Size of the files taken from the base backup, as stored
+    pub bytes_deduplicated: u64,
+    /// Cloning was stopped by `CloneOptions::cancel` and the backup is not finished
+    pub interrupted: bool,
+}
+
+impl CloneStats {
+    /// Share of the backup's stored size that was taken from the base backup, in percent
+    pub fn deduplicated_percent(&self) -> f64 {
+        let total = self.bytes_deduplicated + self.bytes_transferred;
+        if total == 0 {
+            return 0.0;
+        }
+        self.bytes_deduplicated as f64 * 100.0 / total as f64
+    }
+}
+
+/// Number and size of data files by size class, see `Backup::size_histogram`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// Number of files and their total size for each of `SizeHistogram::LABELS`
+    pub buckets: [(u64, u64); 5],
+}
+
+impl SizeHistogram {
+    /// Upper bounds (exclusive) of all but the last bucket
+    pub const LIMITS: [u64; 4] = [1 << 10, 1 << 20, 1 << 30, 1 << 40];
+    pub const LABELS: [&'static str; 5] = ["< 1 KiB", "< 1 MiB", "< 1 GiB", "< 1 TiB", ">= 1 TiB"];
+
+    pub fn add(&mut self, size: u64) {
+        let index = Self::LIMITS
+            .iter()
+            .position(|limit| size < *limit)
+            .unwrap_or(Self::LIMITS.len());
+        self.buckets[index].0 += 1;
+        self.buckets[index].1 += size;
+    }
+
+    pub fn files(&self) -> u64 {
+        self.buckets.iter().map(|(files, _)| files).sum()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.buckets.iter().map(|(_, bytes)| bytes).sum()
+    }
+}
+
+/// Data files that differ between two backups, see `Backup::diff`. Paths are relative to the
+/// data directory and sorted.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BackupDiff {
+    /// Files only in the newer backup
+    pub added: Vec<PathBuf>,
+    /// Files only in the older backup
+    pub removed: Vec<PathBuf>,
+    /// Files in both backups with different checksums
+    pub changed: Vec<PathBuf>,
+    /// Uncompressed size of the added and changed files in the newer backup
+    pub churn_bytes: u64,
+}
+
+impl BackupDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for BackupDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} modified, {} churn",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len(),
+            format_bytes(self.churn_bytes)
+        )
+    }
+}
+
 struct VerifyFileResult {
     path: PathBuf,
-    size: usize,
+    size: u64,
     md5: String,
     result: VerifyResult,
 }
 
-fn format_bytes(bytes: u64) -> String {
+/// Formats `bytes` with a binary prefix, e.g. "1.50 MiB"
+pub fn format_bytes(bytes: u64) -> String {
     let prefix = ["", "ki", "Mi", "Gi", "Ti", "Pi", "Ei", "Zi", "Yi"];
     let mut index = 0;
     let mut num: f64 = bytes as f64;
-    while num > 1000.0 {
+    while num >= 1024.0 && index < prefix.len() - 1 {
         num /= 1024.0;
         index += 1;
     }
@@ -80,14 +301,90 @@ impl fmt::Display for CopyThreadPanicedError {
 }
 impl Error for CopyThreadPanicedError {}
 
+/// Checksum, size and mode of a data file, as recorded in the manifest
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DataFileInfo {
+    pub md5: String,
+    pub size: u64,
+    /// Unix mode of the original file, 0 if unknown
+    pub mode: u32,
+}
+
+impl DataFileInfo {
+    /// Decompresses the data file at `path` and checks its size and md5 sum against this info
+    pub fn matches_file(&self, path: &Path) -> io::Result<bool> {
+        let input = Box::new(fs::File::open(path)?);
+        let (ok, _, _) = verify_file_digest(
+            input,
+            self.size,
+            &self.md5,
+            ChecksumAlgo::Md5,
+            DEFAULT_BUFFER_SIZE,
+        )?;
+        Ok(ok)
+    }
+}
+
+impl From<&manifest::ManifestEntry> for DataFileInfo {
+    fn from(entry: &manifest::ManifestEntry) -> Self {
+        let (md5, size) = entry
+            .data
+            .as_ref()
+            .map_or((String::new(), 0), |data| (data.md5.to_owned(), data.size));
+        Self {
+            md5,
+            size,
+            mode: entry.stat.as_ref().map_or(0, |stat| stat.mode),
+        }
+    }
+}
+
+/// Logical view of a file in a backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    pub path: PathBuf,
+    pub file_type: manifest::FileType,
+    /// Size of the original file, 0 if unknown
+    pub size: u64,
+    /// Unix mode of the original file, 0 if unknown
+    pub mode: u32,
+}
+
+impl From<manifest::ManifestEntry> for FileInfo {
+    fn from(entry: manifest::ManifestEntry) -> Self {
+        let size = match (&entry.stat, &entry.data) {
+            (Some(stat), _) => stat.size,
+            (None, Some(data)) => data.size,
+            (None, None) => 0,
+        };
+        Self {
+            file_type: *entry.file_type(),
+            mode: entry.stat.as_ref().map_or(0, |stat| stat.mode),
+            size,
+            path: entry.path,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Backup {
     base_url: String,
     name: String,
     pub id: u64,
     timestamp: String,
-    checksums: HashMap<PathBuf, String>,
+    checksums: HashMap<PathBuf, DataFileInfo>,
     is_local: bool,
+    /// Size as reported by a remote directory listing
+    listed_size: Option<u64>,
+    /// Modification time as reported by a remote directory listing
+    listed_mtime: Option<String>,
+    /// Cached result of `total_size`
+    total_size: Option<u64>,
+    /// Data files skipped by `CloneOptions::exclude` while cloning
+    excluded: HashSet<PathBuf>,
+    /// Only part of the manifest was cloned because of `CloneOptions::include`, so unwanted
+    /// files are also searched below the top-level data directories
+    partial: bool,
 }
 
 impl Backup {
@@ -100,10 +397,42 @@ impl Backup {
             timestamp,
             checksums: HashMap::new(),
             is_local,
+            listed_size: None,
+            listed_mtime: None,
+            total_size: None,
+            excluded: HashSet::new(),
+            partial: false,
         })
     }
 
+    /// Stores size and modification time from the server's directory listing. The size is
+    /// `None` if the server does not report it (e.g. for directories).
+    pub fn set_listing_metadata(&mut self, size: Option<u64>, mtime: Option<String>) {
+        self.listed_size = size;
+        self.listed_mtime = mtime;
+    }
+
+    /// Size reported by the server's directory listing, if any
+    pub fn listed_size(&self) -> Option<u64> {
+        self.listed_size
+    }
+
+    /// Modification time reported by the server's directory listing, if any
+    pub fn listed_mtime(&self) -> Option<&str> {
+        self.listed_mtime.as_deref()
+    }
+
+    /// Creates a local backup from its directory. Symbolic links (like burp's `current`) are
+    /// resolved, so the backup they point to is used.
     pub fn from_path(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let resolved;
+        let path = match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                resolved = fs::canonicalize(path)?;
+                &resolved
+            }
+            _ => path,
+        };
         let parent = path.parent().ok_or_else(|| InvalidNameError {
             message: format!("Path {:?} has no parent", path),
         })?;
@@ -128,11 +457,31 @@ impl Backup {
         PathBuf::from(&self.base_url).join(&self.name)
     }
 
+    /// Directory or URL of the client the backup was found in
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The backup's timestamp as written in its name
+    pub fn timestamp(&self) -> &str {
+        &self.timestamp
+    }
+
+    /// The backup's timestamp from its name (local time of the burp server), `None` if it does
+    /// not match burp's "YYYY-MM-DD hh:mm:ss" format
+    pub fn datetime(&self) -> Option<PrimitiveDateTime> {
+        PrimitiveDateTime::parse(
+            &self.timestamp,
+            format_description!("[year]-[month]-[day] [hour]:[minute]:[second]"),
+        )
+        .ok()
+    }
+
     pub fn is_local_backup(&self) -> bool {
         self.is_local
     }
 
-    pub fn delete(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn delete(&mut self, storage: &dyn StorageBackend) -> Result<(), Box<dyn Error>> {
         if !self.is_local {
             return Err(Box::new(NotLocalError {
                 message: format!(
@@ -143,39 +492,90 @@ impl Backup {
         }
         let path = self.path();
         log::debug!("Removing backup at {}", path.display());
-        let status = Command::new("btrfs")
-            .arg("subvolume")
-            .arg("delete")
-            .arg(path)
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .status()?;
-        assert!(status.success());
+        storage.delete(&path)?;
         self.checksums = HashMap::new();
         Ok(())
     }
 
+    /// Files of a backup besides the manifest (see `MANIFEST_FILES`) and the data directory
     #[inline]
-    fn metadata_files() -> &'static [&'static str]
+    pub(crate) fn metadata_files() -> &'static [&'static str]
     where
         Self: Sized,
     {
-        &[
-            "manifest.gz",
-            "log.gz",
-            "backup_stats",
-            "timestamp",
-            "incexc",
-        ]
+        &["log.gz", "backup_stats", "timestamp", "incexc"]
     }
 
-    fn manifest_reader(
-        &self,
-    ) -> Result<io::BufReader<flate2::read::GzDecoder<fs::File>>, Box<dyn Error>> {
+    /// Path of the manifest. Uncompressed manifests are used if there is no `manifest.gz`.
+    fn manifest_path(&self) -> PathBuf {
+        MANIFEST_FILES
+            .iter()
+            .map(|name| self.file_path(None, OsStr::new(name)))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| self.file_path(None, OsStr::new(MANIFEST_FILES[0])))
+    }
+
+    /// Compression of this backup's manifest, which burp also uses for the data files
+    pub fn compression(&self) -> io::Result<Compression> {
+        let mut manifest = io::BufReader::new(fs::File::open(self.manifest_path())?);
+        Ok(Compression::detect(manifest.fill_buf()?))
+    }
+
+    /// Opens the manifest, decompressing it according to its magic bytes
+    fn manifest_reader(&self) -> Result<Box<dyn BufRead>, Box<dyn Error>> {
         // TODO fetch
-        let manifest = fs::File::open(self.file_path(None, &OsString::from("manifest.gz")))?;
-        let gz = GzDecoder::new(manifest);
-        Ok(io::BufReader::new(gz))
+        Ok(decompress_manifest(Box::new(fs::File::open(
+            self.manifest_path(),
+        )?))?)
+    }
+
+    /// Opens the manifest and iterates over its parsed entries. Iteration stops after the first
+    /// error.
+    pub fn entries(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<manifest::ManifestEntry, Box<dyn Error>>>, Box<dyn Error>>
+    {
+        Ok(manifest::ManifestReader::new(self.manifest_reader()?))
+    }
+
+    /// Returns the first manifest entry for `path`, without reading the rest of the manifest. A
+    /// leading slash is optional, both "/etc/passwd" and "etc/passwd" find the same entry.
+    pub fn find_entry(
+        &self,
+        path: &Path,
+    ) -> Result<Option<manifest::ManifestEntry>, Box<dyn Error>> {
+        let without_root = |path: &Path| path.strip_prefix("/").unwrap_or(path).to_owned();
+        let wanted = without_root(path);
+        let mut found = None;
+        manifest::read_manifest(
+            &mut self.manifest_reader()?,
+            &mut |entry: manifest::ManifestEntry| {
+                if without_root(&entry.path) == wanted {
+                    found = Some(entry);
+                    return Ok(ControlFlow::Break(()));
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+        Ok(found)
+    }
+
+    /// Writes the manifest as CSV to `out`, see `manifest::write_csv`
+    pub fn write_csv(&self, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        manifest::write_csv(&mut self.manifest_reader()?, out)
+    }
+
+    /// Iterates over all files in this backup's manifest. If the manifest cannot be opened, the
+    /// error is returned as only item.
+    pub fn files_iter(&self) -> impl Iterator<Item = Result<FileInfo, Box<dyn Error>>> {
+        let (entries, error) = match self.entries() {
+            Ok(entries) => (Some(entries), None),
+            Err(err) => (None, Some(Err(err))),
+        };
+        error
+            .into_iter()
+            .chain(entries.into_iter().flatten())
+            .map(|entry| entry.map(FileInfo::from))
     }
 
     fn file_path(&self, prefix: Option<&str>, path: &OsStr) -> PathBuf {
@@ -186,7 +586,11 @@ impl Backup {
         real_path.join(path)
     }
 
-    fn create_volume(&self, base_backup: &Option<&Backup>) -> Result<(), Box<dyn Error>> {
+    fn create_volume(
+        &self,
+        base_backup: &Option<&Backup>,
+        storage: &dyn StorageBackend,
+    ) -> Result<(), Box<dyn Error>> {
         if !self.is_local {
             return Err(Box::new(NotLocalError {
                 message: format!(
@@ -199,7 +603,7 @@ impl Backup {
         let path = self.path();
         if path.exists() {
             log::info!(
-                "Destination directory {} already exists. Not cloning anything.",
+                "Destination directory {} already exists, reusing it",
                 path.display()
             );
             return Ok(());
@@ -217,15 +621,7 @@ impl Backup {
                 base_backup.path().display(),
                 path.display()
             );
-            let status = Command::new("btrfs")
-                .arg("subvolume")
-                .arg("snapshot")
-                .arg(base_backup.path())
-                .arg(&path)
-                .stdin(Stdio::null())
-                .stdout(Stdio::null())
-                .status()?;
-            assert!(status.success());
+            storage.snapshot_from(&base_backup.path(), &path)?;
 
             fs::read_dir(&path)?
                 .map(|result| result.unwrap())
@@ -237,14 +633,7 @@ impl Backup {
                 });
         } else {
             log::info!("Creating empty volume at {}", path.display());
-            let status = Command::new("btrfs")
-                .arg("subvolume")
-                .arg("create")
-                .arg(&path)
-                .stdin(Stdio::null())
-                .stdout(Stdio::null())
-                .status()?;
-            assert!(status.success());
+            storage.create(&path)?;
             fs::create_dir(path.join("data"))?;
         }
         fs::File::create(path.join(".bdup.partial"))?;
@@ -255,19 +644,21 @@ impl Backup {
         &self,
         rx: &Receiver<TransferResult>,
         return_after: Option<&OsStr>,
+        options: &CloneOptions,
     ) -> (u64, u64) {
         let mut files_ok = 0;
         let mut transfer_size = 0;
+        // all results are received even if interrupted, so running transfers can finish
         for result in rx.iter() {
-            match result.error {
-                None => {
-                    files_ok += 1;
-                    transfer_size += result.size;
-                }
-                Some(error) => log::error!("Could not fetch file {:?}: {:?}", result.source, error),
-            }
+            let dest = result.dest.to_owned();
+            Self::count_transfer(
+                result,
+                &mut files_ok,
+                &mut transfer_size,
+                options.is_cancelled(),
+            );
             if let Some(path) = return_after {
-                if path == result.dest {
+                if path == dest {
                     break;
                 }
             }
@@ -276,11 +667,37 @@ impl Backup {
         (files_ok, transfer_size)
     }
 
+    /// Adds a transfer result to the counts. Failures of interrupted transfers are expected and
+    /// only logged as debug messages.
+    fn count_transfer(
+        result: TransferResult,
+        files_ok: &mut u64,
+        transfer_size: &mut u64,
+        interrupted: bool,
+    ) {
+        #[cfg(feature = "systemd")]
+        crate::notify::watchdog();
+        match result.error {
+            None => {
+                *files_ok += 1;
+                *transfer_size += result.size;
+            }
+            Some(error) if interrupted => {
+                log::debug!("Did not fetch file {:?}: {}", result.source, error)
+            }
+            Some(error) => log::error!("Could not fetch file {:?}: {:?}", result.source, error),
+        }
+    }
+
+    /// Clones the source backup whose files `fetch_callback` transfers into this backup.
+    /// `manifest_name` is the name of the source's manifest, one of `MANIFEST_FILES`.
     pub fn clone_from(
         &mut self,
         base_backup: &Option<&Backup>,
-        fetch_callback: &dyn Fn(&OsStr, &Path, &Sender<TransferResult>),
-    ) -> Result<(), Box<dyn Error>> {
+        manifest_name: &str,
+        fetch_callback: &FetchFn,
+        options: &CloneOptions,
+    ) -> Result<CloneStats, Box<dyn Error>> {
         if !self.is_local {
             return Err(Box::new(NotLocalError {
                 message: format!(
@@ -292,70 +709,132 @@ impl Backup {
         let path = self.path();
         if self.is_finished() {
             log::info!("Cloning to {:?} already finished. Skipping", path);
-            return Ok(());
+            return Ok(CloneStats::default());
         }
 
         if let Some(backup) = base_backup {
             assert!(!backup.get_checksums().is_empty());
         }
-        self.create_volume(base_backup)?;
-
-        let (tx, rx) = channel();
+        // an unfinished backup in the destination is left over by an interrupted clone
+        let resuming = path.exists();
+        if resuming {
+            log::info!("Resuming interrupted clone to {}", path.display());
+        }
+        self.create_volume(base_backup, options.storage.as_ref())?;
+
+        // results are only collected while the manifest is read if too many transfers are
+        // queued, which blocks reading the manifest until the transfers caught up. All metadata
+        // results are received before, so the channel always has room for one more result.
+        // Callbacks of remote clients transfer synchronously and send their result before
+        // returning, on the thread that receives the results.
+        let metadata = std::iter::once(manifest_name)
+            .chain(Self::metadata_files().iter().copied())
+            .collect::<Vec<&str>>();
+        let max_queued = options.max_queued_transfers.max(1);
+        let (tx, rx) = sync_channel(max_queued.max(metadata.len()));
 
         let mut files_total = 0;
         let mut files_from_base = 0;
+        let mut files_existing = 0;
+        let mut bytes_deduplicated = 0;
 
         log::debug!("Fetching metadata");
-        for filename in Self::metadata_files() {
+        for filename in &metadata {
             files_total += 1;
             let dest_path = path.join(filename);
-            fetch_callback(OsStr::new(filename), &dest_path, &tx.clone());
+            fetch_callback(OsStr::new(filename), &dest_path, None, &tx.clone());
+        }
+        let mut files_ok = 0;
+        let mut transfer_size = 0;
+        for result in rx.iter().take(metadata.len()) {
+            Self::count_transfer(
+                result,
+                &mut files_ok,
+                &mut transfer_size,
+                options.is_cancelled(),
+            );
         }
-        let (mut files_ok, mut transfer_size) =
-            self.wait_for_transfer(&rx, Some(path.join("manifest.gz").as_os_str()));
 
         log::debug!("Starting data transfers");
+        self.partial = !options.include.is_empty();
+        let mut files_not_included = 0;
         let mut files_in_manifest = HashSet::new();
+        let mut queued = 0;
+        let mut interrupted = false;
         manifest::read_manifest(
             &mut self.manifest_reader()?,
             &mut |entry: manifest::ManifestEntry| {
+                if options.is_cancelled() {
+                    log::warn!("Interrupted, waiting for running transfers to finish");
+                    interrupted = true;
+                    return Ok(ControlFlow::Break(()));
+                }
                 if let Some(data) = &entry.data {
-                    self.checksums
-                        .insert(data.path.to_owned(), data.md5.to_owned());
+                    if !options.includes(&entry.path) {
+                        files_not_included += 1;
+                        return Ok(ControlFlow::Continue(()));
+                    }
+                    if options.excludes(&entry.path) {
+                        log::debug!("Skipping excluded file {}", entry.path.display());
+                        self.excluded.insert(data.path.to_owned());
+                        return Ok(ControlFlow::Continue(()));
+                    }
+                    let info = DataFileInfo::from(&entry);
+                    self.checksums.insert(data.path.to_owned(), info.clone());
                     files_in_manifest.insert(data.path.to_owned());
 
                     files_total += 1;
                     let data_path = data.path.to_owned();
+                    let dest_path = path.join("data").join(&data_path);
                     let mut copied = false;
                     if let Some(base) = &base_backup {
-                        if let Some(base_md5) = &base.get_checksums().get(&data_path) {
-                            if **base_md5 == data.md5 {
+                        if let Some(base_info) = &base.get_checksums().get(&data_path) {
+                            if base_info.md5 == data.md5 {
                                 files_from_base += 1;
+                                // the snapshot of the base already contains the file
+                                bytes_deduplicated += fs::symlink_metadata(&dest_path)
+                                    .map_or(base_info.size, |meta| meta.len());
                                 copied = true;
                             }
                         }
                     }
+                    if !copied && resuming && Self::is_intact(&dest_path, data) {
+                        files_existing += 1;
+                        copied = true;
+                    }
                     if !copied {
-                        let dest_path = path.join("data").join(&data_path);
                         fetch_callback(
                             &PathBuf::from("data").join(data_path).into_os_string(),
                             &dest_path,
+                            Some(&info),
                             &tx.clone(),
                         );
+                        queued += 1;
+                        while queued >= max_queued {
+                            let result = rx.recv()?;
+                            Self::count_transfer(
+                                result,
+                                &mut files_ok,
+                                &mut transfer_size,
+                                options.is_cancelled(),
+                            );
+                            queued -= 1;
+                        }
                     }
                 }
-                Ok(())
+                Ok(ControlFlow::Continue(()))
             },
         )?;
         drop(tx);
 
         log::debug!("Waiting for queued transfers to finish");
-        let (num, size) = self.wait_for_transfer(&rx, None);
+        let (num, size) = self.wait_for_transfer(&rx, None, options);
         files_ok += num;
         transfer_size += size;
 
-        if base_backup.is_some() {
-            log::debug!("Removing superfluous files (cloned from base, not in this backup)");
+        // files of the base that are not in the manifest are only known after reading all of it
+        if base_backup.is_some() && !interrupted {
+            log::debug!("Removing superfluous files (cloned from base, not in this clone)");
             let mut unwanted = self.unwanted_files()?;
 
             // sort unwanted files/dirs by number of path components to remove contained files
@@ -367,15 +846,23 @@ impl Backup {
                     .unwrap()
             });
             log::debug!("Found {} unwanted files", unwanted.len());
+            // unwanted paths are relative to the data directory, which is never removed
+            let data_path = path.join("data");
             unwanted
                 .iter()
-                .filter(|path| path.exists())
-                .map(|path| -> Result<(), Box<dyn Error>> {
-                    match path.is_dir() {
-                        true => fs::remove_dir(path)?,
-                        false => fs::remove_file(path)?,
+                .map(|file| data_path.join(file))
+                .filter_map(|path| Some((fs::symlink_metadata(&path).ok()?, path)))
+                .map(|(meta, path)| -> Result<(), Box<dyn Error>> {
+                    match meta.is_dir() {
+                        true => fs::remove_dir_all(&path)?,
+                        false => fs::remove_file(&path)?,
                     }
-                    for parent in path.parent().unwrap().ancestors() {
+                    for parent in path
+                        .parent()
+                        .unwrap()
+                        .ancestors()
+                        .take_while(|parent| *parent != data_path)
+                    {
                         if parent.read_dir()?.next().is_none() {
                             fs::remove_dir(parent)?;
                         }
@@ -386,57 +873,204 @@ impl Backup {
                 .for_each(|err| log::warn!("Could not remove file: {:?}", err));
         }
 
-        let errors = files_total - files_ok - files_from_base;
-        if errors == 0 {
+        let errors = files_total - files_ok - files_from_base - files_existing;
+        let stats = CloneStats {
+            files_total,
+            files_from_base,
+            files_failed: errors,
+            bytes_transferred: transfer_size,
+            bytes_deduplicated,
+            interrupted,
+        };
+        if files_existing > 0 {
+            log::info!(
+                "{} files were already transferred by an interrupted clone",
+                files_existing
+            );
+        }
+        if files_not_included > 0 {
+            log::info!("{} files were not included", files_not_included);
+        }
+        if !self.excluded.is_empty() {
+            log::info!("{} excluded files were skipped", self.excluded.len());
+        }
+        if interrupted {
+            log::warn!(
+                "Cloning interrupted after {} files, {} transferred. The next run resumes it.",
+                files_from_base + files_existing + files_ok,
+                format_bytes(transfer_size)
+            );
+        } else if errors == 0 {
             log::info!("Cloning finished successfully: {} files total, {} from base backup, {} transferred", files_total, files_from_base, format_bytes(transfer_size));
+            if base_backup.is_some() {
+                log::info!(
+                    "{:.0}% deduplicated vs base ({} shared)",
+                    stats.deduplicated_percent(),
+                    format_bytes(bytes_deduplicated)
+                );
+            }
+            if options.write_checksum_file {
+                self.write_checksum_file()?;
+            }
             fs::remove_file(path.join(".bdup.partial"))?;
-            let status = Command::new("btrfs")
-                .arg("property")
-                .arg("set")
-                .arg(path)
-                .arg("ro")
-                .arg("true")
-                .stdin(Stdio::null())
-                .status()?;
-            assert!(status.success());
+            options.storage.finalize_readonly(&path)?;
         } else {
-            log::warn!("Cloning finished with errors: {}/{} files were successful, {} from base backup, {} transferred", files_from_base + files_ok, files_total, files_from_base, format_bytes(transfer_size));
+            log::warn!("Cloning finished with errors: {}/{} files were successful, {} from base backup, {} transferred", files_from_base + files_existing + files_ok, files_total, files_from_base, format_bytes(transfer_size));
         }
+        Ok(stats)
+    }
+
+    /// Checks whether the data file at `path` exists and matches size and MD5 checksum of
+    /// `data`. The size is that of the uncompressed data, so the file is read completely.
+    fn is_intact(path: &Path, data: &manifest::ManifestEntryData) -> bool {
+        let input = match fs::File::open(path) {
+            Ok(file) => Box::new(file),
+            Err(_) => return false,
+        };
+        matches!(
+            verify_file_digest(
+                input,
+                data.size,
+                &data.md5,
+                ChecksumAlgo::Md5,
+                DEFAULT_BUFFER_SIZE
+            ),
+            Ok((true, _, _))
+        )
+    }
+
+    /// Reads the manifest of the source backup like `clone_from`, but only logs which files
+    /// would be transferred. The checksums are kept, so the backup can serve as base for the
+    /// plan of the next backup.
+    pub fn plan_clone_from(
+        &mut self,
+        base_backup: &Option<&Backup>,
+        manifest: Box<dyn io::Read>,
+        options: &CloneOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut files_total = 0;
+        let mut files_from_base = 0;
+        let mut transfer_size = 0;
+        manifest::read_manifest(
+            &mut decompress_manifest(manifest)?,
+            &mut |entry: manifest::ManifestEntry| {
+                if let Some(data) = &entry.data {
+                    if !options.includes(&entry.path) {
+                        return Ok(ControlFlow::Continue(()));
+                    }
+                    if options.excludes(&entry.path) {
+                        self.excluded.insert(data.path.to_owned());
+                        return Ok(ControlFlow::Continue(()));
+                    }
+                    files_total += 1;
+                    let in_base = base_backup
+                        .and_then(|base| base.get_checksums().get(&data.path))
+                        .is_some_and(|info| info.md5 == data.md5);
+                    if in_base {
+                        files_from_base += 1;
+                    } else {
+                        transfer_size += data.size;
+                    }
+                    self.checksums.insert(data.path.to_owned(), (&entry).into());
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+        log::info!(
+            "Would transfer {} of {} files ({} uncompressed), {} from base backup, {} excluded",
+            files_total - files_from_base,
+            files_total,
+            format_bytes(transfer_size),
+            files_from_base,
+            self.excluded.len()
+        );
         Ok(())
     }
 
-    fn top_level_data_dirs(&self) -> HashSet<PathBuf> {
-        assert!(!self.checksums.is_empty());
-        self.checksums
-            .keys()
-            .map(|entry| entry.components().take(1).collect())
-            .collect()
+    /// Directories containing data files of the manifest, including excluded files. Only the
+    /// top-level directories, unless the backup is `partial`.
+    fn wanted_data_dirs(&self) -> HashSet<PathBuf> {
+        let files = self.checksums.keys().chain(self.excluded.iter());
+        if self.partial {
+            files
+                .flat_map(|file| file.ancestors().skip(1))
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .map(Path::to_path_buf)
+                .collect()
+        } else {
+            files
+                .map(|entry| entry.components().take(1).collect())
+                .collect()
+        }
     }
 
+    /// Files and directories in the data directory that are not part of the manifest. Excluded
+    /// files are wanted, they are neither cloned nor removed. Files outside of the include
+    /// directories of a `partial` clone are unwanted.
     fn unwanted_files(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
         assert!(self.is_local);
-        assert!(!self.checksums.is_empty());
 
-        let wanted_top_level = self.top_level_data_dirs();
+        let wanted_dirs = self.wanted_data_dirs();
         log::debug!(
-            "Found required top-level directories in manifest: {:?}",
-            wanted_top_level
+            "Found {} required directories in manifest",
+            wanted_dirs.len()
         );
 
-        let path = self.path();
-        let data_path = path.join("data");
-        let iter = fs::read_dir(&data_path)?
-            .filter_map(|entry| entry.ok())
-            .filter_map(|entry| {
-                if let Ok(stripped) = entry.path().strip_prefix(&data_path) {
-                    Some(PathBuf::from(stripped))
-                } else {
-                    None
+        let data_path = self.path().join("data");
+        let mut unwanted = Vec::new();
+        let mut pending = vec![PathBuf::new()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(data_path.join(&dir))?.filter_map(|entry| entry.ok()) {
+                let path = dir.join(entry.file_name());
+                if self.checksums.contains_key(&path) || self.excluded.contains(&path) {
+                    continue;
                 }
-            })
-            .filter(|path| !wanted_top_level.contains(path) && !self.checksums.contains_key(path));
+                if !wanted_dirs.contains(&path) {
+                    unwanted.push(path);
+                } else if self.partial {
+                    pending.push(path);
+                }
+            }
+        }
+        Ok(unwanted)
+    }
+
+    /// Sum of the sizes of all data files in the manifest. These are the uncompressed sizes of
+    /// the original files, not the space the backup uses on disk. The result is cached, so the
+    /// manifest is only read once.
+    pub fn total_size(&mut self) -> Result<u64, Box<dyn Error>> {
+        if let Some(size) = self.total_size {
+            return Ok(size);
+        }
+        let mut size = 0;
+        manifest::read_manifest(
+            &mut self.manifest_reader()?,
+            &mut |entry: manifest::ManifestEntry| {
+                if let Some(data) = &entry.data {
+                    size += data.size;
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+        self.total_size = Some(size);
+        Ok(size)
+    }
 
-        Ok(iter.collect())
+    /// Sorts the data files in the manifest into size classes. The manifest is read entry by
+    /// entry, only the counts are kept. Also caches the result of `total_size`.
+    pub fn size_histogram(&mut self) -> Result<SizeHistogram, Box<dyn Error>> {
+        let mut histogram = SizeHistogram::default();
+        manifest::read_manifest(
+            &mut self.manifest_reader()?,
+            &mut |entry: manifest::ManifestEntry| {
+                if let Some(data) = &entry.data {
+                    histogram.add(data.size);
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+        self.total_size = Some(histogram.bytes());
+        Ok(histogram)
     }
 
     pub fn dir_name(&self) -> String {
@@ -444,27 +1078,80 @@ impl Backup {
     }
 
     pub fn load_checksums(&mut self) -> Result<(), Box<dyn Error>> {
+        let path = self.manifest_path();
+        self.load_checksums_with(|| Ok(Box::new(fs::File::open(&path)?)))
+    }
+
+    /// Loads the checksums from the compressed manifest returned by `open`, e.g. a cached copy
+    /// of a remote manifest. `open` is not called if the checksums are already loaded.
+    pub fn load_checksums_with(
+        &mut self,
+        open: impl FnOnce() -> Result<Box<dyn io::Read>, Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
         if self.checksums.is_empty() {
             log::info!("Loading checksums from backup {:?}", self.path());
-            let mut reader = self.manifest_reader()?;
+            let mut reader = decompress_manifest(open()?)?;
 
             manifest::read_manifest(&mut reader, &mut |entry: manifest::ManifestEntry| {
                 if let Some(data) = &entry.data {
-                    self.checksums
-                        .insert(data.path.to_owned(), data.md5.to_owned());
+                    self.checksums.insert(data.path.to_owned(), (&entry).into());
                 }
-                Ok(())
+                Ok(ControlFlow::Continue(()))
             })?;
         }
         Ok(())
     }
 
+    /// Writes md5 sum, size and path of all data files to `CHECKSUM_FILE` in the backup's
+    /// directory, sorted by path. The list is built from the manifest read during cloning, so a
+    /// clone can be checked later without trusting its copy of the manifest.
+    pub fn write_checksum_file(&self) -> Result<(), Box<dyn Error>> {
+        let mut entries = self.checksums.iter().collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        let mut file = io::BufWriter::new(fs::File::create(self.path().join(CHECKSUM_FILE))?);
+        for (path, info) in entries {
+            writeln!(file, "{}  {}  {}", info.md5, info.size, path.display())?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+
     pub fn is_finished(&self) -> bool {
-        // TODO remote check
-        self.path().join("manifest.gz").exists() && !self.path().join(".bdup.partial").exists()
+        self.manifest_path().exists() && !self.path().join(".bdup.partial").exists()
+    }
+
+    /// Checksum, size and mode of the data file at `path`, relative to the data directory. The
+    /// manifest must have been read before, e.g. by `load_checksums`.
+    pub fn data_file_info(&self, path: &Path) -> Option<&DataFileInfo> {
+        self.checksums.get(path)
     }
 
-    fn get_checksums(&self) -> &HashMap<PathBuf, String> {
+    /// Compares the data files of this backup with those of the newer backup `other`. Both
+    /// backups must have their checksums loaded, e.g. by `load_checksums`.
+    pub fn diff(&self, other: &Backup) -> BackupDiff {
+        let old = self.get_checksums();
+        let new = other.get_checksums();
+        let mut diff = BackupDiff::default();
+        for (path, info) in new {
+            match old.get(path) {
+                None => diff.added.push(path.to_owned()),
+                Some(old_info) if old_info.md5 != info.md5 => diff.changed.push(path.to_owned()),
+                Some(_) => continue,
+            }
+            diff.churn_bytes += info.size;
+        }
+        diff.removed = old
+            .keys()
+            .filter(|path| !new.contains_key(*path))
+            .cloned()
+            .collect();
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+
+    fn get_checksums(&self) -> &HashMap<PathBuf, DataFileInfo> {
         if self.checksums.is_empty() {
             log::debug!(
                 "getting empty checksum map from backup {}",
@@ -474,117 +1161,334 @@ impl Backup {
         &self.checksums
     }
 
-    pub fn verify(&mut self, worker_threads: usize) -> Result<u64, Box<dyn Error>> {
+    /// Extracts the files of this backup into a plain directory tree below `dest`. Directories,
+    /// regular files and soft links are restored, other file types are skipped.
+    pub fn restore_to(
+        &mut self,
+        dest: &Path,
+        options: &RestoreOptions,
+    ) -> Result<(), Box<dyn Error>> {
         assert!(self.is_local);
+        let data_path = self.path().join("data");
+        let mut restored = 0;
+        let mut chown = options.apply_metadata;
+        // directory times change while their content is restored, so they are set at the end
+        let mut directories = Vec::new();
 
-        let path = self.path();
-        let data_path = path.join("data");
-        let mut files_in_manifest = HashSet::new();
-
-        let manifest = fs::File::open(path.join("manifest.gz"))?;
-        let gz = GzDecoder::new(manifest);
-        let mut reader = io::BufReader::new(gz);
-
-        let worker_pool = ThreadPool::new(worker_threads);
-        let (tx, rx) = channel();
-
-        log::debug!("Verifying checksums for backup {}", path.display());
-        let mut files_total = 0;
-        manifest::read_manifest(&mut reader, &mut |entry: manifest::ManifestEntry| {
-            if let Some(data) = &entry.data {
-                self.checksums
-                    .insert(data.path.to_owned(), data.md5.to_owned());
-                files_total += 1;
-                files_in_manifest.insert(data.path.to_owned());
-
-                let size = data.size;
-                let checksum = data.md5.to_owned();
-                let file_path = data_path.join(&data.path);
-                let tx = tx.clone();
-                worker_pool.execute(move || {
-                    let result = match verify_file_md5(&file_path, size, &checksum) {
-                        Ok((true, _, _)) => VerifyResult::Ok,
-                        Ok((false, read_size, md5)) => {
-                            if read_size != size {
-                                VerifyResult::FilesizeMismatch(read_size)
-                            } else {
-                                VerifyResult::ChecksumMismatch(md5)
-                            }
-                        }
-                        Err(err) => {
-                            VerifyResult::Error(format!("Error computing checksum: {:?}", err))
+        log::info!(
+            "Restoring backup {} to {}",
+            self.path().display(),
+            dest.display()
+        );
+        log::debug!("Backup uses {} compression", self.compression()?);
+        for entry in manifest::ManifestReader::new(self.manifest_reader()?) {
+            let entry = entry?;
+            let target = restore_path(dest, &entry.path)?;
+            match (entry.file_type(), &entry.data) {
+                (manifest::FileType::Directory, _) => {
+                    fs::create_dir_all(&target)?;
+                    if let Some(stat) = entry.stat.filter(|_| options.apply_metadata) {
+                        directories.push((target, stat));
+                    }
+                }
+                (manifest::FileType::Plain, Some(data)) => {
+                    create_parent_dir(&target)?;
+                    let mut input = Compression::decoder(io::BufReader::new(fs::File::open(
+                        data_path.join(&data.path),
+                    )?))?;
+                    let spec = options.compression.unwrap_or(CompressionSpec::NONE);
+                    let target = match spec.extension() {
+                        Some(extension) => {
+                            let mut name = target.into_os_string();
+                            name.push(".");
+                            name.push(extension);
+                            PathBuf::from(name)
                         }
+                        None => target,
                     };
-                    tx.send(VerifyFileResult {
-                        path: file_path,
-                        size,
-                        md5: checksum,
-                        result,
-                    })
-                    .unwrap();
-                });
-
-                if worker_pool.panic_count() > 0 {
-                    return Err(Box::new(CopyThreadPanicedError {
-                        message: "See thread's backtrace for more information".to_string(),
-                    }));
-                }
-            }
-            Ok(())
-        })?;
-        drop(tx);
-
-        let mut files_ok = 0;
-        for result in rx.iter() {
-            match result.result {
-                VerifyResult::Ok => files_ok += 1,
-                VerifyResult::FilesizeMismatch(size) => {
-                    log::error!(
-                        "File does not have correct size {:?}. Expected: {}, real: {}",
-                        result.path,
-                        result.size,
-                        size
-                    );
+                    let mut output = spec.encoder(fs::File::create(&target)?)?;
+                    io::copy(&mut input, &mut output)?;
+                    output.finish()?;
+                    if let Some(stat) = entry.stat.as_ref().filter(|_| options.apply_metadata) {
+                        apply_metadata(&target, stat, false, &mut chown)?;
+                    }
                 }
-                VerifyResult::ChecksumMismatch(computed) => {
-                    log::error!(
-                        "File's checksum did not match {:?}. Expected: {}, computed: {}",
-                        result.path,
-                        result.md5,
-                        computed
-                    );
+                (manifest::FileType::SoftLink, _) => {
+                    if let Some(link_target) = entry.link_target() {
+                        create_parent_dir(&target)?;
+                        std::os::unix::fs::symlink(link_target, &target)?;
+                        if let Some(stat) = entry.stat.as_ref().filter(|_| options.apply_metadata) {
+                            apply_metadata(&target, stat, true, &mut chown)?;
+                        }
+                    }
                 }
-                VerifyResult::Error(err) => {
-                    log::error!(
-                        "Error while computing checksum for {:?}: {:?}",
-                        result.path,
-                        err
-                    );
+                (file_type, _) => {
+                    log::debug!("Not restoring {:?} of type {:?}", entry.path, file_type);
+                    continue;
                 }
-            };
+            }
+            restored += 1;
         }
-
-        log::debug!("Searching for unwanted files in {}", path.display());
-        let unwanted = self.unwanted_files()?;
-        if !unwanted.is_empty() {
-            log::info!(
-                "Found {} superfluous files while validating: {:?}",
-                unwanted.len(),
-                unwanted
-            );
+        for (path, stat) in directories.iter().rev() {
+            apply_metadata(path, stat, false, &mut chown)?;
         }
-
-        log::info!(
-            "Verify finished: {}/{} files verified successfully, {} unwanted files",
-            files_ok,
-            files_total,
-            unwanted.len()
-        );
-        Ok(files_total - files_ok)
+        log::info!("Restore finished: {} entries restored", restored);
+        Ok(())
     }
-}
 
-impl Eq for Backup {}
+    /// Verifies all data files of this backup and returns the number of failed files.
+    pub fn verify(&mut self, worker_threads: usize) -> Result<u64, Box<dyn Error>> {
+        let report = self.verify_detailed(worker_threads)?;
+        Ok(report.failed.len() as u64)
+    }
+
+    /// Verifies all data files of this backup and reports which files failed.
+    pub fn verify_detailed(
+        &mut self,
+        worker_threads: usize,
+    ) -> Result<VerifyReport, Box<dyn Error>> {
+        self.verify_with_options(&VerifyOptions::new(worker_threads))
+    }
+
+    /// Like `verify_detailed`, but compares the checksums in the manifest with digests computed
+    /// by `algo` instead of MD5.
+    pub fn verify_with_algo(
+        &mut self,
+        worker_threads: usize,
+        algo: ChecksumAlgo,
+    ) -> Result<VerifyReport, Box<dyn Error>> {
+        self.verify_with_options(&VerifyOptions {
+            algo,
+            ..VerifyOptions::new(worker_threads)
+        })
+    }
+
+    /// Like `verify_detailed`, but calls `on_corruption` for each failed file as soon as its
+    /// result is collected, e.g. to raise an alert before the whole backup is verified.
+    ///
+    /// The callback runs on the thread that collects the results of all workers. It should
+    /// return quickly (e.g. queue a notification) instead of doing blocking work itself.
+    pub fn verify_with_callback(
+        &mut self,
+        worker_threads: usize,
+        on_corruption: &dyn Fn(&Path, &VerifyResult),
+    ) -> Result<VerifyReport, Box<dyn Error>> {
+        self.verify_with_options(&VerifyOptions {
+            on_corruption,
+            ..VerifyOptions::new(worker_threads)
+        })
+    }
+
+    /// Like `verify_detailed`, but calls `on_progress` with the number of verified files and
+    /// the number of files in the manifest each time a file has been verified. While the
+    /// manifest is read, the total is the number of files found so far.
+    pub fn verify_with_progress(
+        &mut self,
+        worker_threads: usize,
+        on_progress: &dyn Fn(u64, u64),
+    ) -> Result<VerifyReport, Box<dyn Error>> {
+        self.verify_with_options(&VerifyOptions {
+            on_progress,
+            ..VerifyOptions::new(worker_threads)
+        })
+    }
+
+    pub fn verify_with_options(
+        &mut self,
+        options: &VerifyOptions,
+    ) -> Result<VerifyReport, Box<dyn Error>> {
+        assert!(self.is_local);
+
+        let path = self.path();
+        let data_path = path.join("data");
+        log::debug!(
+            "Backup {} uses {} compression",
+            path.display(),
+            self.compression()?
+        );
+        let manifest = self.manifest_reader()?;
+        let mut report = self.verify_entries(manifest, options, &|name: &Path| {
+            Ok(Box::new(fs::File::open(path.join(name))?))
+        })?;
+
+        log::debug!("Searching for unwanted files in {}", path.display());
+        let unwanted = self.unwanted_files()?;
+        if !unwanted.is_empty() {
+            log::info!(
+                "Found {} superfluous files while validating: {:?}",
+                unwanted.len(),
+                unwanted
+            );
+        }
+        report.unwanted = unwanted.iter().map(|file| data_path.join(file)).collect();
+
+        log::info!(
+            "Verify finished: {}/{} files verified successfully, {} files missing, {} unwanted files",
+            report.ok,
+            report.ok + report.failed.len() as u64,
+            report.missing(),
+            report.unwanted.len()
+        );
+        Ok(report)
+    }
+
+    /// Verifies a backup whose files are opened through `read_file`, which gets paths relative
+    /// to the backup directory (e.g. `Client::read_file` of a remote client). Data files are
+    /// checksummed while they are read, nothing is stored on disk. Unwanted files cannot be
+    /// detected this way, so the report never lists any.
+    pub fn verify_with_reader(
+        &mut self,
+        options: &VerifyOptions,
+        read_file: &ReadFileFn,
+    ) -> Result<VerifyReport, Box<dyn Error>> {
+        let manifest = open_manifest(read_file)?;
+        let report = self.verify_entries(manifest, options, read_file)?;
+        log::info!(
+            "Verify finished: {}/{} files verified successfully, {} files missing",
+            report.ok,
+            report.ok + report.failed.len() as u64,
+            report.missing()
+        );
+        Ok(report)
+    }
+
+    /// Like `verify_with_reader`, but all files are read on the calling thread, so `read_file`
+    /// does not need to be shareable between threads. The worker threads of `options` are not
+    /// used and the checksums are not kept. `on_progress` is called with 0 as total, because the
+    /// manifest is only read once.
+    pub fn verify_sequentially(
+        &self,
+        options: &VerifyOptions,
+        read_file: &SequentialReadFileFn,
+    ) -> Result<VerifyReport, Box<dyn Error>> {
+        let data_path = self.path().join("data");
+        let manifest = open_manifest(read_file)?;
+        let mut report = VerifyReport::default();
+        for entry in manifest::ManifestReader::new(manifest) {
+            let Some(data) = entry?.data else {
+                continue;
+            };
+            let result = verify_data_file(
+                read_file(&Path::new("data").join(&data.path)),
+                data.size,
+                &data.md5,
+                options.algo,
+                options.buffer_size,
+            );
+            let result = VerifyFileResult {
+                path: data_path.join(&data.path),
+                size: data.size,
+                md5: data.md5,
+                result,
+            };
+            collect_verify_result(result, &mut report, options);
+            (options.on_progress)(report.ok + report.failed.len() as u64, 0);
+        }
+        log::info!(
+            "Verify finished: {}/{} files verified successfully, {} files missing",
+            report.ok,
+            report.ok + report.failed.len() as u64,
+            report.missing()
+        );
+        Ok(report)
+    }
+
+    /// Checks all data files listed in `manifest`. Worker threads open the files through
+    /// `read_file`, results are collected on the calling thread which also runs the callbacks.
+    fn verify_entries(
+        &mut self,
+        mut manifest: Box<dyn BufRead>,
+        options: &VerifyOptions,
+        read_file: &ReadFileFn,
+    ) -> Result<VerifyReport, Box<dyn Error>> {
+        let data_path = self.path().join("data");
+        let algo = options.algo;
+        let buffer_size = options.buffer_size;
+        // jobs are only queued while fewer than `max_queued` files are in flight, i.e. queued,
+        // being verified or waiting as result. So neither channel is ever full and sending does
+        // not block. Results are received while reading the manifest once the limit is reached.
+        let max_queued = options.worker_threads.max(1) * 4;
+        let (job_tx, job_rx) = sync_channel::<(PathBuf, u64, String)>(max_queued);
+        let job_rx = Mutex::new(job_rx);
+        let (tx, rx) = sync_channel(max_queued);
+
+        log::debug!("Verifying checksums for backup {}", self.path().display());
+        let mut files_total = 0;
+        thread::scope(|scope| {
+            let workers = (0..options.worker_threads.max(1))
+                .map(|_| {
+                    let tx = tx.clone();
+                    let job_rx = &job_rx;
+                    let data_path = &data_path;
+                    scope.spawn(move || loop {
+                        let job = job_rx.lock().unwrap().recv();
+                        let Ok((name, size, checksum)) = job else {
+                            break;
+                        };
+                        let result = verify_data_file(
+                            read_file(&Path::new("data").join(&name)),
+                            size,
+                            &checksum,
+                            algo,
+                            buffer_size,
+                        );
+                        tx.send(VerifyFileResult {
+                            path: data_path.join(&name),
+                            size,
+                            md5: checksum,
+                            result,
+                        })
+                        .unwrap();
+                    })
+                })
+                .collect::<Vec<_>>();
+            drop(tx);
+
+            // until the manifest is read completely, progress is reported with the number of
+            // files found so far as total
+            let mut report = VerifyReport::default();
+            let mut in_flight = 0;
+            let read_result =
+                manifest::read_manifest(&mut manifest, &mut |entry: manifest::ManifestEntry| {
+                    if let Some(data) = &entry.data {
+                        self.checksums.insert(data.path.to_owned(), (&entry).into());
+                        files_total += 1;
+                        job_tx.send((data.path.to_owned(), data.size, data.md5.to_owned()))?;
+                        in_flight += 1;
+                        while in_flight >= max_queued {
+                            let result = rx.recv().map_err(|_| CopyThreadPanicedError {
+                                message: "All verify threads stopped".to_string(),
+                            })?;
+                            collect_verify_result(result, &mut report, options);
+                            (options.on_progress)(
+                                report.ok + report.failed.len() as u64,
+                                files_total,
+                            );
+                            in_flight -= 1;
+                        }
+                    }
+                    Ok(ControlFlow::Continue(()))
+                });
+            // workers stop as soon as the queue is drained
+            drop(job_tx);
+            read_result?;
+
+            for result in rx.iter() {
+                collect_verify_result(result, &mut report, options);
+                (options.on_progress)(report.ok + report.failed.len() as u64, files_total);
+            }
+
+            if workers.into_iter().any(|worker| worker.join().is_err()) {
+                return Err(Box::new(CopyThreadPanicedError {
+                    message: "See thread's backtrace for more information".to_string(),
+                }) as Box<dyn Error>);
+            }
+            Ok(report)
+        })
+    }
+}
+
+impl Eq for Backup {}
 
 impl Ord for Backup {
     fn cmp(&self, other: &Self) -> Ordering {
@@ -604,39 +1508,336 @@ impl PartialEq for Backup {
     }
 }
 
-fn verify_file_md5(file: &Path, size: usize, md5: &str) -> io::Result<(bool, usize, String)> {
-    let input = fs::File::open(file)?;
-    let (read_size, digest) = calc_md5(&mut GzDecoder::new(input))?;
-    let digest = format!("{:x}", digest);
+/// Checks the data file opened by `input` against its manifest entry
+fn verify_data_file(
+    input: Result<Box<dyn io::Read>, Box<dyn Error>>,
+    size: u64,
+    checksum: &str,
+    algo: ChecksumAlgo,
+    buffer_size: usize,
+) -> VerifyResult {
+    let result = match input {
+        Ok(input) => verify_file_digest(input, size, checksum, algo, buffer_size),
+        Err(err) => match err.downcast::<io::Error>() {
+            Ok(err) => Err(*err),
+            Err(err) => Err(io::Error::other(err.to_string())),
+        },
+    };
+    match result {
+        Ok((true, _, _)) => VerifyResult::Ok,
+        Ok((false, read_size, md5)) => {
+            if read_size != size {
+                VerifyResult::FilesizeMismatch(read_size)
+            } else {
+                VerifyResult::ChecksumMismatch(md5)
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => VerifyResult::Missing,
+        Err(err) => match corrupt_compression(&err) {
+            Some(corrupt) => VerifyResult::CorruptCompression(corrupt.offset),
+            None => VerifyResult::Error(format!("Error computing checksum: {:?}", err)),
+        },
+    }
+}
 
-    Ok((read_size == size && md5 == digest, size, digest))
+/// Logs the result of verifying a file and adds it to `report`
+fn collect_verify_result(
+    result: VerifyFileResult,
+    report: &mut VerifyReport,
+    options: &VerifyOptions,
+) {
+    match &result.result {
+        VerifyResult::Ok => report.ok += 1,
+        VerifyResult::FilesizeMismatch(size) => {
+            log::error!(
+                "File does not have correct size {:?}. Expected: {}, real: {}",
+                result.path,
+                result.size,
+                size
+            );
+        }
+        VerifyResult::ChecksumMismatch(computed) => {
+            log::error!(
+                "File's checksum did not match {:?}. Expected: {}, computed: {}",
+                result.path,
+                result.md5,
+                computed
+            );
+        }
+        VerifyResult::Missing => {
+            log::error!("File is missing {:?}", result.path);
+        }
+        VerifyResult::CorruptCompression(offset) => {
+            log::error!(
+                "File's compressed data is damaged {:?}. Decompression failed at byte {}",
+                result.path,
+                offset
+            );
+        }
+        VerifyResult::Error(err) => {
+            log::error!(
+                "Error while computing checksum for {:?}: {:?}",
+                result.path,
+                err
+            );
+        }
+    };
+    if result.result != VerifyResult::Ok {
+        (options.on_corruption)(&result.path, &result.result);
+        report.failed.push((result.path, result.result));
+    }
 }
 
-fn calc_md5<T: io::Read>(reader: &mut T) -> io::Result<(usize, md5::Digest)> {
-    let mut ctx = md5::Context::new();
-    let mut buf = vec![0_u8; 4096];
-    let mut size = 0;
-    loop {
-        let len = reader.read(&mut buf)?;
-        ctx.consume(&buf[0..len]);
-        size += len;
-        if len == 0 {
-            break;
+/// Wraps a manifest in a decoder for the compression detected from its magic bytes
+pub(crate) fn decompress_manifest(input: Box<dyn io::Read>) -> io::Result<Box<dyn BufRead>> {
+    let decoder = Compression::decoder(io::BufReader::new(input))?;
+    Ok(Box::new(io::BufReader::new(decoder)))
+}
+
+/// Opens the manifest through `read_file`, the uncompressed one if `manifest.gz` cannot be read.
+/// Returns the error of `manifest.gz` if neither can be read.
+fn open_manifest(read_file: &SequentialReadFileFn) -> Result<Box<dyn BufRead>, Box<dyn Error>> {
+    let manifest = read_file(Path::new(MANIFEST_FILES[0]))
+        .or_else(|error| read_file(Path::new(MANIFEST_FILES[1])).map_err(|_| error))?;
+    Ok(decompress_manifest(manifest)?)
+}
+
+/// Maps an absolute path from the manifest below `dest`
+fn restore_path(dest: &Path, path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let mut target = dest.to_owned();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => target.push(part),
+            Component::RootDir | Component::CurDir => (),
+            _ => {
+                return Err(Box::new(InvalidNameError {
+                    message: format!("Refusing to restore path {:?}", path),
+                }))
+            }
         }
     }
-    Ok((size, ctx.compute()))
+    Ok(target)
+}
+
+/// Sets ownership, modification time (if known) and permissions of a restored file. Soft links
+/// only get their ownership changed. If changing the owner is not permitted, a warning is logged
+/// once and `chown` is cleared so the remaining files are restored without ownership.
+fn apply_metadata(
+    path: &Path,
+    stat: &manifest::Stat,
+    is_link: bool,
+    chown: &mut bool,
+) -> io::Result<()> {
+    if *chown {
+        let uid = u32::try_from(stat.owner_id).ok();
+        let gid = u32::try_from(stat.group_id).ok();
+        match std::os::unix::fs::lchown(path, uid, gid) {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                log::warn!("Not restoring file ownership: {}", err);
+                *chown = false;
+            }
+            result => result?,
+        }
+    }
+    if is_link {
+        return Ok(());
+    }
+
+    if let Some(mod_time) = stat.modified() {
+        fs::File::open(path)?.set_modified(mod_time)?;
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(stat.mode & 0o7777))
+}
+
+fn create_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+/// Error for data files that cannot be decompressed, wrapped in an `io::Error`
+#[derive(Debug)]
+struct CorruptCompressionError {
+    offset: u64,
+    message: String,
+}
+
+impl fmt::Display for CorruptCompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+impl Error for CorruptCompressionError {}
+
+fn corrupt_compression(err: &io::Error) -> Option<&CorruptCompressionError> {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<CorruptCompressionError>())
+}
+
+/// Compressed content of a data file. Counts the bytes consumed by the decoder and remembers
+/// read errors, so that errors of the decoder can be told apart from I/O errors.
+struct CompressedInput {
+    inner: io::BufReader<Box<dyn io::Read>>,
+    consumed: u64,
+    read_failed: bool,
+}
+
+impl io::Read for CompressedInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for CompressedInput {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self.inner.fill_buf() {
+            Ok(buf) => Ok(buf),
+            Err(err) => {
+                self.read_failed = true;
+                Err(err)
+            }
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed += amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+fn verify_file_digest(
+    input: Box<dyn io::Read>,
+    size: u64,
+    expected: &str,
+    algo: ChecksumAlgo,
+    buffer_size: usize,
+) -> io::Result<(bool, u64, String)> {
+    let mut decoder = Compression::decoder(CompressedInput {
+        inner: io::BufReader::with_capacity(buffer_size, input),
+        consumed: 0,
+        read_failed: false,
+    })?;
+    let (read_size, digest) =
+        calc_digest_buffered(&mut decoder, algo, buffer_size).map_err(|err| {
+            let input = decoder.get_ref();
+            if input.read_failed {
+                return err;
+            }
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                CorruptCompressionError {
+                    offset: input.consumed,
+                    message: err.to_string(),
+                },
+            )
+        })?;
+
+    // a digest of another algorithm (i.e. length) never matches
+    let matches = expected.len() == algo.hex_len() && expected.eq_ignore_ascii_case(&digest);
+    Ok((read_size == size && matches, read_size, digest))
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
-    use std::io::Cursor;
+    use flate2::write::GzEncoder;
+    use std::cell::RefCell;
+    use std::io::Read;
+    use std::sync::mpsc::channel;
     use std::thread;
 
+    fn manifest_line(kind: char, data: &str) -> String {
+        format!("{}{:04x}{}\n", kind, data.len(), data)
+    }
+
+    pub(crate) fn write_gz(path: &Path, content: &[u8]) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut encoder = GzEncoder::new(fs::File::create(path).unwrap(), Default::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    /// Creates a backup with the given data files below the temp dir. Existing data is removed.
+    /// Empty temporary directory for test `test_name`
+    fn test_dir(test_name: &str) -> PathBuf {
+        let base = std::env::temp_dir().join(format!("bdup-{}-{}", test_name, std::process::id()));
+        if base.exists() {
+            fs::remove_dir_all(&base).unwrap();
+        }
+        base
+    }
+
+    fn create_test_backup(test_name: &str, files: &[(&str, &[u8])]) -> Backup {
+        create_test_backup_at(&test_dir(test_name), "0000001 2021-04-11 00:00:00", files)
+    }
+
+    /// Like `create_test_backup`, but with all metadata files, as the source of a clone
+    fn create_source_backup(test_name: &str, files: &[(&str, &[u8])]) -> Backup {
+        let name = "0000001 2021-04-11 00:00:00";
+        crate::client::test::create_source_backup(&test_dir(test_name), name, files)
+    }
+
+    /// Fetch callback copying the files of `source`, which records the fetched names
+    fn copying_fetch<'a>(
+        source: &'a Backup,
+        fetched: &'a RefCell<Vec<PathBuf>>,
+    ) -> impl Fn(&OsStr, &Path, Option<&DataFileInfo>, &SyncSender<TransferResult>) + 'a {
+        move |name, dest_path, _, tx| {
+            fetched.borrow_mut().push(PathBuf::from(name));
+            if let Some(parent) = dest_path.parent() {
+                fs::create_dir_all(parent).unwrap();
+            }
+            let size = fs::copy(source.path().join(name), dest_path).unwrap();
+            tx.send(TransferResult {
+                source: name.to_owned(),
+                dest: dest_path.as_os_str().to_owned(),
+                size,
+                error: None,
+            })
+            .unwrap();
+        }
+    }
+
+    /// Clone options that work on any file system
+    fn reflink_options() -> CloneOptions {
+        CloneOptions {
+            storage: std::sync::Arc::new(crate::storage::ReflinkBackend),
+            ..Default::default()
+        }
+    }
+
+    /// Creates backup `name` with the given data files in directory `base`
+    pub(crate) fn create_test_backup_at(
+        base: &Path,
+        name: &str,
+        files: &[(&str, &[u8])],
+    ) -> Backup {
+        let backup = Backup::new(&base.to_string_lossy(), name, true).unwrap();
+        let mut manifest = String::new();
+        for (path, content) in files {
+            write_gz(&backup.path().join("data").join(path), content);
+            manifest += &manifest_line('t', path);
+            manifest += &manifest_line('f', &format!("/{}", path));
+            manifest += &manifest_line(
+                'x',
+                &format!("{}:{:x}", content.len(), md5::compute(content)),
+            );
+        }
+        write_gz(&backup.path().join("manifest.gz"), manifest.as_bytes());
+        backup
+    }
+
     #[test]
     fn test_format_bytes() {
         assert_eq!(format_bytes(1), "1.00 B");
         assert_eq!(format_bytes(234), "234.00 B");
+        assert_eq!(format_bytes(1000), "1000.00 B");
+        assert_eq!(format_bytes(1023), "1023.00 B");
         assert_eq!(format_bytes(1024), "1.00 kiB");
         assert_eq!(format_bytes(456 * 1024), "456.00 kiB");
         assert_eq!(format_bytes((2.5 * 1024.0) as u64), "2.50 kiB");
@@ -644,6 +1845,19 @@ mod test {
             format_bytes((99.0001 * 1024.0 * 1024.0) as u64),
             "99.00 MiB"
         );
+        assert_eq!(format_bytes(u64::MAX), "16.00 EiB");
+    }
+
+    #[test]
+    fn backup_from_symlink() {
+        let backup = create_test_backup("from-symlink", &[]);
+        let link = backup.path().parent().unwrap().join("current");
+        std::os::unix::fs::symlink(backup.path(), &link).unwrap();
+
+        let resolved = Backup::from_path(&link).unwrap();
+        assert_eq!(resolved.id, 1);
+        assert_eq!(resolved.path(), fs::canonicalize(backup.path()).unwrap());
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
     }
 
     #[test]
@@ -654,6 +1868,17 @@ mod test {
         );
     }
 
+    #[test]
+    fn datetime() {
+        let backup = Backup::new("/", "0000015 2019-04-13 18:02:26", true).unwrap();
+        assert_eq!(
+            backup.datetime(),
+            Some(time::macros::datetime!(2019-04-13 18:02:26))
+        );
+        let backup = Backup::new("/", "0000015 yesterday", true).unwrap();
+        assert_eq!(backup.datetime(), None);
+    }
+
     #[test]
     fn parse_name_too_short() {
         let result = Backup::parse_name("123");
@@ -671,16 +1896,10 @@ mod test {
     }
 
     #[test]
-    fn calc_md5_lorem() {
-        let lorem = "Lorem ipsum dolor sit amet, consectetur adipisici elit, sed eiusmod tempor incidunt ut labore et dolore magna aliqua";
-        let (size, digest) = calc_md5(&mut Cursor::new(lorem)).unwrap();
-        assert_eq!(size, lorem.len());
-        assert_eq!(format!("{:x}", digest), "112e6e5d321385d524234210bdebec02")
-    }
-
-    #[test]
-    fn metadata_contains_manifest() {
-        assert!(Backup::metadata_files().contains(&"manifest.gz"));
+    fn metadata_without_manifest() {
+        assert!(!Backup::metadata_files()
+            .iter()
+            .any(|name| MANIFEST_FILES.contains(name)));
     }
 
     #[test]
@@ -696,7 +1915,7 @@ mod test {
         );
     }
 
-    fn send_file_results(tx: Sender<TransferResult>, error: Option<String>) {
+    fn send_file_results(tx: std::sync::mpsc::Sender<TransferResult>, error: Option<String>) {
         tx.send(TransferResult {
             source: OsString::from("source path"),
             dest: OsString::from("first dest path"),
@@ -725,7 +1944,11 @@ mod test {
         let backup = Backup::from_path(&PathBuf::from("/0000001 2021-04-11 00:00:00")).unwrap();
         let (tx, rx) = channel();
         let sender = thread::spawn(move || send_file_results(tx, None));
-        let (num, size) = backup.wait_for_transfer(&rx, Some(&OsString::from("second dest path")));
+        let (num, size) = backup.wait_for_transfer(
+            &rx,
+            Some(&OsString::from("second dest path")),
+            &CloneOptions::default(),
+        );
         assert_eq!(num, 2);
         assert_eq!(size, 246);
         sender
@@ -738,7 +1961,7 @@ mod test {
         let backup = Backup::from_path(&PathBuf::from("/0000001 2021-04-11 00:00:00")).unwrap();
         let (tx, rx) = channel();
         let sender = thread::spawn(move || send_file_results(tx, None));
-        let (num, size) = backup.wait_for_transfer(&rx, None);
+        let (num, size) = backup.wait_for_transfer(&rx, None, &CloneOptions::default());
         assert_eq!(num, 3);
         assert_eq!(size, 369);
         sender
@@ -751,7 +1974,7 @@ mod test {
         let backup = Backup::from_path(&PathBuf::from("/0000001 2021-04-11 00:00:00")).unwrap();
         let (tx, rx) = channel();
         let sender = thread::spawn(move || send_file_results(tx, Some("test error".to_string())));
-        let (num, _size_ignored) = backup.wait_for_transfer(&rx, None);
+        let (num, _size_ignored) = backup.wait_for_transfer(&rx, None, &CloneOptions::default());
         assert_eq!(num, 0);
         sender
             .join()
@@ -811,21 +2034,857 @@ mod test {
         );
     }
 
+    #[test]
+    fn verify_report_sorted_listing() {
+        let report = VerifyReport {
+            ok: 3,
+            failed: vec![
+                (
+                    PathBuf::from("/b/data/t/2"),
+                    VerifyResult::ChecksumMismatch("abc".to_string()),
+                ),
+                (
+                    PathBuf::from("/b/data/t/1"),
+                    VerifyResult::FilesizeMismatch(12),
+                ),
+            ],
+            unwanted: vec![PathBuf::from("/b/data/t/10")],
+        };
+        assert_eq!(
+            report.sorted_listing(),
+            vec![
+                "/b/data/t/1: size mismatch, read 12 bytes",
+                "/b/data/t/10: unwanted",
+                "/b/data/t/2: checksum mismatch, computed abc",
+            ]
+        );
+    }
+
+    #[test]
+    fn verify_detailed_report() {
+        let mut backup = create_test_backup(
+            "verify-detailed",
+            &[("t/good", b"good content"), ("t/bad", b"original content")],
+        );
+        write_gz(&backup.path().join("data/t/bad"), b"corrupt content!");
+        write_gz(&backup.path().join("data/extra"), b"not in manifest");
+
+        let report = backup.verify_detailed(2).unwrap();
+        assert_eq!(report.ok, 1);
+        assert_eq!(
+            report.failed_paths(),
+            vec![backup.path().join("data/t/bad")]
+        );
+        assert!(matches!(
+            report.failed[0].1,
+            VerifyResult::ChecksumMismatch(_)
+        ));
+        assert_eq!(report.unwanted, vec![backup.path().join("data/extra")]);
+
+        assert_eq!(backup.verify(2).unwrap(), 1);
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn verify_corrupt_compression() {
+        let mut backup = create_test_backup(
+            "verify-corrupt",
+            &[("t/truncated", &[b'a'; 4096]), ("t/garbage", b"content")],
+        );
+        let truncated = backup.path().join("data/t/truncated");
+        let compressed = fs::read(&truncated).unwrap();
+        fs::write(&truncated, &compressed[..compressed.len() / 2]).unwrap();
+        let mut garbage = fs::read(backup.path().join("data/t/garbage")).unwrap();
+        garbage[10..].fill(0xff);
+        fs::write(backup.path().join("data/t/garbage"), garbage).unwrap();
+
+        let report = backup.verify_detailed(1).unwrap();
+        assert_eq!(report.ok, 0);
+        let mut failed = report.failed;
+        failed.sort_by(|a, b| a.0.cmp(&b.0));
+        assert!(matches!(
+            failed[0].1,
+            VerifyResult::CorruptCompression(10..)
+        ));
+        assert_eq!(
+            failed[1].1,
+            VerifyResult::CorruptCompression(compressed.len() as u64 / 2)
+        );
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn verify_zstd() {
+        let mut backup = create_test_backup("verify-zstd", &[("t/a", b"content")]);
+        for name in ["manifest.gz", "data/t/a"] {
+            let path = backup.path().join(name);
+            let mut plain = Vec::new();
+            flate2::read::GzDecoder::new(fs::File::open(&path).unwrap())
+                .read_to_end(&mut plain)
+                .unwrap();
+            fs::write(&path, zstd::encode_all(&plain[..], 0).unwrap()).unwrap();
+        }
+
+        assert_eq!(backup.compression().unwrap(), Compression::Zstd);
+        let report = backup.verify_detailed(1).unwrap();
+        assert_eq!(report.ok, 1);
+        assert!(report.failed.is_empty());
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn verify_sha256() {
+        let mut backup = create_test_backup("verify-sha256", &[]);
+        write_gz(&backup.path().join("data/t/a"), b"abc");
+        write_gz(
+            &backup.path().join("manifest.gz"),
+            (manifest_line('t', "t/a")
+                + &manifest_line('f', "/t/a")
+                + &manifest_line(
+                    'x',
+                    "3:BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD",
+                ))
+                .as_bytes(),
+        );
+
+        assert_eq!(
+            backup.verify_with_algo(1, ChecksumAlgo::Sha256).unwrap().ok,
+            1
+        );
+        // MD5 digests are shorter and never match a SHA-256 checksum
+        let report = backup.verify_detailed(1).unwrap();
+        assert!(matches!(
+            report.failed[0].1,
+            VerifyResult::ChecksumMismatch(_)
+        ));
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn restore_tree() {
+        let mut backup = create_test_backup("restore", &[("t/a", b"content")]);
+        write_gz(
+            &backup.path().join("manifest.gz"),
+            (manifest_line('d', "/etc/dir")
+                + &manifest_line('t', "t/a")
+                + &manifest_line('f', "/etc/dir/a")
+                + &manifest_line('x', &format!("7:{:x}", md5::compute("content")))
+                + &manifest_line('l', "/etc/link")
+                + &manifest_line('l', "dir/a"))
+                .as_bytes(),
+        );
+        let dest = backup.path().parent().unwrap().join("restored");
+
+        backup
+            .restore_to(&dest, &RestoreOptions::default())
+            .unwrap();
+        assert!(dest.join("etc/dir").is_dir());
+        assert_eq!(fs::read(dest.join("etc/dir/a")).unwrap(), b"content");
+        assert_eq!(
+            fs::read_link(dest.join("etc/link")).unwrap(),
+            PathBuf::from("dir/a")
+        );
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn restore_compressed() {
+        let mut backup = create_test_backup("restore-compressed", &[("t/a", b"content")]);
+        let dest = backup.path().parent().unwrap().join("restored");
+        let mut specs = vec!["gzip:9", "none"];
+        if cfg!(feature = "zstd") {
+            specs.push("zstd:19");
+        }
+        for spec in specs {
+            let options = RestoreOptions {
+                compression: Some(spec.parse().unwrap()),
+                ..Default::default()
+            };
+            backup.restore_to(&dest, &options).unwrap();
+        }
+
+        for (name, compression) in [
+            ("t/a.gz", Compression::Gzip),
+            ("t/a", Compression::None),
+            ("t/a.zst", Compression::Zstd),
+        ] {
+            if compression == Compression::Zstd && !cfg!(feature = "zstd") {
+                continue;
+            }
+            let mut decoder =
+                Compression::decoder(io::BufReader::new(fs::File::open(dest.join(name)).unwrap()))
+                    .unwrap();
+            assert_eq!(decoder.compression(), compression);
+            let mut content = Vec::new();
+            decoder.read_to_end(&mut content).unwrap();
+            assert_eq!(content, b"content");
+        }
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn restore_metadata() {
+        let mut backup = create_test_backup("restore-metadata", &[("t/a", b"content")]);
+        let stat = |mode: i64, mtime: i64| {
+            [
+                0, 0, mode, 1, 0, 0, 0, 7, 4096, 1, mtime, mtime, mtime, 0, 0, 0,
+            ]
+            .iter()
+            .map(|value| manifest::burp_encode_base64(*value))
+            .collect::<Vec<String>>()
+            .join(" ")
+        };
+        write_gz(
+            &backup.path().join("manifest.gz"),
+            (manifest_line('r', &stat(0o40750, 1618099200))
+                + &manifest_line('d', "/dir")
+                + &manifest_line('r', &stat(0o100640, 1618185600))
+                + &manifest_line('t', "t/a")
+                + &manifest_line('f', "/dir/a")
+                + &manifest_line('x', &format!("7:{:x}", md5::compute("content"))))
+                .as_bytes(),
+        );
+        let dest = backup.path().parent().unwrap().join("restored");
+        let options = RestoreOptions {
+            apply_metadata: true,
+            ..Default::default()
+        };
+
+        backup.restore_to(&dest, &options).unwrap();
+        let file = fs::metadata(dest.join("dir/a")).unwrap();
+        assert_eq!(file.permissions().mode() & 0o7777, 0o640);
+        assert_eq!(
+            file.modified().unwrap(),
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1618185600)
+        );
+        let dir = fs::metadata(dest.join("dir")).unwrap();
+        assert_eq!(dir.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(
+            dir.modified().unwrap(),
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1618099200)
+        );
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn restore_path_outside_dest() {
+        let dest = PathBuf::from("/restore");
+        assert_eq!(
+            restore_path(&dest, Path::new("/etc/passwd")).unwrap(),
+            PathBuf::from("/restore/etc/passwd")
+        );
+        assert!(restore_path(&dest, Path::new("/etc/../../passwd")).is_err());
+    }
+
+    #[test]
+    fn verify_with_reader() {
+        let mut files = HashMap::new();
+        let gz = |content: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Default::default());
+            encoder.write_all(content).unwrap();
+            encoder.finish().unwrap()
+        };
+        let mut manifest = String::new();
+        for (path, content) in [("t/a", "content"), ("t/b", "corrupt"), ("t/c", "missing")] {
+            manifest += &manifest_line('t', path);
+            manifest += &manifest_line('f', &format!("/{}", path));
+            manifest += &manifest_line(
+                'x',
+                &format!("{}:{:x}", content.len(), md5::compute(content)),
+            );
+        }
+        files.insert(PathBuf::from("manifest.gz"), gz(manifest.as_bytes()));
+        files.insert(PathBuf::from("data/t/a"), gz(b"content"));
+        files.insert(PathBuf::from("data/t/b"), gz(b"Corrupt"));
+        let mut backup = Backup::new(
+            "https://burp.example.com/client",
+            "0000001 2021-04-11 00:00:00",
+            false,
+        )
+        .unwrap();
+
+        let report = backup
+            .verify_with_reader(
+                &VerifyOptions::new(2),
+                &|name: &Path| match files.get(name) {
+                    Some(content) => Ok(Box::new(io::Cursor::new(content.to_vec()))),
+                    None => Err(Box::new(io::Error::from(io::ErrorKind::NotFound))),
+                },
+            )
+            .unwrap();
+        assert_eq!(report.ok, 1);
+        assert_eq!(report.missing(), 1);
+        let mut failed = report.failed_paths();
+        failed.sort();
+        assert_eq!(
+            failed,
+            vec![
+                backup.path().join("data/t/b"),
+                backup.path().join("data/t/c")
+            ]
+        );
+    }
+
+    #[test]
+    fn resume_clone() {
+        let files: &[(&str, &[u8])] = &[("t/a", b"a"), ("t/b", b"b"), ("t/c", b"c"), ("t/d", b"d")];
+        let source = create_source_backup("resume-clone", files);
+        let base = source.path().parent().unwrap().to_owned();
+        // an interrupted clone transferred a, b and a truncated c
+        let dest_dir = base.join("dest");
+        let mut dest = create_test_backup_at(&dest_dir, &source.dir_name(), &files[..2]);
+        fs::write(dest.path().join("data/t/c"), b"").unwrap();
+        fs::write(dest.path().join(".bdup.partial"), b"").unwrap();
+
+        let fetched = RefCell::new(Vec::new());
+        dest.clone_from(
+            &None,
+            "manifest.gz",
+            &copying_fetch(&source, &fetched),
+            &reflink_options(),
+        )
+        .unwrap();
+
+        let data_files = fetched
+            .into_inner()
+            .into_iter()
+            .filter(|path| path.starts_with("data"))
+            .collect::<Vec<PathBuf>>();
+        assert_eq!(
+            data_files,
+            vec![PathBuf::from("data/t/c"), PathBuf::from("data/t/d")]
+        );
+        assert!(dest.is_finished());
+        assert_eq!(dest.verify(1).unwrap(), 0);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn interrupted_clone() {
+        let files: &[(&str, &[u8])] = &[("t/a", b"a"), ("t/b", b"b"), ("t/c", b"c")];
+        let source = create_source_backup("interrupted-clone", files);
+        let base = source.path().parent().unwrap().to_owned();
+        let options = reflink_options();
+
+        let fetched = RefCell::new(Vec::new());
+        let copy = copying_fetch(&source, &fetched);
+        let fetch = |name: &OsStr,
+                     dest_path: &Path,
+                     info: Option<&DataFileInfo>,
+                     tx: &SyncSender<TransferResult>| {
+            // Ctrl-C while the first data file is transferred
+            if Path::new(name).starts_with("data") {
+                options
+                    .cancel
+                    .store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+            copy(name, dest_path, info, tx);
+        };
+        let mut dest = Backup::new(
+            &base.join("dest").to_string_lossy(),
+            &source.dir_name(),
+            true,
+        )
+        .unwrap();
+        let stats = dest
+            .clone_from(&None, "manifest.gz", &fetch, &options)
+            .unwrap();
+        assert!(stats.interrupted);
+        assert!(!dest.is_finished());
+        assert!(dest.path().join(".bdup.partial").exists());
+        assert_eq!(
+            fetched
+                .borrow()
+                .iter()
+                .filter(|path| path.starts_with("data"))
+                .count(),
+            1
+        );
+
+        options
+            .cancel
+            .store(false, std::sync::atomic::Ordering::SeqCst);
+        fetched.borrow_mut().clear();
+        let stats = dest
+            .clone_from(&None, "manifest.gz", &fetch, &options)
+            .unwrap();
+        assert!(stats.interrupted);
+        options
+            .cancel
+            .store(false, std::sync::atomic::Ordering::SeqCst);
+        let stats = dest
+            .clone_from(&None, "manifest.gz", &fetch, &options)
+            .unwrap();
+        assert!(!stats.interrupted);
+        assert!(dest.is_finished());
+        assert_eq!(dest.verify(1).unwrap(), 0);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn clone_with_exclude() {
+        let files: &[(&str, &[u8])] = &[("t/a", b"new"), ("t/cache/x", b"x"), ("u/y", b"y")];
+        let source = create_source_backup("clone-exclude", files);
+        let base_dir = source.path().parent().unwrap().to_owned();
+        let dest_dir = base_dir.join("dest");
+        let mut base = create_test_backup_at(
+            &dest_dir,
+            "0000000 2021-04-10 00:00:00",
+            &[("t/a", b"old"), ("u/y", b"old")],
+        );
+        base.load_checksums().unwrap();
+
+        let fetched = RefCell::new(Vec::new());
+        let options = CloneOptions {
+            exclude: crate::client::exclude_set(&[
+                "/t/cache".parse().unwrap(),
+                "/u/*".parse().unwrap(),
+            ])
+            .unwrap(),
+            ..reflink_options()
+        };
+        let mut dest = Backup::new(&dest_dir.to_string_lossy(), &source.dir_name(), true).unwrap();
+        let fetch = copying_fetch(&source, &fetched);
+        let stats = dest
+            .clone_from(&Some(&base), "manifest.gz", &fetch, &options)
+            .unwrap();
+
+        let data_files = fetched
+            .take()
+            .into_iter()
+            .filter(|path| path.starts_with("data"))
+            .collect::<Vec<PathBuf>>();
+        assert_eq!(data_files, vec![PathBuf::from("data/t/a")]);
+        assert_eq!(stats.files_failed, 0);
+        assert!(dest.is_finished());
+        // the copy of the base is neither replaced nor removed
+        assert_eq!(
+            fs::read(dest.path().join("data/u/y")).unwrap(),
+            fs::read(base.path().join("data/u/y")).unwrap()
+        );
+        crate::storage::ReflinkBackend.delete(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn clone_with_include() {
+        let files: &[(&str, &[u8])] = &[("t/etc/a", b"new"), ("t/usr/b", b"b"), ("t/var/c", b"c")];
+        let source = create_source_backup("clone-include", files);
+        let base_dir = source.path().parent().unwrap().to_owned();
+        let dest_dir = base_dir.join("dest");
+        let mut base = create_test_backup_at(
+            &dest_dir,
+            "0000000 2021-04-10 00:00:00",
+            &[("t/etc/a", b"old"), ("t/usr/b", b"b"), ("u/d", b"d")],
+        );
+        base.load_checksums().unwrap();
+
+        let fetched = RefCell::new(Vec::new());
+        let options = CloneOptions {
+            include: vec![PathBuf::from("/t/etc"), PathBuf::from("/not/in/backup")],
+            ..reflink_options()
+        };
+        let mut dest = Backup::new(&dest_dir.to_string_lossy(), &source.dir_name(), true).unwrap();
+        let fetch = copying_fetch(&source, &fetched);
+        let stats = dest
+            .clone_from(&Some(&base), "manifest.gz", &fetch, &options)
+            .unwrap();
+
+        let data_files = fetched
+            .take()
+            .into_iter()
+            .filter(|path| path.starts_with("data"))
+            .collect::<Vec<PathBuf>>();
+        assert_eq!(data_files, vec![PathBuf::from("data/t/etc/a")]);
+        assert_eq!(stats.files_failed, 0);
+        assert!(dest.is_finished());
+        // files of the base outside of the included directories are removed
+        let mut left = fs::read_dir(dest.path().join("data"))
+            .unwrap()
+            .chain(fs::read_dir(dest.path().join("data/t")).unwrap())
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, vec![OsStr::new("etc"), OsStr::new("t")]);
+        crate::storage::ReflinkBackend.delete(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn plan_clone() {
+        let base = create_test_backup("plan-base", &[("t/a", b"content"), ("t/b", b"old")]);
+        let source = create_test_backup("plan-source", &[("t/a", b"content"), ("t/b", b"new")]);
+        let mut base = Backup::from_path(&base.path()).unwrap();
+        base.load_checksums().unwrap();
+        let mut dest = Backup::new("/nonexistent", &source.dir_name(), true).unwrap();
+
+        dest.plan_clone_from(
+            &Some(&base),
+            Box::new(fs::File::open(source.path().join("manifest.gz")).unwrap()),
+            &CloneOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(dest.get_checksums().len(), 2);
+        assert!(!dest.path().exists());
+        fs::remove_dir_all(base.path().parent().unwrap()).unwrap();
+        fs::remove_dir_all(source.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn verify_more_files_than_queue() {
+        let names = (0..50).map(|i| format!("t/{}", i)).collect::<Vec<String>>();
+        let files = names
+            .iter()
+            .map(|name| (name.as_str(), name.as_bytes()))
+            .collect::<Vec<(&str, &[u8])>>();
+        let mut backup = create_test_backup("verify-queue", &files);
+        let progress = RefCell::new(Vec::new());
+        let report = backup
+            .verify_with_progress(1, &|done, total| progress.borrow_mut().push((done, total)))
+            .unwrap();
+        assert_eq!(report.ok, 50);
+        // progress is reported while the manifest is read, with the files found so far as total
+        let progress = progress.into_inner();
+        assert_eq!(progress.len(), 50);
+        assert_eq!(progress[0], (1, 4));
+        assert!(progress.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert_eq!(progress[49], (50, 50));
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn verify_progress() {
+        let mut backup = create_test_backup(
+            "verify-progress",
+            &[("t/a", b"a"), ("t/b", b"b"), ("t/c", b"c")],
+        );
+        let progress = RefCell::new(Vec::new());
+        backup
+            .verify_with_progress(2, &|done, total| progress.borrow_mut().push((done, total)))
+            .unwrap();
+        assert_eq!(progress.into_inner(), vec![(1, 3), (2, 3), (3, 3)]);
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn verify_sequentially_progress() {
+        let backup = create_test_backup("verify-sequential", &[("t/a", b"a"), ("t/b", b"b")]);
+        let progress = RefCell::new(Vec::new());
+        let options = VerifyOptions {
+            on_progress: &|done, total| progress.borrow_mut().push((done, total)),
+            ..VerifyOptions::new(1)
+        };
+        let report = backup
+            .verify_sequentially(&options, &|name: &Path| {
+                Ok(Box::new(fs::File::open(backup.path().join(name))?))
+            })
+            .unwrap();
+        assert_eq!(report.ok, 2);
+        assert_eq!(progress.into_inner(), vec![(1, 0), (2, 0)]);
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn verify_missing_file() {
+        let mut backup = create_test_backup(
+            "verify-missing",
+            &[("t/present", b"content"), ("t/deleted", b"content")],
+        );
+        fs::remove_file(backup.path().join("data/t/deleted")).unwrap();
+
+        let report = backup.verify_detailed(2).unwrap();
+        assert_eq!(report.ok, 1);
+        assert_eq!(report.missing(), 1);
+        assert_eq!(
+            report.failed,
+            vec![(backup.path().join("data/t/deleted"), VerifyResult::Missing)]
+        );
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn verify_calls_corruption_callback() {
+        let mut backup = create_test_backup(
+            "verify-callback",
+            &[("t/good", b"good content"), ("t/bad", b"original content")],
+        );
+        write_gz(&backup.path().join("data/t/bad"), b"corrupted content");
+
+        let corrupt = RefCell::new(Vec::new());
+        let report = backup
+            .verify_with_callback(2, &|path, _| corrupt.borrow_mut().push(path.to_owned()))
+            .unwrap();
+        assert_eq!(report.ok, 1);
+        assert_eq!(corrupt.into_inner(), vec![backup.path().join("data/t/bad")]);
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn files_iter() {
+        let backup = create_test_backup("files-iter", &[("t/a", b"abc"), ("t/b", b"")]);
+        let files = backup
+            .files_iter()
+            .collect::<Result<Vec<FileInfo>, Box<dyn Error>>>()
+            .unwrap();
+        assert_eq!(
+            files,
+            vec![
+                FileInfo {
+                    path: PathBuf::from("/t/a"),
+                    file_type: manifest::FileType::Plain,
+                    size: 3,
+                    mode: 0,
+                },
+                FileInfo {
+                    path: PathBuf::from("/t/b"),
+                    file_type: manifest::FileType::Plain,
+                    size: 0,
+                    mode: 0,
+                },
+            ]
+        );
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn uncompressed_manifest() {
+        let backup = create_test_backup("plain-manifest", &[("t/a", b"abc")]);
+        let mut manifest = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(backup.path().join("manifest.gz")).unwrap())
+            .read_to_string(&mut manifest)
+            .unwrap();
+        fs::remove_file(backup.path().join("manifest.gz")).unwrap();
+        fs::write(backup.path().join("manifest"), manifest).unwrap();
+
+        let files = backup
+            .files_iter()
+            .collect::<Result<Vec<FileInfo>, Box<dyn Error>>>()
+            .unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("/t/a"));
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn entries() {
+        let backup = create_test_backup("entries", &[("t/a", b"abc")]);
+        let entries = backup
+            .entries()
+            .unwrap()
+            .collect::<Result<Vec<manifest::ManifestEntry>, Box<dyn Error>>>()
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, PathBuf::from("/t/a"));
+        let data = entries[0].data.as_ref().unwrap();
+        assert_eq!(data.path, PathBuf::from("t/a"));
+        assert_eq!(data.md5, format!("{:x}", md5::compute("abc")));
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+
+        let missing = Backup::from_path(&PathBuf::from("/nonexistent/0000001 x")).unwrap();
+        assert!(missing.entries().is_err());
+    }
+
+    #[test]
+    fn find_entry() {
+        let backup = create_test_backup("find-entry", &[("t/a", b"abc"), ("t/b", b"de")]);
+        let entry = backup.find_entry(Path::new("/t/b")).unwrap().unwrap();
+        assert_eq!(entry.path, PathBuf::from("/t/b"));
+        assert_eq!(entry.data.unwrap().md5, format!("{:x}", md5::compute("de")));
+        assert!(backup.find_entry(Path::new("t/a")).unwrap().is_some());
+        assert!(backup.find_entry(Path::new("/t/c")).unwrap().is_none());
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+
+        let missing = Backup::from_path(&PathBuf::from("/nonexistent/0000001 x")).unwrap();
+        assert!(missing.find_entry(Path::new("/t/a")).is_err());
+    }
+
+    #[test]
+    fn files_iter_missing_manifest() {
+        let backup = Backup::from_path(&PathBuf::from("/nonexistent/0000001 x")).unwrap();
+        let mut files = backup.files_iter();
+        assert!(files.next().unwrap().is_err());
+        assert!(files.next().is_none());
+    }
+
+    #[test]
+    fn data_file_mode() {
+        let mut backup = create_test_backup("data-file-mode", &[("t/a", b"content")]);
+        let stat = [0, 0, 0o100640, 1, 0, 0, 0, 7, 4096, 1, 0, 0, 0, 0, 0, 0]
+            .iter()
+            .map(|value| manifest::burp_encode_base64(*value))
+            .collect::<Vec<String>>()
+            .join(" ");
+        write_gz(
+            &backup.path().join("manifest.gz"),
+            (manifest_line('r', &stat)
+                + &manifest_line('t', "t/a")
+                + &manifest_line('f', "/t/a")
+                + &manifest_line('x', &format!("7:{:x}", md5::compute("content"))))
+                .as_bytes(),
+        );
+
+        backup.load_checksums().unwrap();
+        let info = backup.data_file_info(Path::new("t/a")).unwrap();
+        assert_eq!(info.mode, 0o100640);
+        assert_eq!(info.size, 7);
+        assert!(backup.data_file_info(Path::new("t/b")).is_none());
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn clone_stats() {
+        let source = create_source_backup("clone-stats", &[("t/a", b"same"), ("t/b", b"new")]);
+        let base_dir = source.path().parent().unwrap().to_owned();
+        let dest_dir = base_dir.join("dest");
+        let mut base = create_test_backup_at(
+            &dest_dir,
+            "0000000 2021-04-10 00:00:00",
+            &[("t/a", b"same"), ("t/c", b"old")],
+        );
+        base.load_checksums().unwrap();
+        let shared_size = fs::metadata(base.path().join("data/t/a")).unwrap().len();
+
+        let fetched = RefCell::new(Vec::new());
+        let mut dest = Backup::new(&dest_dir.to_string_lossy(), &source.dir_name(), true).unwrap();
+        let stats = dest
+            .clone_from(
+                &Some(&base),
+                "manifest.gz",
+                &copying_fetch(&source, &fetched),
+                &reflink_options(),
+            )
+            .unwrap();
+
+        let transferred = fs::metadata(source.path().join("data/t/b")).unwrap().len()
+            + fs::metadata(source.path().join("manifest.gz"))
+                .unwrap()
+                .len();
+        assert_eq!(
+            stats,
+            CloneStats {
+                files_total: Backup::metadata_files().len() as u64 + 3,
+                files_from_base: 1,
+                files_failed: 0,
+                bytes_transferred: transferred,
+                bytes_deduplicated: shared_size,
+                interrupted: false,
+            }
+        );
+        assert!(stats.deduplicated_percent() > 0.0 && stats.deduplicated_percent() < 100.0);
+        crate::storage::ReflinkBackend.delete(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn total_size() {
+        let mut backup = create_test_backup("total-size", &[("t/a", b"abc"), ("t/b", b"de")]);
+        assert_eq!(backup.total_size().unwrap(), 5);
+        // cached, the manifest is not read again
+        fs::remove_file(backup.path().join("manifest.gz")).unwrap();
+        assert_eq!(backup.total_size().unwrap(), 5);
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn size_histogram() {
+        let mut backup = create_test_backup(
+            "size-histogram",
+            &[("t/a", b"abc"), ("t/b", b"de"), ("t/c", &[0; 2000])],
+        );
+        let histogram = backup.size_histogram().unwrap();
+        assert_eq!(histogram.buckets[0], (2, 5));
+        assert_eq!(histogram.buckets[1], (1, 2000));
+        assert_eq!((histogram.files(), histogram.bytes()), (3, 2005));
+        fs::remove_file(backup.path().join("manifest.gz")).unwrap();
+        assert_eq!(backup.total_size().unwrap(), 2005);
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+
+        let mut histogram = SizeHistogram::default();
+        histogram.add(1023);
+        histogram.add(1024);
+        histogram.add(1 << 40);
+        assert_eq!(
+            histogram.buckets,
+            [(1, 1023), (1, 1024), (0, 0), (0, 0), (1, 1 << 40)]
+        );
+    }
+
+    #[test]
+    fn diff_backups() {
+        let mut old = create_test_backup(
+            "diff",
+            &[
+                ("t/same", b"same"),
+                ("t/changed", b"old"),
+                ("t/removed", b"x"),
+            ],
+        );
+        let mut new = create_test_backup_at(
+            old.path().parent().unwrap(),
+            "0000002 2021-04-12 00:00:00",
+            &[
+                ("t/same", b"same"),
+                ("t/changed", b"newer"),
+                ("t/added", b"ab"),
+            ],
+        );
+        old.load_checksums().unwrap();
+        new.load_checksums().unwrap();
+
+        let diff = old.diff(&new);
+        assert_eq!(diff.added, vec![PathBuf::from("t/added")]);
+        assert_eq!(diff.removed, vec![PathBuf::from("t/removed")]);
+        assert_eq!(diff.changed, vec![PathBuf::from("t/changed")]);
+        assert_eq!(diff.churn_bytes, 7);
+        assert_eq!(
+            diff.to_string(),
+            "1 added, 1 removed, 1 modified, 7.00 B churn"
+        );
+        assert!(new.diff(&new).is_empty());
+        fs::remove_dir_all(old.path().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn checksum_file() {
+        let mut backup = create_test_backup("checksum-file", &[]);
+        for (path, md5, size) in [("t/b", "bbb", 2), ("t/a", "aaa", 1)] {
+            backup.checksums.insert(
+                PathBuf::from(path),
+                DataFileInfo {
+                    md5: md5.to_string(),
+                    size,
+                    mode: 0o100644,
+                },
+            );
+        }
+        backup.write_checksum_file().unwrap();
+        assert_eq!(
+            fs::read_to_string(backup.path().join(CHECKSUM_FILE)).unwrap(),
+            "aaa  1  t/a\nbbb  2  t/b\n"
+        );
+        fs::remove_dir_all(backup.path().parent().unwrap()).unwrap();
+    }
+
     #[test]
     fn top_level_dirs() {
         let mut backup = Backup::from_path(&PathBuf::from("/0000001 some timestamp")).unwrap();
         backup
             .checksums
-            .insert(PathBuf::from("t/asd"), String::new());
+            .insert(PathBuf::from("t/asd"), DataFileInfo::default());
         backup
             .checksums
-            .insert(PathBuf::from("t/asdf"), String::new());
+            .insert(PathBuf::from("t/asdf"), DataFileInfo::default());
         backup
             .checksums
-            .insert(PathBuf::from("x/asd"), String::new());
+            .insert(PathBuf::from("x/asd"), DataFileInfo::default());
         let mut expected = HashSet::new();
         expected.insert(PathBuf::from("t"));
         expected.insert(PathBuf::from("x"));
-        assert_eq!(backup.top_level_data_dirs(), expected);
+        assert_eq!(backup.wanted_data_dirs(), expected);
+
+        backup.partial = true;
+        backup
+            .checksums
+            .insert(PathBuf::from("t/etc/passwd"), DataFileInfo::default());
+        expected.insert(PathBuf::from("t/etc"));
+        assert_eq!(backup.wanted_data_dirs(), expected);
     }
 }
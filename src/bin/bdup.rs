@@ -1,53 +1,314 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::error::Error;
 use std::fs;
+use std::io;
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use threadpool::ThreadPool;
-use time::macros::format_description;
-use time::OffsetDateTime;
 
+use burp::backup::{format_bytes, Backup, RestoreOptions, SizeHistogram};
+use burp::client::exclude_set;
 use burp::client::Client;
+use burp::client::CloneOptions;
+use burp::client::CloneSummary;
+use burp::client::DateBound;
+use burp::client::GfsRetention;
+use burp::client::IdRange;
 use burp::client::LocalClient;
+use burp::client::TransferMethod;
+use burp::compression::CompressionSpec;
+use burp::expand::{expand_with, ExpandError};
+use burp::json;
+use burp::logfilter::LogFilter;
+use burp::logging::{format_record, verbosity_level, ColorMode};
+use burp::ratelimit::RateLimiter;
+use burp::storage::StorageKind;
+use globset::Glob;
+use signal_hook::consts::SIGINT;
 
 #[cfg(feature = "http")]
-use burp::remoteclient::RemoteClient;
+use burp::remoteclient::{RemoteClient, RemoteOptions};
+#[cfg(feature = "s3")]
+use burp::s3client::{S3Client, S3Options};
+#[cfg(feature = "ssh")]
+use burp::sftpclient::SftpClient;
 
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 struct Config {
     log_level: log::LevelFilter,
+    /// Log levels of single targets, e.g. "burp::manifest=trace", at most the level forced by
+    /// --quiet or --verbose
+    #[serde(skip_serializing_if = "LogFilter::is_empty")]
+    log_filter: LogFilter,
+    /// Threads copying files of local clients and verifying backups. Files of remote clients
+    /// are downloaded sequentially.
     io_threads: usize,
     dest_dir: PathBuf,
+    client_subvolumes: bool,
+    write_checksum_file: bool,
+    /// How backup volumes are created: "btrfs" (subvolumes and snapshots) or "reflink"
+    /// (plain directories, sharing unchanged files with the base backup)
+    storage: StorageKind,
+    /// btrfs executable, looked up in PATH if it has no directory part
+    btrfs_path: PathBuf,
+    /// Number of times a failed file transfer is retried
+    transfer_retries: u32,
+    /// Check each copied data file against its checksum, which costs CPU time for
+    /// decompressing it
+    verify_on_copy: bool,
+    /// Maximum throughput of all data transfers in bytes per second, unlimited if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bandwidth_limit: Option<u64>,
+    /// File locked while bdup changes the destination (default: DEST_DIR/.bdup.lock)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lock_file: Option<PathBuf>,
+    /// Logs are appended to this file in addition to the console, without colors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_file: Option<PathBuf>,
+    /// Prometheus textfile written after cloning, e.g. for the node_exporter textfile collector
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics_file: Option<PathBuf>,
+    /// Directory for manifests downloaded from remote clients, which are reused as long as they
+    /// do not change on the server. Manifests are not cached if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_dir: Option<PathBuf>,
     clients: Vec<ClientConfig>,
 }
 
 fn find_clients_at(base_dir: &Path) -> Result<Vec<ClientConfig>, Box<dyn Error>> {
-    Ok(fs::read_dir(base_dir)?
+    // storage URLs of local clients have to be absolute paths
+    Ok(fs::read_dir(fs::canonicalize(base_dir)?)?
         .filter_map(|result| result.ok())
         .filter(|entry| entry.path().is_dir())
         .map(|entry| ClientConfig {
             name: entry.file_name().to_string_lossy().to_string(),
             storage_url: entry.path().to_string_lossy().to_string(),
+            ..Default::default()
         })
         .collect())
 }
 
+impl Config {
+    /// Directory of the cloned backups of client `conf`
+    fn client_dest(&self, conf: &ClientConfig) -> PathBuf {
+        conf.dest_dir
+            .as_ref()
+            .unwrap_or(&self.dest_dir)
+            .join(&conf.name)
+    }
+
+    /// Checks the settings that would otherwise only fail while cloning
+    fn validate(&self) -> Result<(), String> {
+        if self.dest_dir.as_os_str().is_empty() {
+            return Err("No destination directory configured, use dest_dir or --dest-dir".into());
+        }
+        if !parent_exists(&self.dest_dir) {
+            return Err(format!(
+                "Parent of destination directory {} does not exist",
+                self.dest_dir.display()
+            ));
+        }
+        if self.io_threads < 1 {
+            return Err("io_threads must be at least 1".into());
+        }
+        if self.clients.is_empty() {
+            return Err("No clients configured, use clients, --client or --local-clients".into());
+        }
+        let mut names = HashSet::new();
+        for conf in &self.clients {
+            if conf.name.is_empty() || conf.name.contains('/') {
+                return Err(format!(
+                    "Invalid client name {:?}, it is used as directory name",
+                    conf.name
+                ));
+            }
+            if !names.insert(&conf.name) {
+                return Err(format!("Client {} is configured twice", conf.name));
+            }
+            if !is_supported_url(&conf.storage_url) {
+                return Err(format!(
+                    "Invalid storage URL {:?} of client {}: expected an absolute path or an \
+                     http(s):// or sftp:// URL",
+                    conf.storage_url, conf.name
+                ));
+            }
+            if conf.transfer == TransferMethod::BtrfsSend && !conf.storage_url.starts_with('/') {
+                return Err(format!(
+                    "Client {} uses btrfs_send, which needs a local path as storage URL, not {:?}",
+                    conf.name, conf.storage_url
+                ));
+            }
+            if let Some(dir) = &conf.dest_dir {
+                if !parent_exists(dir) {
+                    return Err(format!(
+                        "Parent of destination directory {} of client {} does not exist",
+                        dir.display(),
+                        conf.name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether the directory containing `path` exists. Relative paths without directory
+/// part are in the current directory.
+fn parent_exists(path: &Path) -> bool {
+    path.parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .is_none_or(Path::is_dir)
+}
+
+/// Returns whether `url` is handled by `create_client`
+fn is_supported_url(url: &str) -> bool {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.starts_with('/');
+    };
+    matches!(scheme, "http" | "https" | "sftp") && !rest.is_empty()
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             log_level: log::LevelFilter::Info,
+            log_filter: LogFilter::default(),
             io_threads: 4,
             dest_dir: PathBuf::new(),
+            client_subvolumes: false,
+            write_checksum_file: false,
+            storage: StorageKind::default(),
+            btrfs_path: PathBuf::from("btrfs"),
+            transfer_retries: 2,
+            verify_on_copy: false,
+            bandwidth_limit: None,
+            lock_file: None,
+            log_file: None,
+            metrics_file: None,
+            cache_dir: None,
             clients: Vec::new(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 struct ClientConfig {
     name: String,
     storage_url: String,
+    /// Clone to this directory instead of the global dest_dir. Its parent directory must exist.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dest_dir: Option<PathBuf>,
+    #[serde(default)]
+    transfer: TransferMethod,
+    /// Command prefix to run `btrfs send` on the source host (e.g. via ssh). The backups are
+    /// sent from storage_url, which must be a path that exists on that host.
+    #[serde(default)]
+    send_command: Vec<String>,
+    /// Only clone backups with ids in these ranges (e.g. "100-200")
+    #[serde(default)]
+    include_ids: Vec<IdRange>,
+    /// Do not clone backups with ids in these ranges
+    #[serde(default)]
+    exclude_ids: Vec<IdRange>,
+    /// Only clone backups with at least this id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_id: Option<u64>,
+    /// Only clone backups with at most this id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_id: Option<u64>,
+    /// Only clone backups taken on or after this date (YYYY-MM-DD [hh:mm:ss])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    since: Option<DateBound>,
+    /// Only clone backups taken on or before this date (YYYY-MM-DD [hh:mm:ss])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    until: Option<DateBound>,
+    /// Only clone files below these directories (e.g. "/etc"), all files if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<PathBuf>,
+    /// Do not clone files whose path or a parent directory matches one of these patterns
+    /// (e.g. "/home/*/.cache")
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclude: Vec<Glob>,
+    /// Keep only the newest N finished backups in the destination. Not applied to uploads to
+    /// `s3`, like `retention`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keep_last: Option<usize>,
+    /// Keep the newest backup of each of the last days, weeks and months
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retention: Option<GfsRetention>,
+    /// User name for HTTP basic auth
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    /// Password for HTTP basic auth
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    /// Bearer token sent with each request. "env:NAME" reads the token from environment
+    /// variable NAME.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    /// PEM file with an additional trusted root certificate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_cert: Option<PathBuf>,
+    /// PEM files with certificate and private key for TLS client authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_cert: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_key: Option<PathBuf>,
+    /// Number of attempts for each HTTP request (default: 3)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_attempts: Option<u32>,
+    /// Delay before retrying a failed HTTP request in milliseconds, doubled for each further
+    /// attempt (default: 1000)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_delay_ms: Option<u64>,
+    /// Maximum time for connecting to the server in milliseconds (default: 30000)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connect_timeout_ms: Option<u64>,
+    /// Maximum time to wait for a response or for more data of a download in milliseconds,
+    /// 0 to wait forever (default: 60000)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    read_timeout_ms: Option<u64>,
+    /// URL of the files of backups on the server (default: "{base}/{backup}/{file}"), with
+    /// placeholders {base} (storage_url), {client} (name), {backup} (directory name of the
+    /// backup), {id} (backup id) and {file} (path in the backup). Backups are listed at the part
+    /// before {backup} or {id}.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url_template: Option<String>,
+    /// Private key file for sftp:// URLs. Without one, the keys of the ssh agent are used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ssh_key: Option<PathBuf>,
+    /// Upload the backups to this bucket instead of cloning them to the destination directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    s3: Option<S3Config>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct S3Config {
+    endpoint: String,
+    #[serde(default = "default_s3_region")]
+    region: String,
+    bucket: String,
+    /// Prefix of all object keys, followed by the client name
+    #[serde(default)]
+    prefix: String,
+    /// "env:NAME" reads the key from environment variable NAME
+    access_key: String,
+    /// "env:NAME" reads the key from environment variable NAME
+    secret_key: String,
+    /// Address the bucket in the URL path, as required by MinIO
+    #[serde(default)]
+    path_style: bool,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
 }
 
 impl Eq for ClientConfig {}
@@ -57,39 +318,210 @@ impl PartialEq for ClientConfig {
     }
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ConfigFormat {
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// Format of the config file given in `args`, detected by its extension unless set
+    /// explicitly. YAML is used for other extensions and without config file.
+    fn of(args: &Args) -> Self {
+        if let Some(format) = args.config_format {
+            return format;
+        }
+        match args
+            .config_file
+            .as_ref()
+            .and_then(|file| Path::new(file).extension())
+        {
+            Some(extension) if extension == "toml" => ConfigFormat::Toml,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+
+    fn parse(&self, file: &str) -> Result<Config, Box<dyn Error>> {
+        match self {
+            ConfigFormat::Yaml => Ok(serde_yaml::from_reader(fs::File::open(file)?)?),
+            #[cfg(feature = "toml")]
+            ConfigFormat::Toml => Ok(toml::from_str(&fs::read_to_string(file)?)?),
+            #[cfg(not(feature = "toml"))]
+            ConfigFormat::Toml => Err("bdup is compiled without \"toml\" feature".into()),
+        }
+    }
+
+    fn dump(&self, config: &Config) -> Result<String, Box<dyn Error>> {
+        match self {
+            ConfigFormat::Yaml => Ok(serde_yaml::to_string(config)?),
+            #[cfg(feature = "toml")]
+            ConfigFormat::Toml => Ok(toml::to_string(config)?),
+            #[cfg(not(feature = "toml"))]
+            ConfigFormat::Toml => Err("bdup is compiled without \"toml\" feature".into()),
+        }
+    }
+}
+
 fn read_config(args: &Args) -> Result<Config, Box<dyn Error>> {
+    read_config_with(args, &|name| std::env::var(name).ok())
+}
+
+/// Like `read_config`, but expands variables in paths with the values returned by `lookup`
+fn read_config_with(
+    args: &Args,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<Config, Box<dyn Error>> {
     let mut config = Config::default();
     if let Some(file) = &args.config_file {
-        config = serde_yaml::from_reader(fs::File::open(file)?)?;
+        config = ConfigFormat::of(args).parse(file)?;
     }
 
-    if let Some(level) = args.log_level {
+    if let Some(level) = verbosity_level(args.quiet, args.verbose).or(args.log_level) {
         config.log_level = level;
     }
+    if let Some(filter) = &args.log_filter {
+        config.log_filter = filter.to_owned();
+    }
+    if let Some(level) = verbosity_level(args.quiet, args.verbose) {
+        config.log_filter = config.log_filter.capped(level);
+    }
     if let Some(path) = &args.dest_dir {
         config.dest_dir = PathBuf::from(path);
     }
     if let Some(num) = args.iothreads {
         config.io_threads = num.try_into()?;
     }
+    if let Some(limit) = args.bandwidth_limit {
+        config.bandwidth_limit = Some(limit);
+    }
+    if args.verify_on_copy {
+        config.verify_on_copy = true;
+    }
+    if let Some(path) = &args.log_file {
+        config.log_file = Some(path.to_owned());
+    }
+    if let Some(path) = &args.metrics_file {
+        config.metrics_file = Some(path.to_owned());
+    }
+    if let Some(path) = &args.cache_dir {
+        config.cache_dir = Some(path.to_owned());
+    }
+    if args.no_cache {
+        config.cache_dir = None;
+    }
     config.clients.extend(args.client.to_vec());
     for dir in &args.local_clients {
-        config.clients.extend(find_clients_at(&PathBuf::from(dir))?);
+        config
+            .clients
+            .extend(find_clients_at(&PathBuf::from(expand_with(dir, lookup)?))?);
+    }
+    config.dest_dir = expand_path(&config.dest_dir, lookup)?;
+    if let Some(path) = &config.log_file {
+        config.log_file = Some(expand_path(path, lookup)?);
+    }
+    if let Some(path) = &config.metrics_file {
+        config.metrics_file = Some(expand_path(path, lookup)?);
+    }
+    if let Some(path) = &config.cache_dir {
+        config.cache_dir = Some(expand_path(path, lookup)?);
+    }
+    for client in &mut config.clients {
+        client.storage_url = strip_file_scheme(&expand_with(&client.storage_url, lookup)?).into();
+        if let Some(dir) = &client.dest_dir {
+            client.dest_dir = Some(expand_path(dir, lookup)?);
+        }
+    }
+    if !args.only_client.is_empty() {
+        for name in &args.only_client {
+            if !config.clients.iter().any(|client| &client.name == name) {
+                let available = config
+                    .clients
+                    .iter()
+                    .map(|client| client.name.as_str())
+                    .collect::<Vec<&str>>();
+                return Err(format!(
+                    "Unknown client {}, available clients: {}",
+                    name,
+                    available.join(", ")
+                )
+                .into());
+            }
+        }
+        config
+            .clients
+            .retain(|client| args.only_client.contains(&client.name));
+    }
+    for client in &mut config.clients {
+        if args.min_id.is_some() {
+            client.min_id = args.min_id;
+        }
+        if args.max_id.is_some() {
+            client.max_id = args.max_id;
+        }
+        if args.since.is_some() {
+            client.since = args.since;
+        }
+        if args.until.is_some() {
+            client.until = args.until;
+        }
+        client.include.extend(args.include.iter().cloned());
+        client.exclude.extend(args.exclude.iter().cloned());
     }
 
     Ok(config)
 }
 
+/// `url` without `file://` or `file:`, as local clients need a plain path
+fn strip_file_scheme(url: &str) -> &str {
+    url.strip_prefix("file://")
+        .or_else(|| url.strip_prefix("file:"))
+        .unwrap_or(url)
+}
+
+/// Expands environment variables and `~` in `path`, see `burp::expand`
+fn expand_path(
+    path: &Path,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<PathBuf, ExpandError> {
+    Ok(PathBuf::from(expand_with(&path.to_string_lossy(), lookup)?))
+}
+
 fn parse_client_arg(input: &str) -> Result<ClientConfig, String> {
     let mut split = input.splitn(2, '=');
     Ok(ClientConfig {
         name: split.next().unwrap().to_string(),
         storage_url: split.next().unwrap().to_string(),
+        ..Default::default()
     })
 }
 
+/// Some clients or backups failed, e.g. a backup could not be cloned or verified
+const EXIT_PARTIAL_FAILURE: i32 = 1;
+/// The config could not be read or is invalid
+const EXIT_CONFIG_ERROR: i32 = 2;
+/// Nothing was done, e.g. all clients failed or the destination is locked
+const EXIT_TOTAL_FAILURE: i32 = 3;
+/// Cloning was interrupted by SIGINT, like shells report processes killed by it
+const EXIT_INTERRUPTED: i32 = 130;
+
+const EXIT_CODES_HELP: &str = "Exit codes:
+    0  success
+    1  some clients or backups failed
+    2  invalid config
+    3  all clients failed or the destination is locked
+  130  interrupted by Ctrl-C, the next run resumes unfinished backups";
+
+/// Stops cloning on the first SIGINT by setting `cancel`, and exits immediately on the second
+fn install_sigint_handler(cancel: &Arc<AtomicBool>) -> io::Result<()> {
+    // handlers run in the order they are registered, so the shutdown only sees the flag set by
+    // an earlier SIGINT
+    signal_hook::flag::register_conditional_shutdown(SIGINT, EXIT_INTERRUPTED, cancel.clone())?;
+    signal_hook::flag::register(SIGINT, cancel.clone())?;
+    Ok(())
+}
+
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(author, version, about, after_help = EXIT_CODES_HELP)]
 struct Args {
     /// Set log level
     ///
@@ -97,6 +529,33 @@ struct Args {
     #[arg(short, long, value_enum, value_name = "LEVEL")]
     log_level: Option<log::LevelFilter>,
 
+    /// Only log warnings and errors, overriding the log level
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log debug messages, or trace messages if given twice, overriding the log level
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log levels of single targets, e.g. "burp::manifest=trace,burp::client=debug"
+    ///
+    /// Targets include their submodules. Other targets use the global log level. --quiet and
+    /// --verbose limit the levels of all targets as well.
+    #[arg(long, value_name = "FILTER")]
+    log_filter: Option<LogFilter>,
+
+    /// Colorize log levels: auto, always or never
+    ///
+    /// With auto, colors are only used if logs are written to a terminal.
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Also append logs to FILE, without colors
+    ///
+    /// If FILE cannot be opened, a warning is logged and the run continues.
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
     /// Define client. Format: name=URL
     #[arg(short, long, value_parser = parse_client_arg)]
     client: Vec<ClientConfig>,
@@ -105,6 +564,12 @@ struct Args {
     #[arg(short = 'L', long, value_name = "DIR")]
     local_clients: Vec<String>,
 
+    /// Only process client NAME, can be given multiple times
+    ///
+    /// Applies to the clients of the config file and the command line.
+    #[arg(long, value_name = "NAME")]
+    only_client: Vec<String>,
+
     /// Destination directory
     #[arg(short, long, value_name = "DIR")]
     dest_dir: Option<String>,
@@ -113,102 +578,1485 @@ struct Args {
     #[arg(short = 'f', long, value_name = "FILE")]
     config_file: Option<String>,
 
+    /// Format of the config file, detected by its extension (".toml" or YAML otherwise) if
+    /// not given
+    ///
+    /// --dump-config uses the same format.
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    config_format: Option<ConfigFormat>,
+
     /// Dump config to stdout and exit
     #[arg(short = 'C', long)]
     dump_config: bool,
 
     /// Thread pool size for I/O operations (i.e. copying files)
+    ///
+    /// Files of clients with an http(s):// or sftp:// storage URL are downloaded one after
+    /// another, so only local clients are copied in parallel.
     #[arg(short = 't', long)]
     iothreads: Option<u64>,
+
+    /// Limit the throughput of all data transfers to BYTES per second
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    bandwidth_limit: Option<u64>,
+
+    /// Check each copied data file against the checksum in the manifest
+    #[arg(long)]
+    verify_on_copy: bool,
+
+    /// Only log which backups would be cloned and deleted, without changing anything
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+
+    /// Only clone backups with at least id ID, for all clients
+    ///
+    /// Backups outside of the id range are not deleted from the destination.
+    #[arg(long, value_name = "ID")]
+    min_id: Option<u64>,
+
+    /// Only clone backups with at most id ID, for all clients
+    #[arg(long, value_name = "ID")]
+    max_id: Option<u64>,
+
+    /// Only clone backups taken on or after DATE (YYYY-MM-DD [hh:mm:ss]), for all clients
+    ///
+    /// Backups with timestamps that cannot be parsed are cloned regardless of the date.
+    #[arg(long, value_name = "DATE")]
+    since: Option<DateBound>,
+
+    /// Only clone backups taken on or before DATE (YYYY-MM-DD [hh:mm:ss]), for all clients
+    #[arg(long, value_name = "DATE")]
+    until: Option<DateBound>,
+
+    /// Only clone files below directory DIR, for all clients
+    ///
+    /// Can be given multiple times, e.g. `--include /etc --include /home`. Other files are
+    /// removed from the clone, even if a base backup contains them. A directory that is not in
+    /// a backup is no error, it just matches nothing. --exclude applies to the included files.
+    /// `verify` reports files that are not included as missing.
+    #[arg(long, value_name = "DIR")]
+    include: Vec<PathBuf>,
+
+    /// Do not clone files whose path matches PATTERN, for all clients
+    ///
+    /// Can be given multiple times. `*` and `?` do not match '/', `**` does. A matching
+    /// directory excludes all files below it. Excluded files are kept in the destination if a
+    /// base backup contains them. `verify` reports excluded files as missing.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<Glob>,
+
+    /// Do not lock the destination, allowing concurrent runs on it
+    #[arg(long)]
+    no_lock: bool,
+
+    /// Write Prometheus metrics of the run to PATH (textfile collector format)
+    ///
+    /// The file is replaced atomically. The last success timestamp of clients that failed is
+    /// taken from the previous file.
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// Cache manifests of remote clients in DIR between runs
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Always download manifests of remote clients, ignoring the configured cache directory
+    #[arg(long, conflicts_with = "cache_dir")]
+    no_cache: bool,
+
+    #[command(subcommand)]
+    command: Option<Action>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Print compiled-in features and detected system tools as JSON and exit
+    Capabilities,
+    /// List the backups of all configured clients without cloning anything
+    ///
+    /// Prints one tab separated line per backup: client, id, timestamp, state and size in
+    /// bytes. The size of local backups is the uncompressed size of their files according to
+    /// the manifest. Unknown states (of remote backups) and sizes are printed as "-".
+    List {
+        /// Print a JSON array instead
+        #[arg(long)]
+        json: bool,
+
+        /// Also print the number and size of files by size class for local, finished backups
+        ///
+        /// The classes are printed as indented lines below each backup, or as "histogram"
+        /// array with JSON.
+        #[arg(long)]
+        stats: bool,
+    },
+    /// Verify the cloned backups of all configured clients in the destination directory
+    ///
+    /// Exits with status 1 if any backup fails to verify.
+    Verify,
+    /// Delete unfinished backups left over by interrupted runs from the destination directory
+    ///
+    /// With --dry-run, the backups are only listed. If another run holds the destination's
+    /// lock, the newest backup of each client is kept, because it might be cloned right now.
+    Prune,
+    /// Print the manifest of BACKUP as CSV and exit
+    ///
+    /// Prints one row per entry with path, type, size, md5, mode (octal), mtime (seconds since
+    /// the epoch) and permissions like `ls -l` shows them. No config is needed.
+    ManifestCsv {
+        /// Directory of the backup
+        backup: PathBuf,
+    },
+    /// Extract the files of BACKUP into DEST and exit
+    ///
+    /// Restores directories, regular files and soft links. No config is needed.
+    Restore {
+        /// Directory of the backup
+        backup: PathBuf,
+        /// Directory to extract the files to
+        dest: PathBuf,
+        /// Apply mode, modification time and ownership stored in the manifest
+        #[arg(long)]
+        metadata: bool,
+        /// Write files compressed: "gzip[:LEVEL]" (0-9, default 6), "zstd[:LEVEL]" (1-22,
+        /// default 3) or "none"
+        ///
+        /// The extension of the codec (".gz" or ".zst") is appended to the file names. Higher
+        /// levels save space at the cost of CPU time. zstd at its default level compresses
+        /// better and faster than gzip, zstd levels above 19 need much more memory.
+        #[arg(long, value_name = "SPEC")]
+        compression: Option<CompressionSpec>,
+    },
+}
+
+/// Checks whether `program` can be executed by running it with `--version`.
+fn tool_available(program: &Path) -> bool {
+    Command::new(program)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Checks whether files in `dir` can be cloned with `cp --reflink=always`.
+fn reflink_supported(dir: &Path) -> bool {
+    let source = dir.join(".bdup.reflink-probe");
+    let dest = dir.join(".bdup.reflink-probe.clone");
+    if fs::write(&source, b"probe").is_err() {
+        return false;
+    }
+    let supported = Command::new("cp")
+        .arg("--reflink=always")
+        .arg(&source)
+        .arg(&dest)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false);
+    let _ = fs::remove_file(&source);
+    let _ = fs::remove_file(&dest);
+    supported
+}
+
+/// Machine-readable description of this build, meant for orchestration tools.
+///
+/// Reflink support depends on the file system, so it is probed in the destination directory and
+/// reported as `null` if that directory does not exist.
+fn capabilities(config: &Config) -> String {
+    let features = [
+        ("http", cfg!(feature = "http")),
+        ("ssh", cfg!(feature = "ssh")),
+        ("s3", cfg!(feature = "s3")),
+        ("toml", cfg!(feature = "toml")),
+        ("zstd", cfg!(feature = "zstd")),
+        ("systemd", cfg!(feature = "systemd")),
+    ]
+    .iter()
+    .map(|(name, enabled)| format!("\"{}\":{}", name, enabled))
+    .collect::<Vec<String>>()
+    .join(",");
+    let reflink = if config.dest_dir.is_dir() {
+        reflink_supported(&config.dest_dir).to_string()
+    } else {
+        "null".to_string()
+    };
+    format!(
+        "{{\"name\":\"{}\",\"version\":\"{}\",\"features\":{{{}}},\"tools\":{{\"btrfs\":{},\"reflink\":{}}}}}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        features,
+        tool_available(&config.btrfs_path),
+        reflink
+    )
 }
 
 fn main() {
     let matches = Args::parse();
+    if let Some(Action::ManifestCsv { backup }) = &matches.command {
+        let result = Backup::from_path(backup)
+            .and_then(|backup| backup.write_csv(&mut std::io::stdout().lock()));
+        if let Err(err) = result {
+            eprintln!("Could not export manifest of {}: {}", backup.display(), err);
+            std::process::exit(EXIT_PARTIAL_FAILURE);
+        }
+        return;
+    }
+    if let Some(Action::Restore {
+        backup,
+        dest,
+        metadata,
+        compression,
+    }) = &matches.command
+    {
+        let options = RestoreOptions {
+            apply_metadata: *metadata,
+            compression: *compression,
+        };
+        let result =
+            Backup::from_path(backup).and_then(|mut backup| backup.restore_to(dest, &options));
+        if let Err(err) = result {
+            eprintln!("Could not restore {}: {}", backup.display(), err);
+            std::process::exit(EXIT_PARTIAL_FAILURE);
+        }
+        return;
+    }
     let config = read_config(&matches).unwrap_or_else(|err| {
-        panic!("Could not parse config: {:?}", err);
+        eprintln!("Could not parse config: {}", err);
+        std::process::exit(EXIT_CONFIG_ERROR);
     });
     if matches.dump_config {
         println!(
             "{}",
-            serde_yaml::to_string(&config)
+            ConfigFormat::of(&matches)
+                .dump(&config)
                 .unwrap_or_else(|err| panic!("Could not serialize config: {:?}", err))
         );
         return;
     }
+    if let Some(Action::Capabilities) = matches.command {
+        println!("{}", capabilities(&config));
+        return;
+    }
 
-    // TODO: sanity checks? e.g. dest_dir has to be a valid path
-
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            let tstamp = match OffsetDateTime::now_local() {
-                Ok(time) => time.format(format_description!(
-                    "[year]-[month]-[day] [hour]:[minute]:[second]"
-                )),
-                _ => OffsetDateTime::now_utc().format(format_description!(
-                    "[year]-[month]-[day] [hour]:[minute]:[second] UTC"
-                )),
-            }
-            .unwrap();
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                tstamp,
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
+    // listings are printed to stdout, so keep logs out of them
+    let (log_output, is_terminal): (Box<dyn Write + Send>, bool) = match matches.command {
+        Some(Action::List { .. }) => (Box::new(std::io::stderr()), std::io::stderr().is_terminal()),
+        _ => (Box::new(std::io::stdout()), std::io::stdout().is_terminal()),
+    };
+    let color = matches.color.enabled(is_terminal);
+
+    let mut dispatch = fern::Dispatch::new();
+    for (target, level) in &config.log_filter.targets {
+        dispatch = dispatch.level_for(target.to_owned(), *level);
+    }
+    dispatch = dispatch.chain(
+        fern::Dispatch::new()
+            .format(move |out, message, record| format_record(out, message, record, color))
+            .chain(log_output),
+    );
+    let mut log_file_error = None;
+    if let Some(path) = &config.log_file {
+        match fern::log_file(path) {
+            Ok(file) => {
+                dispatch = dispatch.chain(
+                    fern::Dispatch::new()
+                        .format(|out, message, record| format_record(out, message, record, false))
+                        .chain(file),
+                )
+            }
+            Err(err) => log_file_error = Some((path, err)),
+        }
+    }
+    dispatch
         .level(config.log_level)
-        .chain(std::io::stdout())
         .apply()
         .unwrap_or_else(|err| panic!("Log init failed: {:?}", err));
+    if let Some((path, err)) = log_file_error {
+        log::warn!("Could not open log file {}: {}", path.display(), err);
+    }
+
+    if let Err(err) = config.validate() {
+        log::error!("{}", err);
+        std::process::exit(EXIT_CONFIG_ERROR);
+    }
+    #[cfg(feature = "systemd")]
+    burp::notify::ready();
+
+    // shared by all clients, so the limit applies to the whole run
+    let limiter = config
+        .bandwidth_limit
+        .map(|limit| Arc::new(RateLimiter::new(limit)));
+    let cancel = Arc::new(AtomicBool::new(false));
+    if let Some(Action::Verify) = matches.command {
+        if !verify_clones(&config) {
+            std::process::exit(EXIT_PARTIAL_FAILURE);
+        }
+        return;
+    }
+    // only runs that change the destination take the lock. It is released on exit.
+    let lock_path = config
+        .lock_file
+        .clone()
+        .unwrap_or_else(|| config.dest_dir.join(".bdup.lock"));
+    let lock = match matches.command {
+        None | Some(Action::Prune) if !matches.no_lock && !matches.dry_run => {
+            match lock_destination(&lock_path) {
+                Ok(lock) => Some(lock),
+                Err(err) => {
+                    log::error!("{}", err);
+                    std::process::exit(EXIT_TOTAL_FAILURE);
+                }
+            }
+        }
+        _ => None,
+    };
+    if let Some(Action::Prune) = matches.command {
+        let busy = lock.is_none() && is_locked(&lock_path);
+        if !prune(&config, matches.dry_run, busy) {
+            std::process::exit(EXIT_PARTIAL_FAILURE);
+        }
+        return;
+    }
 
-    let mut clients: Vec<Box<dyn Client>> = Vec::new();
-    for conf in config.clients {
+    let mut clients: Vec<(Box<dyn Client>, CloneOptions, PathBuf)> = Vec::new();
+    let mut failed_clients = HashSet::new();
+    for conf in &config.clients {
         log::debug!("Loading list of existing backups for client {}", &conf.name);
-        let mut client = create_client(&conf);
-        client
-            .find_backups(&conf.storage_url)
-            .unwrap_or_else(|err| {
-                log::error!(
-                    "Could not find backups for client {}: {:?}",
-                    &conf.name,
-                    err
+        let mut client = match create_client(conf, config.cache_dir.as_deref()) {
+            Ok(client) => client,
+            Err(err) => {
+                log::error!("Could not create client {}: {}", &conf.name, err);
+                failed_clients.insert(conf.name.as_str());
+                continue;
+            }
+        };
+        let options = match clone_options(&config, conf, matches.dry_run, &limiter, &cancel) {
+            Ok(options) => options,
+            Err(err) => {
+                log::error!("Invalid exclude pattern for client {}: {}", &conf.name, err);
+                failed_clients.insert(conf.name.as_str());
+                continue;
+            }
+        };
+        // without the list of source backups, every clone would count as vanished and be deleted
+        if let Err(err) = client.find_backups(&conf.storage_url) {
+            log::error!(
+                "Could not find backups for client {}: {:?}",
+                &conf.name,
+                err
+            );
+            failed_clients.insert(conf.name.as_str());
+            continue;
+        }
+        clients.push((client, options, config.client_dest(conf)));
+    }
+
+    if let Some(Action::List { json, stats }) = matches.command {
+        print!("{}", list_backups(&mut clients, json, stats));
+        return;
+    }
+
+    if let Err(err) = install_sigint_handler(&cancel) {
+        log::warn!("Could not install SIGINT handler: {}", err);
+    }
+    let summaries = clone_backups(&clients, config.io_threads, matches.dry_run);
+    print!("{}", summary_table(&summaries));
+    failed_clients.extend(
+        summaries
+            .iter()
+            .filter(|result| result.summary.is_none_or(|summary| summary.failed > 0))
+            .map(|result| result.name.as_str()),
+    );
+    if let Some(path) = config.metrics_file.as_ref().filter(|_| !matches.dry_run) {
+        if let Err(err) = write_metrics(path, &config, &summaries, &failed_clients) {
+            log::error!("Could not write metrics to {}: {}", path.display(), err);
+        }
+    }
+    if cancel.load(Ordering::SeqCst) {
+        std::process::exit(EXIT_INTERRUPTED);
+    } else if failed_clients.len() == config.clients.len() {
+        std::process::exit(EXIT_TOTAL_FAILURE);
+    } else if !failed_clients.is_empty() {
+        std::process::exit(EXIT_PARTIAL_FAILURE);
+    }
+}
+
+/// Verifies all finished backups of the configured clients in the destination directory and
+/// logs a summary per client and for all clients. Returns whether all backups verified.
+fn verify_clones(config: &Config) -> bool {
+    let (mut total, mut failed) = (0, 0);
+    for conf in &config.clients {
+        let mut client = LocalClient::new(&conf.name);
+        let dest = config.client_dest(conf);
+        if let Err(err) = client.find_backups(&dest.to_string_lossy()) {
+            log::error!(
+                "Could not find cloned backups of client {} in {}: {:?}",
+                &conf.name,
+                dest.display(),
+                err
+            );
+            failed += 1;
+            continue;
+        }
+
+        let mut ids = client
+            .backups()
+            .values()
+            .filter(|backup| backup.is_finished())
+            .map(|backup| backup.id)
+            .collect::<Vec<u64>>();
+        ids.sort_unstable();
+        let mut client_failed = 0;
+        for id in &ids {
+            let backup = client.backups_mut().get_mut(id).unwrap();
+            match backup.verify(config.io_threads) {
+                Ok(0) => (),
+                Ok(errors) => {
+                    log::error!(
+                        "Backup {} has {} damaged files",
+                        backup.path().display(),
+                        errors
+                    );
+                    client_failed += 1;
+                }
+                Err(err) => {
+                    log::error!(
+                        "Verify of backup {} failed: {:?}",
+                        backup.path().display(),
+                        err
+                    );
+                    client_failed += 1;
+                }
+            }
+        }
+        log::info!(
+            "Client {}: {}/{} backups verified successfully",
+            &conf.name,
+            ids.len() - client_failed,
+            ids.len()
+        );
+        total += ids.len();
+        failed += client_failed;
+    }
+    log::info!(
+        "Verified {} backups of {} clients, {} failed",
+        total,
+        config.clients.len(),
+        failed
+    );
+    failed == 0
+}
+
+/// Sum of the sizes of all files below `path`. Files shared with other backups (e.g. snapshots
+/// or hardlinks) are counted, too, so this is an upper bound of the space used by `path`.
+fn disk_usage(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => disk_usage(&entry.path()),
+            _ => entry.metadata().map_or(0, |meta| meta.len()),
+        })
+        .sum()
+}
+
+/// Takes an exclusive lock on `path`, which is created if necessary. The lock is held until the
+/// returned file is closed.
+fn lock_destination(path: &Path) -> Result<fs::File, String> {
+    let describe =
+        |err: &dyn std::fmt::Display| format!("Could not lock {}: {}", path.display(), err);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|err| describe(&err))?;
+    }
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|err| describe(&err))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(fs::TryLockError::WouldBlock) => Err(format!(
+            "Another bdup run holds the lock {}, use --no-lock to run anyway",
+            path.display()
+        )),
+        Err(fs::TryLockError::Error(err)) => Err(describe(&err)),
+    }
+}
+
+/// Checks whether another process holds the lock on `path`
+fn is_locked(path: &Path) -> bool {
+    match fs::File::open(path) {
+        Ok(file) => matches!(file.try_lock_shared(), Err(fs::TryLockError::WouldBlock)),
+        Err(_) => false,
+    }
+}
+
+/// Deletes the unfinished backups of all configured clients in the destination directory.
+/// If `busy`, another run might be cloning the newest backup of a client, so it is kept.
+/// Returns whether all of them were deleted.
+fn prune(config: &Config, dry_run: bool, busy: bool) -> bool {
+    let storage = config.storage.backend(&config.btrfs_path);
+    let mut success = true;
+    let mut reclaimed = 0;
+    for conf in &config.clients {
+        let dest = config.client_dest(conf);
+        if !dest.exists() {
+            continue;
+        }
+        let mut client = LocalClient::new(&conf.name);
+        if let Err(err) = client.find_backups(&dest.to_string_lossy()) {
+            log::error!(
+                "Could not find cloned backups of client {} in {}: {:?}",
+                &conf.name,
+                dest.display(),
+                err
+            );
+            success = false;
+            continue;
+        }
+
+        let newest = client.latest_backup().map(|backup| backup.id);
+        let mut unfinished = client
+            .backups()
+            .values()
+            .filter(|backup| !backup.is_finished())
+            .map(|backup| backup.id)
+            .collect::<Vec<u64>>();
+        unfinished.sort_unstable();
+        for id in unfinished {
+            let backup = client.backups_mut().get_mut(&id).unwrap();
+            if busy && Some(id) == newest {
+                log::info!(
+                    "Not pruning {}, because another run might still be cloning it",
+                    backup.path().display()
+                );
+                continue;
+            }
+            let size = disk_usage(&backup.path());
+            if dry_run {
+                log::info!(
+                    "Would delete unfinished backup {} ({})",
+                    backup.path().display(),
+                    format_bytes(size)
+                );
+                continue;
+            }
+            match backup.delete(storage.as_ref()) {
+                Ok(()) => {
+                    log::info!("Deleted unfinished backup {}", backup.path().display());
+                    reclaimed += size;
+                }
+                Err(err) => {
+                    log::error!(
+                        "Could not delete unfinished backup {}: {:?}",
+                        backup.path().display(),
+                        err
+                    );
+                    success = false;
+                }
+            }
+        }
+    }
+    if !dry_run {
+        log::info!("Prune reclaimed up to {}", format_bytes(reclaimed));
+    }
+    success
+}
+
+/// JSON array with one object per size class of `histogram`. The upper bound of the last class
+/// is `null`.
+fn histogram_json(histogram: &SizeHistogram) -> String {
+    json::array(
+        histogram
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(index, (files, bytes))| {
+                format!(
+                    "{{\"below\":{},\"files\":{},\"bytes\":{}}}",
+                    SizeHistogram::LIMITS
+                        .get(index)
+                        .map_or("null".to_string(), |limit| limit.to_string()),
+                    files,
+                    bytes
+                )
+            }),
+    )
+}
+
+/// Lists the backups of all clients, in client order and sorted by id. See `Action::List`.
+fn list_backups(
+    clients: &mut [(Box<dyn Client>, CloneOptions, PathBuf)],
+    json: bool,
+    stats: bool,
+) -> String {
+    let mut lines = Vec::new();
+    for (client, _, _) in clients {
+        let mut ids = client.backups().keys().copied().collect::<Vec<u64>>();
+        ids.sort();
+        let name = client.name().to_owned();
+        for id in ids {
+            let backup = client.backups_mut().get_mut(&id).unwrap();
+            let finished = backup.is_local_backup().then(|| backup.is_finished());
+            let histogram = if stats && finished == Some(true) {
+                backup
+                    .size_histogram()
+                    .map_err(|err| {
+                        log::warn!("Could not read manifest of {:?}: {}", backup.path(), err)
+                    })
+                    .ok()
+            } else {
+                None
+            };
+            let size = match backup.listed_size() {
+                Some(size) => Some(size),
+                None if finished == Some(true) => backup
+                    .total_size()
+                    .map_err(|err| {
+                        log::warn!("Could not read manifest of {:?}: {}", backup.path(), err)
+                    })
+                    .ok(),
+                None => None,
+            };
+            lines.push(if json {
+                format!(
+                    "{{\"client\":{},\"id\":{},\"timestamp\":{},\"finished\":{},\"size\":{}{}}}",
+                    json::quote(&name),
+                    backup.id,
+                    json::quote(backup.timestamp()),
+                    finished.map_or("null".to_string(), |finished| finished.to_string()),
+                    size.map_or("null".to_string(), |size| size.to_string()),
+                    if stats {
+                        format!(
+                            ",\"histogram\":{}",
+                            histogram
+                                .map_or("null".to_string(), |histogram| histogram_json(&histogram))
+                        )
+                    } else {
+                        String::new()
+                    }
+                )
+            } else {
+                format!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    name,
+                    backup.id,
+                    backup.timestamp(),
+                    match finished {
+                        Some(true) => "finished",
+                        Some(false) => "unfinished",
+                        None => "-",
+                    },
+                    size.map_or("-".to_string(), |size| size.to_string())
                 )
             });
-        clients.push(client);
+            if let (false, Some(histogram)) = (json, histogram) {
+                for (label, (files, bytes)) in SizeHistogram::LABELS.iter().zip(histogram.buckets) {
+                    lines.push(format!(
+                        "  {:>8}: {} files, {}",
+                        label,
+                        files,
+                        format_bytes(bytes)
+                    ));
+                }
+            }
+        }
+    }
+    if json {
+        format!("{}\n", json::array(lines))
+    } else {
+        lines.iter().map(|line| format!("{}\n", line)).collect()
     }
+}
 
-    clone_backups(&clients, &config.dest_dir, config.io_threads);
+fn clone_options(
+    config: &Config,
+    conf: &ClientConfig,
+    dry_run: bool,
+    limiter: &Option<Arc<RateLimiter>>,
+    cancel: &Arc<AtomicBool>,
+) -> Result<CloneOptions, globset::Error> {
+    Ok(CloneOptions {
+        client_subvolume: config.client_subvolumes,
+        transfer: conf.transfer,
+        send_command: conf.send_command.to_vec(),
+        write_checksum_file: config.write_checksum_file,
+        include_ids: conf.include_ids.to_vec(),
+        exclude_ids: conf.exclude_ids.to_vec(),
+        min_id: conf.min_id,
+        max_id: conf.max_id,
+        since: conf.since,
+        until: conf.until,
+        include: conf.include.to_vec(),
+        exclude: exclude_set(&conf.exclude)?,
+        max_queued_transfers: config.io_threads * 4,
+        keep_last: conf.keep_last,
+        dry_run,
+        btrfs: config.btrfs_path.clone(),
+        transfer_retries: config.transfer_retries,
+        verify_on_copy: config.verify_on_copy,
+        bandwidth_limit: limiter.clone(),
+        storage: config.storage.backend(&config.btrfs_path),
+        retention: conf.retention,
+        cancel: cancel.clone(),
+    })
 }
 
 #[cfg(feature = "http")]
-fn create_remote_client(conf: &ClientConfig) -> Box<dyn Client> {
-    Box::new(RemoteClient::new(&conf.name))
+fn create_remote_client(
+    conf: &ClientConfig,
+    cache_dir: Option<&Path>,
+) -> Result<Box<dyn Client>, Box<dyn Error>> {
+    let mut options = RemoteOptions {
+        username: conf.username.to_owned(),
+        password: conf.password.to_owned(),
+        token: conf.token.as_deref().map(resolve_secret).transpose()?,
+        ca_cert: conf.ca_cert.to_owned(),
+        client_cert: conf.client_cert.to_owned(),
+        client_key: conf.client_key.to_owned(),
+        manifest_cache: cache_dir.map(Path::to_owned),
+        url_template: conf.url_template.to_owned(),
+        ..Default::default()
+    };
+    if let Some(attempts) = conf.max_attempts {
+        options.max_attempts = attempts;
+    }
+    if let Some(delay) = conf.retry_delay_ms {
+        options.retry_delay = std::time::Duration::from_millis(delay);
+    }
+    if let Some(timeout) = conf.connect_timeout_ms {
+        options.connect_timeout = std::time::Duration::from_millis(timeout);
+    }
+    if let Some(timeout) = conf.read_timeout_ms {
+        options.read_timeout =
+            Some(std::time::Duration::from_millis(timeout)).filter(|timeout| !timeout.is_zero());
+    }
+    Ok(Box::new(RemoteClient::with_options(&conf.name, &options)?))
+}
+
+/// Returns `value`, or the content of environment variable NAME if `value` is "env:NAME"
+#[cfg(any(feature = "http", feature = "s3"))]
+fn resolve_secret(value: &str) -> Result<String, String> {
+    match value.strip_prefix("env:") {
+        Some(name) => {
+            std::env::var(name).map_err(|err| format!("Environment variable {}: {}", name, err))
+        }
+        None => Ok(value.to_string()),
+    }
 }
 
 #[cfg(not(feature = "http"))]
-fn create_remote_client(conf: &ClientConfig) -> Box<dyn Client> {
-    panic!("Unable to create remote client for URL {:?}, because bdup is compiled without \"http\" feature", conf.storage_url);
+fn create_remote_client(
+    conf: &ClientConfig,
+    _cache_dir: Option<&Path>,
+) -> Result<Box<dyn Client>, Box<dyn Error>> {
+    Err(format!(
+        "Unable to create remote client for URL {:?}, because bdup is compiled without \"http\" feature",
+        conf.storage_url
+    )
+    .into())
 }
 
-fn create_client(conf: &ClientConfig) -> Box<dyn Client> {
-    if conf.storage_url.starts_with('/') || conf.storage_url.starts_with("file:/") {
-        Box::new(LocalClient::new(&conf.name))
-    } else {
-        create_remote_client(conf)
+#[cfg(feature = "ssh")]
+fn create_sftp_client(conf: &ClientConfig) -> Result<Box<dyn Client>, Box<dyn Error>> {
+    Ok(Box::new(SftpClient::connect(
+        &conf.name,
+        &conf.storage_url,
+        conf.ssh_key.as_deref(),
+    )?))
+}
+
+#[cfg(not(feature = "ssh"))]
+fn create_sftp_client(conf: &ClientConfig) -> Result<Box<dyn Client>, Box<dyn Error>> {
+    Err(format!(
+        "Unable to create sftp client for URL {:?}, because bdup is compiled without \"ssh\" feature",
+        conf.storage_url
+    )
+    .into())
+}
+
+#[cfg(feature = "s3")]
+fn create_s3_client(
+    source: Box<dyn Client>,
+    conf: &S3Config,
+) -> Result<Box<dyn Client>, Box<dyn Error>> {
+    let options = S3Options {
+        endpoint: conf.endpoint.to_owned(),
+        region: conf.region.to_owned(),
+        bucket: conf.bucket.to_owned(),
+        prefix: conf.prefix.to_owned(),
+        access_key: resolve_secret(&conf.access_key)?,
+        secret_key: resolve_secret(&conf.secret_key)?,
+        path_style: conf.path_style,
+    };
+    Ok(Box::new(S3Client::new(source, &options)?))
+}
+
+#[cfg(not(feature = "s3"))]
+fn create_s3_client(
+    _source: Box<dyn Client>,
+    conf: &S3Config,
+) -> Result<Box<dyn Client>, Box<dyn Error>> {
+    Err(format!(
+        "Unable to upload to bucket {:?}, because bdup is compiled without \"s3\" feature",
+        conf.bucket
+    )
+    .into())
+}
+
+/// Creates the client of `conf`. Remote clients cache manifests in `cache_dir`.
+fn create_client(
+    conf: &ClientConfig,
+    cache_dir: Option<&Path>,
+) -> Result<Box<dyn Client>, Box<dyn Error>> {
+    let source = create_source_client(conf, cache_dir)?;
+    match &conf.s3 {
+        Some(s3) => create_s3_client(source, s3),
+        None => Ok(source),
     }
 }
 
-fn clone_backups(clients: &[Box<dyn Client>], dest: &Path, num_threads: usize) {
-    if !dest.exists() {
-        fs::create_dir(dest)
-            .unwrap_or_else(|err| panic!("Could not create destination directory: {:?}", err));
+fn create_source_client(
+    conf: &ClientConfig,
+    cache_dir: Option<&Path>,
+) -> Result<Box<dyn Client>, Box<dyn Error>> {
+    if conf.storage_url.starts_with('/') {
+        Ok(Box::new(LocalClient::new(&conf.name)))
+    } else if conf.storage_url.starts_with("sftp://") {
+        create_sftp_client(conf)
+    } else {
+        create_remote_client(conf, cache_dir)
     }
+}
+
+/// Result of cloning the backups of one client
+struct ClientResult {
+    name: String,
+    /// `None` if cloning failed before all backups of the client were processed
+    summary: Option<CloneSummary>,
+    duration: Duration,
+}
 
+/// Clones the backups of each client to its destination directory, see `Config::client_dest`
+fn clone_backups(
+    clients: &[(Box<dyn Client>, CloneOptions, PathBuf)],
+    num_threads: usize,
+    dry_run: bool,
+) -> Vec<ClientResult> {
     let transfer_threads = ThreadPool::new(num_threads);
-    for client in clients {
-        if let Err(error) = client.clone_backups_to(&dest.join(client.name()), &transfer_threads) {
-            log::error!("Error cloning backups of {}: {:?}", client.name(), error);
+    let mut summaries = Vec::new();
+    for (client, options, client_dest) in clients {
+        let dest = client_dest.parent().unwrap_or(Path::new("."));
+        if !dest.exists() && dry_run {
+            log::info!("Would create destination directory {}", dest.display());
+        } else if !dest.exists() {
+            fs::create_dir(dest)
+                .unwrap_or_else(|err| panic!("Could not create destination directory: {:?}", err));
         }
+
+        let start = Instant::now();
+        let summary = match client.clone_backups_to(client_dest, &transfer_threads, options) {
+            Ok(summary) => Some(summary),
+            Err(error) => {
+                log::error!("Error cloning backups of {}: {:?}", client.name(), error);
+                None
+            }
+        };
+        summaries.push(ClientResult {
+            name: client.name().to_string(),
+            summary,
+            duration: start.elapsed(),
+        });
+    }
+    summaries
+}
+
+/// Table of the per-client results of `clone_backups` and their totals. Clients that failed
+/// before all backups were processed are marked as aborted.
+fn summary_table(summaries: &[ClientResult]) -> String {
+    let width = summaries
+        .iter()
+        .map(|result| result.name.len())
+        .chain(["client".len(), "total".len()])
+        .max()
+        .unwrap_or_default();
+    let row = |name: &str, summary: &CloneSummary| {
+        format!(
+            "{:<width$} {:>7} {:>7} {:>7} {:>12} {:>12}\n",
+            name,
+            summary.cloned,
+            summary.skipped,
+            summary.failed,
+            format_bytes(summary.bytes_transferred),
+            format_bytes(summary.bytes_deduplicated),
+        )
+    };
+    let mut table = format!(
+        "{:<width$} {:>7} {:>7} {:>7} {:>12} {:>12}\n",
+        "client", "cloned", "skipped", "failed", "transferred", "deduplicated"
+    );
+    let mut total = CloneSummary::default();
+    for result in summaries {
+        match &result.summary {
+            Some(summary) => {
+                table += &row(&result.name, summary);
+                total += *summary;
+            }
+            None => table += &format!("{:<width$} aborted\n", result.name),
+        }
+    }
+    table + &row("total", &total)
+}
+
+/// Escapes `value` for use as a Prometheus label value
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Value of the sample of `metric` for client `label` in `text`, the metrics file written by a
+/// previous run
+fn previous_value<'a>(text: &'a str, metric: &str, label: &str) -> Option<&'a str> {
+    let prefix = format!("{}{{client=\"{}\"}} ", metric, label);
+    text.lines().find_map(|line| line.strip_prefix(&prefix))
+}
+
+/// Writes the results of a run to `path` in the Prometheus textfile format. Clients that could
+/// not be created or searched for backups count as failed. Counters continue from the values in
+/// the file written by the previous run.
+fn write_metrics(
+    path: &Path,
+    config: &Config,
+    summaries: &[ClientResult],
+    failed_clients: &HashSet<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    let previous = fs::read_to_string(path).unwrap_or_default();
+    let mut families = [
+        (
+            "bdup_last_success_timestamp_seconds",
+            "gauge",
+            "Time of the last run that cloned all backups of the client",
+            Vec::new(),
+        ),
+        (
+            "bdup_client_success",
+            "gauge",
+            "Whether the last run cloned all backups of the client",
+            Vec::new(),
+        ),
+        (
+            "bdup_clone_duration_seconds",
+            "gauge",
+            "Time spent cloning the backups of the client",
+            Vec::new(),
+        ),
+        (
+            "bdup_backups_cloned_total",
+            "counter",
+            "Backups cloned by all runs",
+            Vec::new(),
+        ),
+        (
+            "bdup_backups_skipped_total",
+            "counter",
+            "Backups skipped by all runs",
+            Vec::new(),
+        ),
+        (
+            "bdup_backups_failed_total",
+            "counter",
+            "Backups that could not be cloned completely by all runs",
+            Vec::new(),
+        ),
+        (
+            "bdup_files_failed_total",
+            "counter",
+            "Files that could not be transferred by all runs",
+            Vec::new(),
+        ),
+        (
+            "bdup_backup_bytes_transferred_total",
+            "counter",
+            "Bytes transferred by all runs",
+            Vec::new(),
+        ),
+        (
+            "bdup_backup_bytes_deduplicated_total",
+            "counter",
+            "Bytes taken from base backups instead of transferring them by all runs",
+            Vec::new(),
+        ),
+    ];
+    for conf in &config.clients {
+        let label = label_value(&conf.name);
+        let result = summaries.iter().find(|result| result.name == conf.name);
+        let summary = result.and_then(|result| result.summary).unwrap_or_default();
+        let success = !failed_clients.contains(conf.name.as_str());
+        let last_success = if success {
+            Some(now.to_string())
+        } else {
+            previous_value(&previous, "bdup_last_success_timestamp_seconds", &label)
+                .map(str::to_string)
+        };
+        let values = [
+            last_success,
+            Some(u8::from(success).to_string()),
+            result.map(|result| format!("{:.3}", result.duration.as_secs_f64())),
+            Some(summary.cloned.to_string()),
+            Some(summary.skipped.to_string()),
+            Some(summary.failed.to_string()),
+            Some(summary.files_failed.to_string()),
+            Some(summary.bytes_transferred.to_string()),
+            Some(summary.bytes_deduplicated.to_string()),
+        ];
+        for ((name, kind, _, samples), value) in families.iter_mut().zip(values) {
+            let Some(mut value) = value else {
+                continue;
+            };
+            if *kind == "counter" {
+                let total = previous_value(&previous, name, &label)
+                    .and_then(|total| total.parse::<u64>().ok())
+                    .unwrap_or(0);
+                value = (total + value.parse::<u64>()?).to_string();
+            }
+            samples.push(format!("{{client=\"{}\"}} {}", label, value));
+        }
+    }
+
+    let mut text = String::new();
+    for (name, kind, help, samples) in families {
+        text += &format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind);
+        for sample in samples {
+            text += &format!("{}{}\n", name, sample);
+        }
+    }
+    text += &format!(
+        "# HELP bdup_last_run_timestamp_seconds Time of the last run\n\
+         # TYPE bdup_last_run_timestamp_seconds gauge\n\
+         bdup_last_run_timestamp_seconds {}\n",
+        now
+    );
+
+    // the collector must never read a partially written file
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, text)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn restore_compression() {
+        let args = Args::try_parse_from([
+            "bdup",
+            "restore",
+            "backup",
+            "dest",
+            "--compression",
+            "gzip:9",
+        ])
+        .unwrap();
+        let Some(Action::Restore { compression, .. }) = args.command else {
+            panic!("not parsed as restore");
+        };
+        assert_eq!(compression.unwrap().to_string(), "gzip:9");
+        for spec in ["gzip:10", "lzma"] {
+            let args = ["bdup", "restore", "backup", "dest", "--compression", spec];
+            assert!(Args::try_parse_from(args).is_err());
+        }
+        let zstd = [
+            "bdup",
+            "restore",
+            "backup",
+            "dest",
+            "--compression",
+            "zstd:19",
+        ];
+        assert_eq!(Args::try_parse_from(zstd).is_ok(), cfg!(feature = "zstd"));
+    }
+
+    fn s3_client_config() -> ClientConfig {
+        serde_yaml::from_str(
+            "name: client
+storage_url: /srv/burp/client
+s3:
+  endpoint: https://s3.example.com
+  bucket: offsite
+  access_key: access
+  secret_key: secret
+",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "toml")]
+    fn toml_config() {
+        let file = std::env::temp_dir().join(format!("bdup-config-{}.toml", std::process::id()));
+        fs::write(
+            &file,
+            r#"io_threads = 3
+dest_dir = "/srv/dup"
+log_filter = "burp::manifest=trace"
+
+[[clients]]
+name = "client"
+storage_url = "/srv/burp/client"
+keep_last = 5
+exclude = ["/home/*/.cache"]
+"#,
+        )
+        .unwrap();
+        let args =
+            Args::try_parse_from(["bdup", "--config-file", &file.to_string_lossy(), "-C"]).unwrap();
+        let format = ConfigFormat::of(&args);
+        assert_eq!(format, ConfigFormat::Toml);
+        let config = read_config(&args).unwrap();
+        assert_eq!(config.io_threads, 3);
+        assert_eq!(config.dest_dir, PathBuf::from("/srv/dup"));
+        assert_eq!(config.clients[0].keep_last, Some(5));
+
+        // --dump-config output is a config file that reads back to the same config
+        let dumped = format.dump(&config).unwrap();
+        fs::write(&file, &dumped).unwrap();
+        let reread = read_config(&args).unwrap();
+        assert_eq!(format.dump(&reread).unwrap(), dumped);
+        assert_eq!(reread.clients, config.clients);
+        assert_eq!(reread.log_filter, config.log_filter);
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn expand_config_paths() {
+        let base = std::env::temp_dir().join(format!("bdup-expand-{}", std::process::id()));
+        fs::create_dir_all(base.join("local/other")).unwrap();
+        let file = base.join("bdup.yaml");
+        fs::write(
+            &file,
+            r#"dest_dir: ~/dup
+log_file: $BASE/bdup.log
+clients:
+  - name: client
+    storage_url: ${BASE}/burp/client
+"#,
+        )
+        .unwrap();
+        let args = Args::try_parse_from([
+            "bdup",
+            "--config-file",
+            &file.to_string_lossy(),
+            "--local-clients",
+            "$BASE/local",
+        ])
+        .unwrap();
+        let base_dir = base.to_string_lossy().to_string();
+        let lookup = |name: &str| match name {
+            "HOME" => Some("/home/burp".to_string()),
+            "BASE" => Some(base_dir.clone()),
+            _ => None,
+        };
+        let config = read_config_with(&args, &lookup).unwrap();
+        assert_eq!(config.dest_dir, PathBuf::from("/home/burp/dup"));
+        assert_eq!(config.log_file, Some(base.join("bdup.log")));
+        assert_eq!(
+            config.clients[0].storage_url,
+            format!("{}/burp/client", base_dir)
+        );
+        assert_eq!(config.clients[1].name, "other");
+        assert_eq!(
+            PathBuf::from(&config.clients[1].storage_url),
+            fs::canonicalize(base.join("local/other")).unwrap()
+        );
+        assert!(read_config_with(&args, &|_| None).is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn validate_config() {
+        let valid = || Config {
+            dest_dir: std::env::temp_dir().join("dup"),
+            clients: vec![ClientConfig {
+                name: "client".to_string(),
+                storage_url: "/srv/burp/client".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(valid().validate(), Ok(()));
+
+        let mut config = valid();
+        config.dest_dir = PathBuf::new();
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .contains("No destination directory"));
+        let mut config = valid();
+        config.io_threads = 0;
+        assert!(config.validate().unwrap_err().contains("io_threads"));
+        let mut config = valid();
+        config.clients.push(config.clients[0].clone());
+        assert!(config.validate().unwrap_err().contains("configured twice"));
+        for url in [
+            "srv/burp/client",
+            "ftp://host/client",
+            "https://",
+            "file:/srv",
+        ] {
+            let mut config = valid();
+            config.clients[0].storage_url = url.to_string();
+            assert!(config
+                .validate()
+                .unwrap_err()
+                .contains("Invalid storage URL"));
+        }
+    }
+
+    #[test]
+    fn file_urls_are_paths() {
+        assert_eq!(strip_file_scheme("file:///srv/burp"), "/srv/burp");
+        assert_eq!(strip_file_scheme("file:/srv/burp"), "/srv/burp");
+        assert_eq!(strip_file_scheme("/srv/burp"), "/srv/burp");
+        let args = Args::try_parse_from(["bdup", "--client", "client=file:///srv/burp"]).unwrap();
+        let config = read_config(&args).unwrap();
+        assert_eq!(config.clients[0].storage_url, "/srv/burp");
+        assert!(is_supported_url(&config.clients[0].storage_url));
+    }
+
+    #[test]
+    fn btrfs_send_needs_local_path() {
+        let mut config = Config {
+            dest_dir: std::env::temp_dir().join("dup"),
+            clients: vec![ClientConfig {
+                name: "client".to_string(),
+                storage_url: "sftp://backup@host/srv/burp/client".to_string(),
+                transfer: TransferMethod::BtrfsSend,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(config.validate().unwrap_err().contains("btrfs_send"));
+        config.clients[0].storage_url = "/srv/burp/client".to_string();
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn quiet_caps_log_filter() {
+        let file = std::env::temp_dir().join(format!("bdup-quiet-{}.yaml", std::process::id()));
+        fs::write(
+            &file,
+            "log_filter: burp::manifest=trace,burp::client=error\n",
+        )
+        .unwrap();
+        let config_file = file.to_string_lossy().to_string();
+        let config = read_config(&Args::try_parse_from(["bdup", "-f", &config_file]).unwrap());
+        assert_eq!(
+            config.unwrap().log_filter.to_string(),
+            "burp::manifest=trace,burp::client=error"
+        );
+        let quiet = Args::try_parse_from(["bdup", "-f", &config_file, "--quiet"]).unwrap();
+        let config = read_config(&quiet).unwrap();
+        assert_eq!(config.log_level, log::LevelFilter::Warn);
+        assert_eq!(
+            config.log_filter.to_string(),
+            "burp::manifest=warn,burp::client=error"
+        );
+        let verbose = Args::try_parse_from(["bdup", "-f", &config_file, "-v"]).unwrap();
+        assert_eq!(
+            read_config(&verbose).unwrap().log_filter.to_string(),
+            "burp::manifest=debug,burp::client=error"
+        );
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn metrics_accumulate() {
+        let file = std::env::temp_dir().join(format!("bdup-metrics-{}.prom", std::process::id()));
+        // written by a previous run, with the label escaped
+        fs::write(
+            &file,
+            "bdup_last_success_timestamp_seconds{client=\"a\\\"b\\\\c\"} 1000\n\
+             bdup_backups_cloned_total{client=\"a\\\"b\\\\c\"} 5\n",
+        )
+        .unwrap();
+        let config = Config {
+            clients: vec![ClientConfig {
+                name: "a\"b\\c".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let summaries = [ClientResult {
+            name: config.clients[0].name.clone(),
+            summary: Some(CloneSummary {
+                cloned: 2,
+                bytes_transferred: 100,
+                ..Default::default()
+            }),
+            duration: Duration::from_secs(1),
+        }];
+        let label = r#"a\"b\\c"#;
+
+        // counters add up, the last success is kept while the client fails
+        let failed = HashSet::from([config.clients[0].name.as_str()]);
+        write_metrics(&file, &config, &summaries, &failed).unwrap();
+        let text = fs::read_to_string(&file).unwrap();
+        assert_eq!(
+            previous_value(&text, "bdup_client_success", label),
+            Some("0")
+        );
+        assert_eq!(
+            previous_value(&text, "bdup_last_success_timestamp_seconds", label),
+            Some("1000")
+        );
+        assert_eq!(
+            previous_value(&text, "bdup_backups_cloned_total", label),
+            Some("7")
+        );
+
+        write_metrics(&file, &config, &summaries, &HashSet::new()).unwrap();
+        let text = fs::read_to_string(&file).unwrap();
+        assert_eq!(
+            previous_value(&text, "bdup_client_success", label),
+            Some("1")
+        );
+        assert_ne!(
+            previous_value(&text, "bdup_last_success_timestamp_seconds", label),
+            Some("1000")
+        );
+        assert_eq!(
+            previous_value(&text, "bdup_backups_cloned_total", label),
+            Some("9")
+        );
+        assert_eq!(
+            previous_value(&text, "bdup_backup_bytes_transferred_total", label),
+            Some("200")
+        );
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn only_client() {
+        let file =
+            std::env::temp_dir().join(format!("bdup-only-client-{}.yaml", std::process::id()));
+        fs::write(
+            &file,
+            r#"clients:
+  - name: a
+    storage_url: /srv/burp/a
+  - name: b
+    storage_url: /srv/burp/b
+  - name: c
+    storage_url: /srv/burp/c
+"#,
+        )
+        .unwrap();
+        let config_file = file.to_string_lossy().to_string();
+        let args = |names: &[&str]| {
+            let mut args = vec!["bdup", "-f", &config_file];
+            for name in names {
+                args.extend(["--only-client", name]);
+            }
+            Args::try_parse_from(args).unwrap()
+        };
+        let config = read_config_with(&args(&["c", "a"]), &|_| None).unwrap();
+        let names = config
+            .clients
+            .iter()
+            .map(|client| client.name.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(names, ["a", "c"]);
+
+        let Err(error) = read_config_with(&args(&["a", "d"]), &|_| None) else {
+            panic!("unknown client accepted");
+        };
+        assert_eq!(
+            error.to_string(),
+            "Unknown client d, available clients: a, b, c"
+        );
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn prune_unfinished() {
+        let base = std::env::temp_dir().join(format!("bdup-prune-{}", std::process::id()));
+        let dest = base.join("client");
+        let finished = dest.join("0000001 2021-04-11 00:00:00");
+        let unfinished = dest.join("0000002 2021-04-12 00:00:00");
+        let newest = dest.join("0000003 2021-04-13 00:00:00");
+        fs::create_dir_all(finished.join("data")).unwrap();
+        fs::write(finished.join("manifest.gz"), b"").unwrap();
+        fs::create_dir_all(unfinished.join("data")).unwrap();
+        fs::write(unfinished.join("data/a"), b"a").unwrap();
+        fs::create_dir_all(newest.join("data")).unwrap();
+        fs::write(newest.join("manifest.gz"), b"").unwrap();
+        fs::write(newest.join(".bdup.partial"), b"").unwrap();
+        let config = Config {
+            dest_dir: base.clone(),
+            storage: StorageKind::Reflink,
+            clients: vec![ClientConfig {
+                name: "client".to_string(),
+                storage_url: "/srv/burp/client".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(prune(&config, true, false));
+        assert!(unfinished.exists() && newest.exists());
+        // another run might be cloning the newest backup
+        assert!(prune(&config, false, true));
+        assert!(!unfinished.exists());
+        assert!(newest.exists());
+        assert!(prune(&config, false, false));
+        assert!(!newest.exists());
+        assert!(finished.exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn lock_twice() {
+        let dir = std::env::temp_dir().join(format!("bdup-lock-{}", std::process::id()));
+        let path = dir.join("bdup.lock");
+        assert!(!is_locked(&path));
+        let lock = lock_destination(&path).unwrap();
+        assert!(is_locked(&path));
+        let error = lock_destination(&path).unwrap_err();
+        assert!(error.starts_with("Another bdup run holds the lock"));
+        drop(lock);
+        assert!(!is_locked(&path));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "s3")]
+    fn s3_client() {
+        let client = create_client(&s3_client_config(), None).unwrap();
+        assert_eq!(client.name(), "client");
+    }
+
+    #[test]
+    #[cfg(not(feature = "s3"))]
+    fn s3_needs_feature() {
+        let error = create_client(&s3_client_config(), None).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("compiled without \"s3\" feature"));
     }
 }
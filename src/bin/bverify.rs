@@ -1,11 +1,26 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use derive_more::{Display, Error};
 use std::error::Error;
-use std::path::PathBuf;
-use time::macros::format_description;
-use time::OffsetDateTime;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
-use burp::backup::Backup;
+use burp::backup::{Backup, VerifyOptions, VerifyReport};
+use burp::checksum::ChecksumAlgo;
+use burp::client::{Client, LocalClient};
+use burp::json;
+use burp::logfilter::LogFilter;
+use burp::logging::{format_record, verbosity_level, ColorMode};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// Log messages only
+    Text,
+    /// Print a JSON array with one object per backup to stdout, logs go to stderr
+    Json,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Verifies burp backups")]
@@ -16,15 +31,77 @@ struct Args {
     #[arg(short, long, value_enum, value_name = "LEVEL")]
     log_level: Option<log::LevelFilter>,
 
+    /// Only log warnings and errors, overriding the log level
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log debug messages, or trace messages if given twice, overriding the log level
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log levels of single targets, e.g. "burp::manifest=trace,burp::client=debug"
+    ///
+    /// Targets include their submodules. Other targets use the global log level. --quiet and
+    /// --verbose limit the levels of all targets as well.
+    #[arg(long, value_name = "FILTER")]
+    log_filter: Option<LogFilter>,
+
+    /// Colorize log levels: auto, always or never
+    ///
+    /// With auto, colors are only used if logs are written to a terminal.
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Also append logs to FILE, without colors
+    ///
+    /// If FILE cannot be opened, a warning is logged and the run continues.
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
     /// Thread pool size for I/O operations (i.e. copying files)
+    ///
+    /// With --jobs, the threads are divided among the backups verified at the same time.
     #[arg(short = 't', long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
     iothreads: u64,
 
+    /// Number of backups to verify at the same time
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    jobs: u64,
+
     /// Directories of backups to verify
     ///
-    /// At least one directory must be specified. Backups are verified in the given order.
-    #[arg(required(true))]
+    /// At least one directory must be specified, unless --client-dir is used. Backups are
+    /// verified in the given order.
+    #[arg(required_unless_present = "client_dir")]
     backup: Vec<String>,
+
+    /// Verify all backups in client directory DIR, in id order
+    ///
+    /// Directories that are not named like a backup are skipped. Backups of client directories
+    /// are verified after the backups given as arguments.
+    #[arg(short = 'c', long, value_name = "DIR")]
+    client_dir: Vec<String>,
+
+    /// Print all failed and unwanted files sorted by path after verifying
+    ///
+    /// The listing is printed to stdout, or to stderr with --format json, and is stable across
+    /// runs, so it can be compared with the listing of a previous run.
+    #[arg(short = 's', long)]
+    sorted_failures: bool,
+
+    /// Checksum algorithm used in the manifests: md5 or sha256
+    #[arg(long, default_value_t = ChecksumAlgo::Md5)]
+    checksum: ChecksumAlgo,
+
+    /// Show the number of verified files on stderr
+    ///
+    /// Ignored if more than one backup is verified at the same time.
+    #[arg(short, long)]
+    progress: bool,
+
+    /// Output format of the results
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
 }
 
 #[derive(Debug, Display, Error)]
@@ -34,52 +111,211 @@ struct VerifyError {
     total: usize,
 }
 
+/// Overwrites the progress line on stderr. A total of 0 is not known yet. The line is ended by
+/// `verify_backup` once the backup is verified.
+fn print_progress(done: u64, total: u64) {
+    match total {
+        0 => eprint!("\r{} files verified", done),
+        _ => eprint!("\r{}/{} files verified", done, total),
+    }
+}
+
+/// JSON object with the results of verifying the backup at `path`. Backups that could not be
+/// verified at all have all counts set to 0 and the reason in `error`.
+fn report_json(path: &Path, result: Result<&VerifyReport, String>) -> String {
+    let (ok, failed, missing, unwanted, paths, error) = match result {
+        Ok(report) => (
+            report.ok,
+            report.failed.len(),
+            report.missing(),
+            report.unwanted.len(),
+            report
+                .failed_paths()
+                .into_iter()
+                .chain(report.unwanted.iter().map(PathBuf::as_path))
+                .map(|path| json::quote(&path.to_string_lossy()))
+                .collect(),
+            "null".to_string(),
+        ),
+        Err(err) => (0, 0, 0, 0, Vec::new(), json::quote(&err)),
+    };
+    format!(
+        "{{\"backup\":{},\"ok\":{},\"failed\":{},\"missing\":{},\"unwanted\":{},\"paths\":{},\"error\":{}}}",
+        json::quote(&path.to_string_lossy()),
+        ok,
+        failed,
+        missing,
+        unwanted,
+        json::array(paths),
+        error
+    )
+}
+
+/// Results of verifying one backup
+struct BackupResult {
+    /// Lines for --sorted-failures
+    failures: Vec<String>,
+    json: String,
+    /// The backup could not be verified at all
+    error: bool,
+}
+
+fn verify_backup(path: &Path, matches: &Args, threads: usize, progress: bool) -> BackupResult {
+    let mut backup = match Backup::from_path(path) {
+        Ok(backup) => backup,
+        Err(err) => {
+            log::error!(
+                "Path {} does not seem to be a backup: {:?}",
+                path.display(),
+                err
+            );
+            return BackupResult {
+                failures: Vec::new(),
+                json: report_json(path, Err(err.to_string())),
+                error: true,
+            };
+        }
+    };
+    let options = VerifyOptions {
+        algo: matches.checksum,
+        on_progress: if progress {
+            &print_progress
+        } else {
+            &|_, _| {}
+        },
+        ..VerifyOptions::new(threads)
+    };
+    let result = backup.verify_with_options(&options);
+    if progress {
+        eprintln!();
+    }
+    match result {
+        Ok(report) => BackupResult {
+            failures: report.sorted_listing(),
+            json: report_json(path, Ok(&report)),
+            error: false,
+        },
+        Err(err) => {
+            log::error!(
+                "Verify of backup {} failed: {:?}",
+                backup.path().display(),
+                err
+            );
+            BackupResult {
+                failures: Vec::new(),
+                json: report_json(path, Err(err.to_string())),
+                error: true,
+            }
+        }
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = Args::parse();
 
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            let tstamp = match OffsetDateTime::now_local() {
-                Ok(time) => time.format(format_description!(
-                    "[year]-[month]-[day] [hour]:[minute]:[second]"
-                )),
-                _ => OffsetDateTime::now_utc().format(format_description!(
-                    "[year]-[month]-[day] [hour]:[minute]:[second] UTC"
-                )),
+    let color = matches.color.enabled(match matches.format {
+        Format::Text => std::io::stdout().is_terminal(),
+        Format::Json => std::io::stderr().is_terminal(),
+    });
+    let verbosity = verbosity_level(matches.quiet, matches.verbose);
+    let mut log_filter = matches.log_filter.clone().unwrap_or_default();
+    if let Some(level) = verbosity {
+        log_filter = log_filter.capped(level);
+    }
+    let mut dispatch = fern::Dispatch::new();
+    for (target, level) in log_filter.targets {
+        dispatch = dispatch.level_for(target, level);
+    }
+    dispatch = dispatch.chain(
+        fern::Dispatch::new()
+            .format(move |out, message, record| format_record(out, message, record, color))
+            .chain(match matches.format {
+                Format::Text => fern::Output::from(std::io::stdout()),
+                Format::Json => fern::Output::from(std::io::stderr()),
+            }),
+    );
+    let mut log_file_error = None;
+    if let Some(path) = &matches.log_file {
+        match fern::log_file(path) {
+            Ok(file) => {
+                dispatch = dispatch.chain(
+                    fern::Dispatch::new()
+                        .format(|out, message, record| format_record(out, message, record, false))
+                        .chain(file),
+                )
             }
-            .unwrap();
-            out.finish(format_args!(
-                "{} [{}][{}] {}",
-                tstamp,
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .level(matches.log_level.unwrap_or(log::LevelFilter::Info))
-        .chain(std::io::stdout())
+            Err(err) => log_file_error = Some((path, err)),
+        }
+    }
+    dispatch
+        .level(
+            verbosity
+                .or(matches.log_level)
+                .unwrap_or(log::LevelFilter::Info),
+        )
         .apply()
         .unwrap_or_else(|err| panic!("Log init failed: {:?}", err));
+    if let Some((path, err)) = log_file_error {
+        log::warn!("Could not open log file {}: {}", path.display(), err);
+    }
 
     let mut errors: usize = 0;
     let mut total_backups = 0;
+    let mut failures = Vec::new();
+    let mut reports = Vec::new();
     let num_threads = matches.iothreads;
-    for path in &matches.backup {
-        total_backups += 1;
-        match Backup::from_path(&PathBuf::from(path)) {
-            Ok(mut backup) => {
-                if let Err(err) = backup.verify(num_threads.try_into()?) {
-                    errors += 1;
-                    log::error!(
-                        "Verify of backup {} failed: {:?}",
-                        backup.path().display(),
-                        err
-                    );
-                }
-            }
+    let mut paths = matches.backup.iter().map(PathBuf::from).collect::<Vec<_>>();
+    for dir in &matches.client_dir {
+        let mut client = LocalClient::new(dir);
+        match client.find_backups(dir) {
+            Ok(()) => paths.extend(client.backups_sorted().iter().map(|backup| backup.path())),
             Err(err) => {
-                log::error!("Path {} does not seem to be a backup: {:?}", path, err);
+                log::error!("Could not find backups in {}: {:?}", dir, err);
                 errors += 1;
+                total_backups += 1;
+            }
+        }
+    }
+    // each job takes the next backup until all are verified, results are kept in input order
+    let jobs = usize::try_from(matches.jobs)?.min(paths.len()).max(1);
+    let threads_per_job = (usize::try_from(num_threads)? / jobs).max(1);
+    let progress = matches.progress && jobs == 1;
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(index) else {
+                    break;
+                };
+                let result = verify_backup(path, &matches, threads_per_job, progress);
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    for (_, result) in results {
+        total_backups += 1;
+        if result.error {
+            errors += 1;
+        }
+        failures.extend(result.failures);
+        reports.push(result.json);
+    }
+
+    if matches.format == Format::Json {
+        println!("{}", json::array(reports));
+    }
+
+    if matches.sorted_failures {
+        failures.sort();
+        for line in failures {
+            // stdout only holds the JSON array
+            match matches.format {
+                Format::Text => println!("{}", line),
+                Format::Json => eprintln!("{}", line),
             }
         }
     }
@@ -0,0 +1,182 @@
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// Digest used to verify data files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumAlgo {
+    #[default]
+    Md5,
+    Sha256,
+}
+
+impl ChecksumAlgo {
+    /// Length of the hex encoded digest
+    pub fn hex_len(&self) -> usize {
+        match self {
+            ChecksumAlgo::Md5 => 32,
+            ChecksumAlgo::Sha256 => 64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownChecksumAlgo {
+    name: String,
+}
+
+impl fmt::Display for UnknownChecksumAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unknown checksum algorithm {:?}, expected md5 or sha256",
+            self.name
+        )
+    }
+}
+impl Error for UnknownChecksumAlgo {}
+
+impl FromStr for ChecksumAlgo {
+    type Err = UnknownChecksumAlgo;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "md5" => Ok(ChecksumAlgo::Md5),
+            "sha256" | "sha-256" => Ok(ChecksumAlgo::Sha256),
+            _ => Err(UnknownChecksumAlgo {
+                name: name.to_string(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChecksumAlgo::Md5 => write!(f, "md5"),
+            ChecksumAlgo::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+enum Context {
+    Md5(md5::Context),
+    Sha256(Sha256),
+}
+
+impl Context {
+    fn new(algo: ChecksumAlgo) -> Self {
+        match algo {
+            ChecksumAlgo::Md5 => Context::Md5(md5::Context::new()),
+            ChecksumAlgo::Sha256 => Context::Sha256(Sha256::new()),
+        }
+    }
+
+    fn consume(&mut self, data: &[u8]) {
+        match self {
+            Context::Md5(ctx) => ctx.consume(data),
+            Context::Sha256(ctx) => ctx.update(data),
+        }
+    }
+
+    fn hex_digest(self) -> String {
+        match self {
+            Context::Md5(ctx) => format!("{:x}", ctx.compute()),
+            Context::Sha256(ctx) => format!("{:x}", ctx.finalize()),
+        }
+    }
+}
+
+/// Size of the reads of `calc_digest`. Large reads are much faster for big files on fast disks.
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Reads `reader` to its end and returns the number of bytes read and the hex encoded digest
+pub fn calc_digest<T: io::Read>(reader: &mut T, algo: ChecksumAlgo) -> io::Result<(u64, String)> {
+    calc_digest_buffered(reader, algo, DEFAULT_BUFFER_SIZE)
+}
+
+/// Like `calc_digest`, but reads up to `buffer_size` bytes at once
+pub fn calc_digest_buffered<T: io::Read>(
+    reader: &mut T,
+    algo: ChecksumAlgo,
+    buffer_size: usize,
+) -> io::Result<(u64, String)> {
+    let mut ctx = Context::new(algo);
+    let mut buf = vec![0_u8; buffer_size.max(1)];
+    let mut size = 0;
+    loop {
+        let len = reader.read(&mut buf)?;
+        ctx.consume(&buf[0..len]);
+        size += len as u64;
+        if len == 0 {
+            break;
+        }
+    }
+    Ok((size, ctx.hex_digest()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn md5_digest() {
+        let lorem = "Lorem ipsum dolor sit amet, consectetur adipisici elit, sed eiusmod tempor incidunt ut labore et dolore magna aliqua";
+        let (size, digest) = calc_digest(&mut Cursor::new(lorem), ChecksumAlgo::Md5).unwrap();
+        assert_eq!(size, lorem.len() as u64);
+        assert_eq!(digest, "112e6e5d321385d524234210bdebec02");
+    }
+
+    #[test]
+    fn buffer_sizes() {
+        let input = (0..100_000).map(|i| i as u8).collect::<Vec<u8>>();
+        let expected = format!("{:x}", md5::compute(&input));
+        for buffer_size in [0, 1, 4096, DEFAULT_BUFFER_SIZE, 1 << 20] {
+            let (size, digest) =
+                calc_digest_buffered(&mut Cursor::new(&input), ChecksumAlgo::Md5, buffer_size)
+                    .unwrap();
+            assert_eq!(
+                (size, digest.as_str()),
+                (input.len() as u64, expected.as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn sha256_digest() {
+        let digest = |input: &[u8]| {
+            calc_digest(&mut Cursor::new(input), ChecksumAlgo::Sha256)
+                .unwrap()
+                .1
+        };
+        assert_eq!(
+            digest(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            digest(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            digest(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn parse_algo() {
+        assert_eq!("md5".parse::<ChecksumAlgo>().unwrap(), ChecksumAlgo::Md5);
+        assert_eq!(
+            "SHA256".parse::<ChecksumAlgo>().unwrap(),
+            ChecksumAlgo::Sha256
+        );
+        assert!("crc32".parse::<ChecksumAlgo>().is_err());
+    }
+}
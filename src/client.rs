@@ -1,13 +1,674 @@
-use std::collections::HashMap;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::ffi::OsStr;
 use std::fmt;
 use std::fs;
 use std::io;
+use std::io::Seek;
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use threadpool::ThreadPool;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime, PrimitiveDateTime, Time};
 
-use crate::backup::Backup;
 use crate::backup::TransferResult;
+use crate::backup::{
+    format_bytes, Backup, CloneStats, DataFileInfo, VerifyOptions, VerifyReport, MANIFEST_FILES,
+};
+use crate::ratelimit::{LimitedReader, RateLimiter};
+use crate::storage::{BtrfsBackend, StorageBackend};
+
+/// How backups are transferred to the destination
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferMethod {
+    /// Copy data files one by one, reusing unchanged files from a snapshot of the base backup
+    #[default]
+    Files,
+    /// Pipe `btrfs send` into `btrfs receive`. Only works if the source backups are read-only
+    /// btrfs subvolumes, e.g. another bdup destination, given by a local path. Falls back to
+    /// `Files` on failure.
+    BtrfsSend,
+}
+
+/// Directory in the destination of a client that `btrfs receive` writes to
+pub const RECEIVE_DIR: &str = ".bdup.receive";
+
+/// Inclusive range of backup ids, written as `first-last` or as a single id
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct IdRange {
+    pub first: u64,
+    pub last: u64,
+}
+
+#[derive(Debug)]
+pub struct InvalidIdRange {
+    message: String,
+}
+
+impl fmt::Display for InvalidIdRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl Error for InvalidIdRange {}
+
+impl IdRange {
+    pub fn contains(&self, id: u64) -> bool {
+        self.first <= id && id <= self.last
+    }
+}
+
+impl FromStr for IdRange {
+    type Err = InvalidIdRange;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = |message: String| InvalidIdRange { message };
+        let parse = |value: &str| {
+            value.trim().parse::<u64>().map_err(|err| {
+                invalid(format!(
+                    "Invalid id {:?} in range {:?}: {}",
+                    value, input, err
+                ))
+            })
+        };
+        let range = match input.split_once('-') {
+            Some((first, last)) => Self {
+                first: parse(first)?,
+                last: parse(last)?,
+            },
+            None => {
+                let id = parse(input)?;
+                Self {
+                    first: id,
+                    last: id,
+                }
+            }
+        };
+        if range.first > range.last {
+            return Err(invalid(format!(
+                "Invalid id range {:?}: first id is greater than last id",
+                input
+            )));
+        }
+        Ok(range)
+    }
+}
+
+impl TryFrom<String> for IdRange {
+    type Error = InvalidIdRange;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+impl fmt::Display for IdRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+impl From<IdRange> for String {
+    fn from(range: IdRange) -> Self {
+        range.to_string()
+    }
+}
+
+/// Date, optionally with time of day, written as `YYYY-MM-DD` or `YYYY-MM-DD hh:mm:ss`. Without
+/// time, the bound covers the whole day.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct DateBound {
+    pub date: Date,
+    pub time: Option<Time>,
+}
+
+#[derive(Debug)]
+pub struct InvalidDateBound {
+    message: String,
+}
+
+impl fmt::Display for InvalidDateBound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl Error for InvalidDateBound {}
+
+impl DateBound {
+    /// The first second covered by this bound
+    pub fn start(&self) -> PrimitiveDateTime {
+        PrimitiveDateTime::new(self.date, self.time.unwrap_or(Time::MIDNIGHT))
+    }
+
+    /// The last second covered by this bound
+    pub fn end(&self) -> PrimitiveDateTime {
+        let end_of_day = Time::from_hms(23, 59, 59).unwrap();
+        PrimitiveDateTime::new(self.date, self.time.unwrap_or(end_of_day))
+    }
+}
+
+impl FromStr for DateBound {
+    type Err = InvalidDateBound;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        if let Ok(datetime) = PrimitiveDateTime::parse(
+            input,
+            format_description!("[year]-[month]-[day] [hour]:[minute]:[second]"),
+        ) {
+            return Ok(Self {
+                date: datetime.date(),
+                time: Some(datetime.time()),
+            });
+        }
+        match Date::parse(input, format_description!("[year]-[month]-[day]")) {
+            Ok(date) => Ok(Self { date, time: None }),
+            Err(err) => Err(InvalidDateBound {
+                message: format!(
+                    "Invalid date {:?}, expected YYYY-MM-DD or YYYY-MM-DD hh:mm:ss: {}",
+                    input, err
+                ),
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for DateBound {
+    type Error = InvalidDateBound;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+impl fmt::Display for DateBound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let date = self
+            .date
+            .format(format_description!("[year]-[month]-[day]"))
+            .map_err(|_| fmt::Error)?;
+        match self.time {
+            Some(time) => {
+                let time = time
+                    .format(format_description!("[hour]:[minute]:[second]"))
+                    .map_err(|_| fmt::Error)?;
+                write!(f, "{} {}", date, time)
+            }
+            None => write!(f, "{}", date),
+        }
+    }
+}
+
+impl From<DateBound> for String {
+    fn from(bound: DateBound) -> Self {
+        bound.to_string()
+    }
+}
+
+/// Settings that control how backups are cloned
+#[derive(Debug, Clone)]
+pub struct CloneOptions {
+    /// Create the per-client destination directory as btrfs subvolume instead of a plain
+    /// directory. Falls back to a plain directory if the destination is not on btrfs.
+    pub client_subvolume: bool,
+    pub transfer: TransferMethod,
+    /// Command prefix used to run `btrfs send` on the source host, e.g. `["ssh", "root@host"]`.
+    /// Empty to run it locally. Only local sources can be sent, whose paths must exist on that
+    /// host as well.
+    pub send_command: Vec<String>,
+    /// Write a list of all data files with their checksums into each cloned backup
+    pub write_checksum_file: bool,
+    /// Only clone backups with ids in one of these ranges. Empty to clone all backups.
+    pub include_ids: Vec<IdRange>,
+    /// Never clone backups with ids in one of these ranges
+    pub exclude_ids: Vec<IdRange>,
+    /// Only clone backups with at least this id. Like all id filters, this does not delete
+    /// backups outside of the range from the destination, and they are still used as base.
+    pub min_id: Option<u64>,
+    /// Only clone backups with at most this id
+    pub max_id: Option<u64>,
+    /// Only clone backups taken on or after this date. Backups with timestamps that cannot be
+    /// parsed are cloned regardless of the date filters.
+    pub since: Option<DateBound>,
+    /// Only clone backups taken on or before this date
+    pub until: Option<DateBound>,
+    /// Only clone data files whose original path is below one of these directories, e.g.
+    /// "/etc". Empty to clone all files. Other files are removed from the clone.
+    pub include: Vec<PathBuf>,
+    /// Do not clone data files whose original path or one of its parent directories matches
+    /// one of these patterns, see `exclude_set`. Skipped files do not count as errors.
+    pub exclude: GlobSet,
+    /// Maximum number of transfers queued while reading the manifest. Reading pauses until
+    /// transfers finish, which limits memory usage for backups with many files.
+    pub max_queued_transfers: usize,
+    /// Keep only this many finished backups in the destination. Older backups are deleted
+    /// after cloning and not cloned in the first place. Backups outside of the id and date
+    /// filters are neither counted nor deleted.
+    pub keep_last: Option<usize>,
+    /// Only log what would be cloned and deleted, without changing the destination
+    pub dry_run: bool,
+    /// btrfs executable used for client subvolumes and send/receive
+    pub btrfs: PathBuf,
+    /// Number of times a failed file transfer is retried before it counts as error
+    pub transfer_retries: u32,
+    /// Check each copied data file against the checksum in the manifest. A mismatch counts as
+    /// failed transfer.
+    pub verify_on_copy: bool,
+    /// Limits the throughput of all data transfers sharing the limiter
+    pub bandwidth_limit: Option<Arc<RateLimiter>>,
+    /// Volume operations on the destination
+    pub storage: Arc<dyn StorageBackend>,
+    /// Keep daily, weekly and monthly backups. Combined with `keep_last`, a backup is kept if
+    /// any of both policies keeps it.
+    pub retention: Option<GfsRetention>,
+    /// Set, e.g. by a signal handler, to stop cloning. Running transfers are finished, but no
+    /// new ones are started and the interrupted backup stays partial, so the next run resumes it.
+    pub cancel: Arc<AtomicBool>,
+}
+
+/// Grandfather-father-son retention: keeps the newest backup of each of the last `daily` days,
+/// `weekly` (ISO) weeks and `monthly` months
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GfsRetention {
+    pub daily: u32,
+    pub weekly: u32,
+    pub monthly: u32,
+}
+
+impl Default for GfsRetention {
+    fn default() -> Self {
+        Self {
+            daily: 7,
+            weekly: 4,
+            monthly: 12,
+        }
+    }
+}
+
+impl GfsRetention {
+    /// Returns the ids of the backups to keep, counting days, weeks and months back from `now`
+    pub fn keep(
+        &self,
+        backups: &[(u64, PrimitiveDateTime)],
+        now: PrimitiveDateTime,
+    ) -> HashSet<u64> {
+        let mut sorted = backups.to_vec();
+        sorted.sort_by_key(|backup| std::cmp::Reverse(backup.1));
+
+        let today = now.date();
+        let mut days = HashSet::new();
+        let mut weeks = HashSet::new();
+        let mut months = HashSet::new();
+        let mut keep = HashSet::new();
+        for (id, time) in sorted {
+            let date = time.date();
+            let age_days = (today - date).whole_days();
+            let age_months = (today.year() - date.year()) * 12 + i32::from(u8::from(today.month()))
+                - i32::from(u8::from(date.month()));
+            let (week_year, week, _) = date.to_iso_week_date();
+            // sorted newest first, so the first backup seen in a period is the one to keep. All
+            // periods are marked as taken, even if the backup is kept for another period already.
+            let daily = age_days < i64::from(self.daily) && days.insert(date);
+            let weekly = age_days < 7 * i64::from(self.weekly) && weeks.insert((week_year, week));
+            let monthly =
+                age_months < self.monthly as i32 && months.insert((date.year(), date.month()));
+            if age_days < 0 || daily || weekly || monthly {
+                keep.insert(id);
+            }
+        }
+        keep
+    }
+}
+
+/// Builds the matcher for `CloneOptions::exclude`. `*` and `?` do not match '/', `**` does, and
+/// `**/` matches any number of directories, including none.
+pub fn exclude_set(patterns: &[Glob]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(
+            GlobBuilder::new(pattern.glob())
+                .literal_separator(true)
+                .backslash_escape(true)
+                .build()?,
+        );
+    }
+    builder.build()
+}
+
+impl Default for CloneOptions {
+    fn default() -> Self {
+        Self {
+            client_subvolume: false,
+            transfer: TransferMethod::default(),
+            send_command: Vec::new(),
+            write_checksum_file: false,
+            include_ids: Vec::new(),
+            exclude_ids: Vec::new(),
+            min_id: None,
+            max_id: None,
+            since: None,
+            until: None,
+            include: Vec::new(),
+            exclude: GlobSet::empty(),
+            max_queued_transfers: 64,
+            keep_last: None,
+            dry_run: false,
+            retention: None,
+            btrfs: PathBuf::from("btrfs"),
+            transfer_retries: 2,
+            verify_on_copy: false,
+            bandwidth_limit: None,
+            storage: Arc::new(BtrfsBackend::default()),
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl CloneOptions {
+    /// Whether cloning was interrupted by setting `cancel`
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::SeqCst)
+    }
+
+    /// Ids of the finished `backups` that are not kept by `keep_last` or `retention`. Backups
+    /// with a timestamp that cannot be parsed are never expired by `retention`.
+    fn expired_ids<'a>(&self, finished: impl Iterator<Item = &'a Backup>) -> Vec<u64> {
+        if self.keep_last.is_none() && self.retention.is_none() {
+            return Vec::new();
+        }
+        let finished = finished.collect::<Vec<&Backup>>();
+
+        let mut keep = HashSet::new();
+        if let Some(keep_last) = self.keep_last {
+            let ids = finished
+                .iter()
+                .map(|backup| backup.id)
+                .collect::<Vec<u64>>();
+            let expired = ids_beyond_limit(ids.to_vec(), keep_last);
+            keep.extend(ids.into_iter().filter(|id| !expired.contains(id)));
+        }
+        if let Some(retention) = &self.retention {
+            let mut dated = Vec::new();
+            for backup in &finished {
+                match backup.datetime() {
+                    Some(time) => dated.push((backup.id, time)),
+                    None => {
+                        keep.insert(backup.id);
+                    }
+                }
+            }
+            keep.extend(retention.keep(&dated, local_now()));
+        }
+
+        let mut expired = finished
+            .iter()
+            .map(|backup| backup.id)
+            .filter(|id| !keep.contains(id))
+            .collect::<Vec<u64>>();
+        expired.sort_unstable();
+        expired
+    }
+
+    /// Whether the backup with `id` passes the include and exclude filters. Backups that are
+    /// filtered out are neither cloned nor deleted from the destination, and may still serve as
+    /// base for other backups if they already exist there.
+    pub fn wants_backup(&self, id: u64) -> bool {
+        (self.include_ids.is_empty() || self.include_ids.iter().any(|range| range.contains(id)))
+            && !self.exclude_ids.iter().any(|range| range.contains(id))
+            && self.min_id.is_none_or(|min| id >= min)
+            && self.max_id.is_none_or(|max| id <= max)
+    }
+
+    /// Whether the data file with original path `path` is below one of the `include`
+    /// directories. A leading slash is optional, "etc" includes "/etc/passwd".
+    pub fn includes(&self, path: &Path) -> bool {
+        let without_root = |path: &Path| path.strip_prefix("/").unwrap_or(path).to_owned();
+        self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|prefix| without_root(path).starts_with(without_root(prefix)))
+    }
+
+    /// Whether the data file with original path `path` is skipped because of `exclude`
+    pub fn excludes(&self, path: &Path) -> bool {
+        path.ancestors().any(|path| self.exclude.is_match(path))
+    }
+
+    /// Whether `backup` was taken between `since` and `until`. Backups with timestamps that
+    /// cannot be parsed are always in range.
+    pub fn in_date_range(&self, backup: &Backup) -> bool {
+        match backup.datetime() {
+            Some(datetime) => {
+                self.since.is_none_or(|since| datetime >= since.start())
+                    && self.until.is_none_or(|until| datetime <= until.end())
+            }
+            None => true,
+        }
+    }
+}
+
+/// Current local time, or UTC if the local offset is unknown. burp names backups with local time.
+fn local_now() -> PrimitiveDateTime {
+    let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+    PrimitiveDateTime::new(now.date(), now.time())
+}
+
+/// Returns the ids exceeding `keep_last`, i.e. all but the `keep_last` highest ones
+fn ids_beyond_limit(mut ids: Vec<u64>, keep_last: usize) -> Vec<u64> {
+    ids.sort_unstable_by(|a, b| b.cmp(a));
+    ids.into_iter().skip(keep_last).collect()
+}
+
+#[derive(Debug)]
+struct SendReceiveError {
+    message: String,
+}
+
+impl fmt::Display for SendReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl Error for SendReceiveError {}
+
+/// Temporary file next to `to`, which is renamed to `to` once it is complete
+fn tmp_path(to: &Path) -> PathBuf {
+    let mut tmp_name = to.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".bdup.tmp");
+    to.with_file_name(tmp_name)
+}
+
+/// Lets `write` create a temporary file next to `to` and renames it into place when complete,
+/// so an interrupted transfer never leaves a truncated file at `to`.
+fn write_atomic(to: &Path, write: impl FnOnce(&Path) -> io::Result<u64>) -> io::Result<u64> {
+    let tmp = tmp_path(to);
+    let result = write(&tmp).and_then(|size| fs::rename(&tmp, to).map(|_| size));
+    if result.is_err() {
+        if let Err(error) = fs::remove_file(&tmp) {
+            if error.kind() != io::ErrorKind::NotFound {
+                log::warn!("Could not remove temporary file {:?}: {:?}", tmp, error);
+            }
+        }
+    }
+    result
+}
+
+/// Copies the local file `from` to `to`, see `write_atomic`
+fn copy_atomic(from: &Path, to: &Path, limiter: Option<&RateLimiter>) -> io::Result<u64> {
+    write_atomic(to, |tmp| match limiter {
+        Some(limiter) => copy_limited(from, tmp, limiter),
+        None => fs::copy(from, tmp),
+    })
+}
+
+/// Writes everything read from the input returned by `open` to `to`, through the temporary file
+/// of `write_atomic`. Unlike there, a temporary file of a failed transfer is kept, and the next
+/// transfer appends to it. `open` is called with the size of the temporary file and returns the
+/// input and the offset it starts at, e.g. 0 if the input cannot be resumed. Returns the size of
+/// the file at `to`.
+fn receive_resumable(
+    to: &Path,
+    open: impl FnOnce(u64) -> io::Result<(Box<dyn io::Read>, u64)>,
+    limiter: Option<&RateLimiter>,
+) -> io::Result<u64> {
+    let tmp = tmp_path(to);
+    let existing = match fs::metadata(&tmp) {
+        Ok(metadata) => metadata.len(),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => 0,
+        Err(error) => return Err(error),
+    };
+    let (mut input, offset) = open(existing)?;
+    if offset > existing {
+        return Err(io::Error::other(format!(
+            "Cannot resume {} at {} bytes, only {} were received",
+            tmp.display(),
+            offset,
+            existing
+        )));
+    }
+    if offset > 0 {
+        log::debug!("Resuming {} at {} bytes", to.display(), offset);
+    }
+    let mut output = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&tmp)?;
+    output.set_len(offset)?;
+    output.seek(io::SeekFrom::Start(offset))?;
+    let received = match limiter {
+        Some(limiter) => io::copy(&mut LimitedReader::new(&mut input, limiter), &mut output),
+        None => io::copy(&mut input, &mut output),
+    }?;
+    fs::rename(&tmp, to)?;
+    Ok(offset + received)
+}
+
+/// Checks the copied data file at `path` against its manifest entry, if `expected` is given
+fn verify_copy(path: &Path, expected: Option<&DataFileInfo>) -> io::Result<()> {
+    match expected {
+        Some(info) if !info.matches_file(path)? => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} does not match its checksum after copying",
+                path.display()
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Runs `transfer` until it succeeds, at most `retries + 1` times. Returns the last error if
+/// all attempts fail.
+pub(crate) fn with_retries<T>(
+    retries: u32,
+    description: &str,
+    mut transfer: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match transfer() {
+            Err(error) if attempt < retries => {
+                attempt += 1;
+                log::warn!(
+                    "Transfer of {} failed, retrying ({}/{}): {:?}",
+                    description,
+                    attempt,
+                    retries,
+                    error
+                );
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Like `fs::copy`, but reads `from` no faster than `limiter` allows
+fn copy_limited(from: &Path, to: &Path, limiter: &RateLimiter) -> io::Result<u64> {
+    let input = fs::File::open(from)?;
+    let permissions = input.metadata()?.permissions();
+    let mut output = fs::File::create(to)?;
+    let size = io::copy(&mut LimitedReader::new(input, limiter), &mut output)?;
+    fs::set_permissions(to, permissions)?;
+    Ok(size)
+}
+
+fn create_client_dir(dest: &Path, subvolume: bool, btrfs: &Path) -> Result<(), Box<dyn Error>> {
+    if subvolume {
+        log::debug!("Creating client subvolume at {}", dest.display());
+        let status = Command::new(btrfs)
+            .arg("subvolume")
+            .arg("create")
+            .arg(dest)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        match status {
+            Ok(status) if status.success() => return Ok(()),
+            _ => log::info!(
+                "Could not create subvolume at {}, falling back to a plain directory",
+                dest.display()
+            ),
+        }
+    }
+    fs::create_dir(dest)?;
+    Ok(())
+}
+
+/// Results of `Client::clone_backups_to`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CloneSummary {
+    /// Backups that were cloned completely
+    pub cloned: u64,
+    /// Backups that were filtered, not finished at the source or already cloned
+    pub skipped: u64,
+    /// Backups that were cloned with errors or interrupted and are not finished
+    pub failed: u64,
+    /// Files of failed backups that could not be transferred
+    pub files_failed: u64,
+    pub bytes_transferred: u64,
+    pub bytes_deduplicated: u64,
+}
+
+impl CloneSummary {
+    /// Counts the result of `Client::clone_backup`
+    fn add_clone(&mut self, stats: Option<CloneStats>) {
+        let Some(stats) = stats else {
+            self.skipped += 1;
+            return;
+        };
+        if stats.files_failed > 0 || stats.interrupted {
+            self.failed += 1;
+            self.files_failed += stats.files_failed;
+        } else {
+            self.cloned += 1;
+        }
+        self.bytes_transferred += stats.bytes_transferred;
+        self.bytes_deduplicated += stats.bytes_deduplicated;
+    }
+}
+
+impl std::ops::AddAssign for CloneSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.cloned += other.cloned;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.files_failed += other.files_failed;
+        self.bytes_transferred += other.bytes_transferred;
+        self.bytes_deduplicated += other.bytes_deduplicated;
+    }
+}
 
 pub trait Client {
     fn find_backups(&mut self, url: &str) -> Result<(), Box<dyn Error>>;
@@ -15,74 +676,415 @@ pub trait Client {
     fn backups(&self) -> &HashMap<u64, Backup>;
     fn backups_mut(&mut self) -> &mut HashMap<u64, Backup>;
 
+    /// All backups ordered by id, i.e. oldest first
+    fn backups_sorted(&self) -> Vec<&Backup> {
+        let mut backups = self.backups().values().collect::<Vec<&Backup>>();
+        backups.sort();
+        backups
+    }
+
+    /// The backup with the highest id, i.e. the newest one
+    fn latest_backup(&self) -> Option<&Backup> {
+        self.backups().values().max_by_key(|backup| backup.id)
+    }
+
+    fn backup_by_id(&self, id: u64) -> Option<&Backup> {
+        self.backups().get(&id)
+    }
+
     fn read_file(&self, backup: u64, name: &str) -> Result<Box<dyn io::Read>, Box<dyn Error>>;
 
+    /// Whether backup `id` contains the file `name`. The default implementation tries to open
+    /// it with `read_file`.
+    fn has_file(&self, id: u64, name: &str) -> bool {
+        self.read_file(id, name).is_ok()
+    }
+
+    /// Name of the manifest of backup `id`, the first of `MANIFEST_FILES` that exists. `None` if
+    /// the backup has no manifest.
+    fn manifest_name(&self, id: u64) -> Option<&'static str> {
+        MANIFEST_FILES
+            .into_iter()
+            .find(|name| self.has_file(id, name))
+    }
+
+    /// Whether `backup` is complete: it has a manifest and is no interrupted clone. Unlike
+    /// `Backup::is_finished`, this works for backups that are not on the local filesystem.
+    fn is_backup_finished(&self, backup: &Backup) -> bool {
+        self.manifest_name(backup.id).is_some() && !self.has_file(backup.id, ".bdup.partial")
+    }
+
+    /// Like `read_file`, but starts reading at byte `offset`, e.g. to resume an interrupted
+    /// download. Returns the reader and the offset it actually starts at, which is 0 for clients
+    /// that cannot skip the beginning of a file.
+    fn read_file_from(
+        &self,
+        backup: u64,
+        name: &str,
+        _offset: u64,
+    ) -> Result<(Box<dyn io::Read>, u64), Box<dyn Error>> {
+        Ok((self.read_file(backup, name)?, 0))
+    }
+
+    /// Verifies the data files of backup `id` against its manifest. The default implementation
+    /// reads the manifest and all data files through `read_file` one after the other, so
+    /// `threads` is not used and unwanted files are not detected.
+    fn verify_backup(&self, id: u64, _threads: usize) -> Result<VerifyReport, Box<dyn Error>> {
+        let backup = self.backup_by_id(id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No backup with id {}", id))
+        })?;
+        backup.verify_sequentially(&VerifyOptions::new(1), &|name: &Path| {
+            self.read_file(id, &name.to_string_lossy())
+        })
+    }
+
+    /// Clones all finished backups to `dest`. Backups are processed in ascending id order, so
+    /// the predecessor of each backup is already cloned and can be used as its base.
     fn clone_backups_to(
         &self,
         dest: &Path,
         transfer_threads: &ThreadPool,
-    ) -> Result<(), Box<dyn Error>> {
-        if !dest.exists() {
-            fs::create_dir(dest)?;
+        options: &CloneOptions,
+    ) -> Result<CloneSummary, Box<dyn Error>> {
+        let mut cloned = LocalClient::new(&format!("cloned_{}", self.name()));
+        let mut summary = CloneSummary::default();
+        if !dest.exists() && options.dry_run {
+            log::info!("Would create client directory {}", dest.display());
+        } else {
+            if !dest.exists() {
+                create_client_dir(dest, options.client_subvolume, &options.btrfs)?;
+            }
+            cloned.find_backups(&dest.to_string_lossy())?;
         }
 
-        let mut cloned = LocalClient::new(&format!("cloned_{}", self.name()));
-        cloned.find_backups(&dest.to_string_lossy())?;
+        // checking whether a source backup is finished may take requests to a server, so it is
+        // only done for backups that pass the filters. Backups that are finished in the
+        // destination were finished at the source when they were cloned.
+        let wanted = self
+            .backups()
+            .values()
+            .filter(|backup| options.wants_backup(backup.id) && options.in_date_range(backup))
+            .collect::<Vec<&Backup>>();
+        let is_cloned = |backup: &Backup| {
+            cloned
+                .backups
+                .get(&backup.id)
+                .is_some_and(Backup::is_finished)
+        };
+        let finished = wanted
+            .iter()
+            .filter(|backup| is_cloned(backup) || self.is_backup_finished(backup))
+            .map(|backup| backup.id)
+            .collect::<HashSet<u64>>();
+        let expired = options.expired_ids(
+            wanted
+                .into_iter()
+                .filter(|backup| finished.contains(&backup.id)),
+        );
 
-        for source in self.backups() {
-            if source.1.is_finished() {
-                self.clone_backup(source.1, dest, &mut cloned, transfer_threads)?;
+        for source in self.backups_sorted() {
+            #[cfg(feature = "systemd")]
+            crate::notify::watchdog();
+            if options.is_cancelled() {
+                log::warn!("Cloning of {} interrupted", self.name());
+                return Ok(summary);
+            }
+            if !options.wants_backup(source.id) {
+                log::info!(
+                    "Skipping clone of {}, because its id is filtered",
+                    source.path().display()
+                );
+                summary.skipped += 1;
+                continue;
+            }
+            if options.since.is_some() || options.until.is_some() {
+                if source.datetime().is_none() {
+                    log::warn!(
+                        "Cannot parse timestamp of {}, cloning it regardless of the date filter",
+                        source.path().display()
+                    );
+                } else if !options.in_date_range(source) {
+                    log::info!(
+                        "Skipping clone of {}, because its date is filtered",
+                        source.path().display()
+                    );
+                    summary.skipped += 1;
+                    continue;
+                }
+            }
+            if expired.contains(&source.id) {
+                log::info!(
+                    "Skipping clone of {}, because it expired by retention policy",
+                    source.path().display()
+                );
+                summary.skipped += 1;
+                continue;
+            }
+            if finished.contains(&source.id) {
+                if options.transfer == TransferMethod::BtrfsSend && !options.dry_run {
+                    match self.send_backup(source, dest, &mut cloned, options) {
+                        Ok(stats) => {
+                            summary.add_clone(stats);
+                            continue;
+                        }
+                        Err(error) => log::warn!(
+                            "Could not transfer {} with btrfs send, falling back to file copy: {:?}",
+                            source.path().display(),
+                            error
+                        ),
+                    }
+                }
+                let stats =
+                    self.clone_backup(source, dest, &mut cloned, transfer_threads, options)?;
+                summary.add_clone(stats);
             } else {
                 log::info!(
                     "Skipping clone of {}, because it is not finished",
-                    source.1.path().display()
+                    source.path().display()
                 );
+                summary.skipped += 1;
             }
         }
 
-        for backup in cloned
+        let vanished = cloned
             .backups
-            .iter_mut()
-            .filter(|backup| !self.backups().contains_key(backup.0))
-        {
-            match backup.1.delete() {
-                Ok(_) => log::debug!("Removed old backup {}", backup.1.path().display()),
+            .keys()
+            .filter(|id| !self.backups().contains_key(id))
+            .copied()
+            .collect::<Vec<u64>>();
+        for id in vanished {
+            let mut backup = cloned.backups.remove(&id).unwrap();
+            if options.dry_run {
+                log::info!("Would delete old backup {}", backup.path().display());
+                continue;
+            }
+            match backup.delete(options.storage.as_ref()) {
+                Ok(_) => log::debug!("Removed old backup {}", backup.path().display()),
                 Err(error) => log::error!(
                     "Could not remove old backup {}: {:?}",
-                    backup.1.path().display(),
+                    backup.path().display(),
                     error
                 ),
             }
         }
 
-        Ok(())
+        // like in the source, backups outside of the filters are not expired
+        let expired = options.expired_ids(cloned.backups.values().filter(|backup| {
+            backup.is_finished() && options.wants_backup(backup.id) && options.in_date_range(backup)
+        }));
+        for id in expired {
+            let mut backup = cloned.backups.remove(&id).unwrap();
+            if options.dry_run {
+                log::info!(
+                    "Would delete backup {}, expired by retention policy",
+                    backup.path().display()
+                );
+                continue;
+            }
+            match backup.delete(options.storage.as_ref()) {
+                Ok(_) => log::info!(
+                    "Removed backup {}, expired by retention policy",
+                    backup.path().display()
+                ),
+                Err(error) => log::error!(
+                    "Could not remove expired backup {}: {:?}",
+                    backup.path().display(),
+                    error
+                ),
+            }
+        }
+
+        Ok(summary)
     }
 
-    fn find_base_for(&mut self, id: u64) -> Option<&Backup> {
+    /// Reads the checksums of the data files of backup `id` from its manifest
+    fn load_checksums(&mut self, id: u64) -> Result<(), Box<dyn Error>> {
+        self.backups_mut().get_mut(&id).unwrap().load_checksums()
+    }
+
+    /// The nearest finished backup older than `id`, with its checksums loaded. Unfinished
+    /// backups are skipped, because their manifest may list files that were never transferred.
+    /// In a dry run, the backups planned before are used as well, although they do not exist.
+    fn find_base_for(&mut self, id: u64, options: &CloneOptions) -> Option<&Backup> {
         let base = self
-            .backups_mut()
-            .iter_mut()
-            .filter(|backup| *backup.0 < id)
-            .max();
-
-        if let Some(backup) = base {
-            backup
-                .1
-                .load_checksums()
-                .expect("Could not load checksums from base backup");
-            Some(backup.1)
-        } else {
-            None
+            .backups()
+            .values()
+            .filter(|base| base.id < id && (options.dry_run || base.is_finished()))
+            .map(|base| base.id)
+            .max()?;
+        self.load_checksums(base)
+            .expect("Could not load checksums from base backup");
+        self.backup_by_id(base)
+    }
+
+    /// Duplicates a backup subvolume with `btrfs send | btrfs receive`. The nearest older backup
+    /// that exists on both sides is used as parent for an incremental send. The subvolume is
+    /// received in `RECEIVE_DIR` and only moved into `dest` when both commands succeeded, so an
+    /// interrupted transfer never looks like a backup. Returns `None` if the backup was already
+    /// cloned. The transferred size is not known.
+    fn send_backup(
+        &self,
+        source: &Backup,
+        dest: &Path,
+        cloned: &mut LocalClient,
+        options: &CloneOptions,
+    ) -> Result<Option<CloneStats>, Box<dyn Error>> {
+        if !source.is_local_backup() {
+            return Err(Box::new(SendReceiveError {
+                message: format!("{} is not a local backup", source.path().display()),
+            }));
+        }
+        let dest_backup = Backup::new(&dest.to_string_lossy(), &source.dir_name(), true)?;
+        if dest_backup.is_finished() {
+            log::debug!(
+                "Backup {} is already finished.",
+                dest_backup.path().display()
+            );
+            return Ok(None);
+        }
+        if dest_backup.path().exists() {
+            return Err(Box::new(SendReceiveError {
+                message: format!(
+                    "Unable to receive into existing directory {}",
+                    dest_backup.path().display()
+                ),
+            }));
+        }
+
+        let parent = cloned
+            .backups
+            .keys()
+            .filter(|id| **id < source.id && self.backups().contains_key(id))
+            .max()
+            .and_then(|id| self.backup_by_id(*id));
+
+        let mut args = options.send_command.clone();
+        args.extend([
+            options.btrfs.to_string_lossy().to_string(),
+            "send".to_string(),
+        ]);
+        if let Some(parent) = parent {
+            args.extend([
+                "-p".to_string(),
+                parent.path().to_string_lossy().to_string(),
+            ]);
+        }
+        args.push(source.path().to_string_lossy().to_string());
+        log::info!(
+            "Sending backup {}/{} {}",
+            &self.name(),
+            source.dir_name(),
+            match parent {
+                Some(backup) => format!("with parent {}", backup.path().display()),
+                None => "without parent".to_string(),
+            }
+        );
+        log::debug!("Running {:?}", args);
+
+        // received backups are always subvolumes, regardless of the configured storage
+        let btrfs = BtrfsBackend::new(&options.btrfs);
+        let staging = dest.join(RECEIVE_DIR);
+        let received = staging.join(source.dir_name());
+        if received.exists() {
+            log::info!("Removing incomplete backup {}", received.display());
+            btrfs.delete(&received)?;
+        }
+        fs::create_dir_all(&staging)?;
+
+        let mut send = Command::new(&args[0])
+            .args(&args[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let receive_status = match send.stdout.take() {
+            Some(stream) => Command::new(&options.btrfs)
+                .arg("receive")
+                .arg(&staging)
+                .stdin(stream)
+                .stdout(Stdio::null())
+                .status(),
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "btrfs send has no output stream",
+            )),
+        };
+        let send_status = send.wait()?;
+
+        if send_status.success() && receive_status.as_ref().is_ok_and(|s| s.success()) {
+            fs::rename(&received, dest_backup.path())?;
+            let _ = fs::remove_dir(&staging);
+            let dest_backup = Backup::new(&dest.to_string_lossy(), &source.dir_name(), true)?;
+            cloned.backups.insert(dest_backup.id, dest_backup);
+            return Ok(Some(CloneStats::default()));
+        }
+
+        if received.exists() {
+            btrfs.delete(&received)?;
         }
+        let _ = fs::remove_dir(&staging);
+        Err(Box::new(SendReceiveError {
+            message: format!(
+                "btrfs send exited with {}, btrfs receive with {:?}",
+                send_status, receive_status
+            ),
+        }))
     }
 
+    /// Transfers `source_path` of a remote backup to `dest_path` with `read_file`
+    fn receive_file(
+        &self,
+        source: &Backup,
+        source_path: &OsStr,
+        dest_path: &Path,
+        expected: Option<&DataFileInfo>,
+        options: &CloneOptions,
+    ) -> TransferResult {
+        let name = source_path.to_string_lossy();
+        let mut result = TransferResult {
+            source: source.path().join(source_path).into(),
+            dest: dest_path.to_owned().into(),
+            size: 0,
+            error: None,
+        };
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent).expect("Unable to create target directories");
+        }
+        if let Err(error) = fs::remove_file(dest_path) {
+            if error.kind() != io::ErrorKind::NotFound {
+                log::warn!(
+                    "Could not remove outdated file {:?}: {:?}",
+                    dest_path,
+                    error
+                );
+            }
+        }
+        let receive = || {
+            let open = |offset| {
+                self.read_file_from(source.id, &name, offset)
+                    .map_err(|error| io::Error::other(error.to_string()))
+            };
+            let size = receive_resumable(dest_path, open, options.bandwidth_limit.as_deref())?;
+            verify_copy(dest_path, expected)?;
+            Ok(size)
+        };
+        match with_retries(options.transfer_retries, &name, receive) {
+            Ok(size) => result.size = size,
+            Err(error) => result.error = Some(format!("{:?}", error)),
+        }
+        result
+    }
+
+    /// Clones `source` to client directory `dest`. Returns `None` if the backup was already
+    /// cloned. Files of local backups are copied by `transfer_threads`, those of remote backups
+    /// are downloaded one after another, because clients are not shared between threads.
     fn clone_backup(
         &self,
         source: &Backup,
         dest: &Path,
         cloned: &mut LocalClient,
         transfer_threads: &ThreadPool,
-    ) -> Result<(), Box<dyn Error>> {
+        options: &CloneOptions,
+    ) -> Result<Option<CloneStats>, Box<dyn Error>> {
         let mut dest_backup = Backup::new(&dest.to_string_lossy(), &source.dir_name(), true)?;
 
         if dest_backup.is_finished() {
@@ -90,43 +1092,100 @@ pub trait Client {
                 "Backup {} is already finished.",
                 dest_backup.path().display()
             );
-            return Ok(());
+            return Ok(None);
         }
 
-        let base_backup = cloned.find_base_for(source.id);
+        let base_backup = cloned.find_base_for(source.id, options);
         let base_msg = match base_backup {
             Some(backup) => format!("with base {}", backup.path().display()),
             None => "without base".to_string(),
         };
+        // only finished backups are cloned, so a missing manifest is reported when reading it
+        let manifest_name = self.manifest_name(source.id).unwrap_or(MANIFEST_FILES[0]);
+        let size_msg = match source.listed_size() {
+            Some(size) => format!(" ({} at source)", format_bytes(size)),
+            None => String::new(),
+        };
+        if options.dry_run {
+            log::info!(
+                "Would clone backup {}/{} {}{}",
+                &self.name(),
+                source.dir_name(),
+                base_msg,
+                size_msg
+            );
+            dest_backup.plan_clone_from(
+                &base_backup,
+                self.read_file(source.id, manifest_name)?,
+                options,
+            )?;
+            cloned.backups.insert(dest_backup.id, dest_backup);
+            return Ok(Some(CloneStats::default()));
+        }
         log::info!(
-            "Cloning backup {}/{} {}",
+            "Cloning backup {}/{} {}{}",
             &self.name(),
             source.dir_name(),
-            base_msg
+            base_msg,
+            size_msg
         );
-        dest_backup.clone_from(&base_backup, &|source_path, dest_path, tx| {
-            let from = source.path().join(source_path);
-            let to = dest_path.to_owned();
-            let tx_clone = tx.clone();
-            transfer_threads.execute(move || {
-                if let Some(parent) = to.parent() {
-                    fs::create_dir_all(parent).expect("Unable to create target directories");
+        let stats = dest_backup.clone_from(
+            &base_backup,
+            manifest_name,
+            &|source_path, dest_path, data, tx| {
+                let expected = data.filter(|_| options.verify_on_copy);
+                if !source.is_local_backup() {
+                    // remote streams cannot be moved to the transfer threads
+                    let result =
+                        self.receive_file(source, source_path, dest_path, expected, options);
+                    tx.send(result).expect("Unable to send result");
+                    return;
                 }
-                let mut result = TransferResult {
-                    source: from.to_owned().into(),
-                    dest: to.to_owned().into(),
-                    size: 0,
-                    error: None,
-                };
-                match fs::copy(from, to) {
-                    Ok(size) => result.size = size,
-                    Err(error) => result.error = Some(format!("{:?}", error)),
-                }
-                tx_clone.send(result).expect("Unable to send result");
-            });
-        })?;
+                let from = source.path().join(source_path);
+                let to = dest_path.to_owned();
+                let tx_clone = tx.clone();
+                let limiter = options.bandwidth_limit.clone();
+                let retries = options.transfer_retries;
+                let expected = expected.cloned();
+                let cancel = options.cancel.clone();
+                transfer_threads.execute(move || {
+                    if let Some(parent) = to.parent() {
+                        fs::create_dir_all(parent).expect("Unable to create target directories");
+                    }
+                    let mut result = TransferResult {
+                        source: from.to_owned().into(),
+                        dest: to.to_owned().into(),
+                        size: 0,
+                        error: None,
+                    };
+                    if cancel.load(Ordering::SeqCst) {
+                        result.error = Some("Interrupted before the transfer started".to_string());
+                        tx_clone.send(result).expect("Unable to send result");
+                        return;
+                    }
+                    // the file might be a hardlink shared with the base backup, which must not
+                    // be overwritten in place
+                    if let Err(error) = fs::remove_file(&to) {
+                        if error.kind() != io::ErrorKind::NotFound {
+                            log::warn!("Could not remove outdated file {:?}: {:?}", to, error);
+                        }
+                    }
+                    let copy = || {
+                        let size = copy_atomic(&from, &to, limiter.as_deref())?;
+                        verify_copy(&to, expected.as_ref())?;
+                        Ok(size)
+                    };
+                    match with_retries(retries, &from.to_string_lossy(), copy) {
+                        Ok(size) => result.size = size,
+                        Err(error) => result.error = Some(format!("{:?}", error)),
+                    }
+                    tx_clone.send(result).expect("Unable to send result");
+                });
+            },
+            options,
+        )?;
         cloned.backups.insert(dest_backup.id, dest_backup);
-        Ok(())
+        Ok(Some(stats))
     }
 }
 
@@ -182,8 +1241,6 @@ impl Client for LocalClient {
                 ),
             };
         }
-        // self.backups
-        //     .sort_unstable_by(|a, b| a.id.partial_cmp(&b.id).unwrap());
         Ok(())
     }
 
@@ -191,4 +1248,828 @@ impl Client for LocalClient {
         let base_path = self.backups.get(&backup).unwrap().path();
         Ok(Box::new(fs::File::open(base_path.join(name))?))
     }
+
+    fn has_file(&self, id: u64, name: &str) -> bool {
+        self.backups
+            .get(&id)
+            .is_some_and(|backup| backup.path().join(name).exists())
+    }
+
+    /// Verifies the backup on disk with `threads` worker threads, including the detection of
+    /// unwanted files
+    fn verify_backup(&self, id: u64, threads: usize) -> Result<VerifyReport, Box<dyn Error>> {
+        let backup = self.backups.get(&id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No backup with id {}", id))
+        })?;
+        Backup::from_path(&backup.path())?.verify_detailed(threads)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Records the base that would be available for each cloned backup instead of copying
+    struct RecordingClient {
+        backups: HashMap<u64, Backup>,
+        bases: RefCell<Vec<(u64, Option<u64>)>>,
+    }
+
+    impl Client for RecordingClient {
+        fn find_backups(&mut self, _url: &str) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn backups(&self) -> &HashMap<u64, Backup> {
+            &self.backups
+        }
+
+        fn backups_mut(&mut self) -> &mut HashMap<u64, Backup> {
+            &mut self.backups
+        }
+
+        fn read_file(
+            &self,
+            _backup: u64,
+            _name: &str,
+        ) -> Result<Box<dyn io::Read>, Box<dyn Error>> {
+            Err("not supported by RecordingClient".into())
+        }
+
+        fn has_file(&self, id: u64, name: &str) -> bool {
+            self.backups[&id].path().join(name).exists()
+        }
+
+        fn clone_backup(
+            &self,
+            source: &Backup,
+            dest: &Path,
+            cloned: &mut LocalClient,
+            _transfer_threads: &ThreadPool,
+            _options: &CloneOptions,
+        ) -> Result<Option<CloneStats>, Box<dyn Error>> {
+            let base = cloned.backups.keys().filter(|id| **id < source.id).max();
+            self.bases.borrow_mut().push((source.id, base.copied()));
+            let backup = Backup::new(&dest.to_string_lossy(), &source.dir_name(), true)?;
+            cloned.backups.insert(backup.id, backup);
+            Ok(Some(CloneStats::default()))
+        }
+    }
+
+    #[test]
+    fn parse_id_range() {
+        assert_eq!(
+            "100-200".parse::<IdRange>().unwrap(),
+            IdRange {
+                first: 100,
+                last: 200
+            }
+        );
+        assert_eq!(
+            "42".parse::<IdRange>().unwrap(),
+            IdRange {
+                first: 42,
+                last: 42
+            }
+        );
+        assert!("200-100".parse::<IdRange>().is_err());
+        assert!("1-x".parse::<IdRange>().is_err());
+        assert!("".parse::<IdRange>().is_err());
+    }
+
+    #[test]
+    fn id_range_is_inclusive() {
+        let options = CloneOptions {
+            include_ids: vec!["3-5".parse().unwrap(), "9".parse().unwrap()],
+            exclude_ids: vec!["4".parse().unwrap()],
+            ..Default::default()
+        };
+        let wanted = (1..=10)
+            .filter(|id| options.wants_backup(*id))
+            .collect::<Vec<u64>>();
+        assert_eq!(wanted, vec![3, 5, 9]);
+    }
+
+    #[test]
+    fn parse_date_bound() {
+        let day = "2021-04-11".parse::<DateBound>().unwrap();
+        assert_eq!(day.start(), time::macros::datetime!(2021-04-11 00:00:00));
+        assert_eq!(day.end(), time::macros::datetime!(2021-04-11 23:59:59));
+        assert_eq!(day.to_string(), "2021-04-11");
+        let exact = "2021-04-11 12:30:00".parse::<DateBound>().unwrap();
+        assert_eq!(exact.start(), time::macros::datetime!(2021-04-11 12:30:00));
+        assert_eq!(exact.end(), exact.start());
+        assert_eq!(exact.to_string(), "2021-04-11 12:30:00");
+        assert!("2021-13-01".parse::<DateBound>().is_err());
+        assert!("last month".parse::<DateBound>().is_err());
+    }
+
+    #[test]
+    fn date_range() {
+        let options = CloneOptions {
+            since: Some("2021-04-11".parse().unwrap()),
+            until: Some("2021-04-12".parse().unwrap()),
+            ..Default::default()
+        };
+        let in_range = |name: &str| options.in_date_range(&Backup::new("/", name, true).unwrap());
+        assert!(!in_range("0000001 2021-04-10 23:59:59"));
+        assert!(in_range("0000002 2021-04-11 00:00:00"));
+        assert!(in_range("0000003 2021-04-12 23:00:00"));
+        assert!(!in_range("0000004 2021-04-13 00:00:00"));
+        assert!(in_range("0000005 unknown"));
+    }
+
+    #[test]
+    fn min_max_id() {
+        let options = CloneOptions {
+            min_id: Some(3),
+            max_id: Some(5),
+            exclude_ids: vec!["4".parse().unwrap()],
+            ..Default::default()
+        };
+        let wanted = (1..=10)
+            .filter(|id| options.wants_backup(*id))
+            .collect::<Vec<u64>>();
+        assert_eq!(wanted, vec![3, 5]);
+    }
+
+    #[test]
+    fn exclude_patterns() {
+        let excludes = |pattern: &str, path: &str| {
+            let options = CloneOptions {
+                exclude: exclude_set(&[pattern.parse().unwrap()]).unwrap(),
+                ..Default::default()
+            };
+            options.excludes(Path::new(path))
+        };
+        assert!(excludes("/home/*/.cache", "/home/burp/.cache"));
+        assert!(excludes(
+            "/home/*/.cache",
+            "/home/burp/.cache/thumbnails/a.png"
+        ));
+        assert!(!excludes("/home/*/.cache", "/home/a/b/.cache"));
+        assert!(!excludes("/home/*/.cache", "/home/burp/.cached"));
+        assert!(excludes("/var/**", "/var/cache/apt/archives"));
+        assert!(excludes("**/*.tmp", "/a/b/c.tmp"));
+        assert!(excludes("/a/**/b", "/a/b"));
+        assert!(excludes("/a/**/b", "/a/x/y/b"));
+        assert!(!excludes("/file?.log", "/file10.log"));
+        assert!(excludes("/log.[!0-9]", "/log.x"));
+        assert!(!excludes("/a\\*", "/ab"));
+        assert!("/log.[0-9".parse::<Glob>().is_err());
+    }
+
+    #[test]
+    fn resume_receive() {
+        let dir = std::env::temp_dir().join(format!("bdup-resume-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let content = b"0123456789";
+        let open_at = |start: u64| {
+            move |offset: u64| -> io::Result<(Box<dyn io::Read>, u64)> {
+                let start = start.min(offset);
+                Ok((Box::new(&content[start as usize..]), start))
+            }
+        };
+
+        // left over by an interrupted transfer
+        fs::write(dir.join("resumed.bdup.tmp"), b"0123").unwrap();
+        assert_eq!(
+            receive_resumable(&dir.join("resumed"), open_at(u64::MAX), None).unwrap(),
+            10
+        );
+        assert_eq!(fs::read(dir.join("resumed")).unwrap(), content);
+        assert!(!dir.join("resumed.bdup.tmp").exists());
+
+        // the input cannot be resumed, the partial file is overwritten
+        fs::write(dir.join("restarted.bdup.tmp"), b"xxxxxxxxxxxx").unwrap();
+        assert_eq!(
+            receive_resumable(&dir.join("restarted"), open_at(0), None).unwrap(),
+            10
+        );
+        assert_eq!(fs::read(dir.join("restarted")).unwrap(), content);
+
+        // a failed transfer keeps the partial file
+        let failing = |_| -> io::Result<(Box<dyn io::Read>, u64)> {
+            Err(io::Error::other("connection reset"))
+        };
+        fs::write(dir.join("failed.bdup.tmp"), b"0123").unwrap();
+        assert!(receive_resumable(&dir.join("failed"), failing, None).is_err());
+        assert_eq!(fs::read(dir.join("failed.bdup.tmp")).unwrap(), b"0123");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_copy() {
+        let dir = std::env::temp_dir().join(format!("bdup-atomic-copy-{}", std::process::id()));
+        fs::create_dir_all(dir.join("source_dir")).unwrap();
+        fs::write(dir.join("source"), b"content").unwrap();
+        // left over by an interrupted copy
+        fs::write(dir.join("target.bdup.tmp"), b"cont").unwrap();
+
+        assert_eq!(
+            copy_atomic(&dir.join("source"), &dir.join("target"), None).unwrap(),
+            7
+        );
+        assert_eq!(fs::read(dir.join("target")).unwrap(), b"content");
+        assert!(!dir.join("target.bdup.tmp").exists());
+
+        assert!(copy_atomic(&dir.join("source_dir"), &dir.join("failed"), None).is_err());
+        assert!(!dir.join("failed").exists());
+        assert!(!dir.join("failed.bdup.tmp").exists());
+
+        let limiter = RateLimiter::new(1000);
+        fs::set_permissions(dir.join("source"), fs::Permissions::from_mode(0o640)).unwrap();
+        assert_eq!(
+            copy_atomic(&dir.join("source"), &dir.join("limited"), Some(&limiter)).unwrap(),
+            7
+        );
+        assert_eq!(fs::read(dir.join("limited")).unwrap(), b"content");
+        let mode = fs::metadata(dir.join("limited"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o640);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retry_flaky_transfer() {
+        let attempts = RefCell::new(0);
+        let flaky = || {
+            *attempts.borrow_mut() += 1;
+            match *attempts.borrow() {
+                1 => Err(io::Error::new(io::ErrorKind::TimedOut, "flaky")),
+                _ => Ok(42),
+            }
+        };
+        assert_eq!(with_retries(2, "flaky", flaky).unwrap(), 42);
+        assert_eq!(*attempts.borrow(), 2);
+
+        let attempts = RefCell::new(0);
+        let failing = || -> io::Result<()> {
+            *attempts.borrow_mut() += 1;
+            Err(io::Error::new(io::ErrorKind::TimedOut, "failing"))
+        };
+        assert!(with_retries(2, "failing", failing).is_err());
+        assert_eq!(*attempts.borrow(), 3);
+        assert!(with_retries(0, "failing", failing).is_err());
+        assert_eq!(*attempts.borrow(), 4);
+    }
+
+    #[test]
+    fn keep_last_ids() {
+        assert_eq!(ids_beyond_limit(vec![3, 1, 5, 2, 4], 2), vec![3, 2, 1]);
+        assert_eq!(ids_beyond_limit(vec![3, 1], 5), Vec::<u64>::new());
+        assert_eq!(ids_beyond_limit(vec![3, 1], 0), vec![3, 1]);
+    }
+
+    #[test]
+    fn gfs_retention() {
+        let now = time::macros::datetime!(2021-04-11 12:00:00);
+        let backups = [
+            (1, time::macros::datetime!(2021-04-11 08:00:00)),
+            (2, time::macros::datetime!(2021-04-11 10:00:00)),
+            (3, time::macros::datetime!(2021-04-10 10:00:00)),
+            // sunday of the previous week and friday before it
+            (4, time::macros::datetime!(2021-04-04 10:00:00)),
+            (5, time::macros::datetime!(2021-04-02 10:00:00)),
+            (6, time::macros::datetime!(2021-02-14 10:00:00)),
+            (7, time::macros::datetime!(2021-02-10 10:00:00)),
+            (8, time::macros::datetime!(2019-01-01 10:00:00)),
+        ];
+        let retention = GfsRetention {
+            daily: 2,
+            weekly: 2,
+            monthly: 3,
+        };
+        let mut keep = retention
+            .keep(&backups, now)
+            .into_iter()
+            .collect::<Vec<u64>>();
+        keep.sort_unstable();
+        assert_eq!(keep, vec![2, 3, 4, 6]);
+    }
+
+    #[test]
+    fn backups_sorted_by_id() {
+        let mut client = LocalClient::new("test");
+        for name in [
+            "0000003 2021-04-13 00:00:00",
+            "0000001 2021-04-11 00:00:00",
+            "0000002 2021-04-12 00:00:00",
+        ] {
+            let backup = Backup::new("/", name, true).unwrap();
+            client.backups.insert(backup.id, backup);
+        }
+        let ids = client
+            .backups_sorted()
+            .iter()
+            .map(|backup| backup.id)
+            .collect::<Vec<u64>>();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn latest_backup_and_by_id() {
+        let mut client = LocalClient::new("test");
+        assert!(client.latest_backup().is_none());
+        for name in [
+            "0000002 2021-04-12 00:00:00",
+            "0000010 2021-04-20 00:00:00",
+            "0000001 2021-04-11 00:00:00",
+        ] {
+            let backup = Backup::new("/", name, true).unwrap();
+            client.backups.insert(backup.id, backup);
+        }
+        assert_eq!(client.latest_backup().map(|backup| backup.id), Some(10));
+        assert_eq!(
+            client.backup_by_id(2).map(|backup| backup.dir_name()),
+            Some("0000002 2021-04-12 00:00:00".to_string())
+        );
+        assert!(client.backup_by_id(3).is_none());
+    }
+
+    #[test]
+    fn base_skips_unfinished_backups() {
+        let base = std::env::temp_dir().join(format!("bdup-find-base-{}", std::process::id()));
+        for name in ["0000001 2021-04-11 00:00:00", "0000002 2021-04-12 00:00:00"] {
+            crate::backup::test::create_test_backup_at(&base, name, &[("t/a", b"a")]);
+        }
+        fs::write(base.join("0000002 2021-04-12 00:00:00/.bdup.partial"), b"").unwrap();
+        let mut client = LocalClient::new("test");
+        client.find_backups(&base.to_string_lossy()).unwrap();
+
+        let options = CloneOptions::default();
+        let found = client.find_base_for(3, &options).unwrap();
+        assert_eq!(found.id, 1);
+        assert!(found.data_file_info(Path::new("t/a")).is_some());
+        assert!(client.find_base_for(1, &options).is_none());
+        let dry_run = CloneOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(client.find_base_for(3, &dry_run).unwrap().id, 2);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn clone_bases_before_dependents() {
+        let base = std::env::temp_dir().join(format!("bdup-clone-order-{}", std::process::id()));
+        let mut client = RecordingClient {
+            backups: HashMap::new(),
+            bases: RefCell::new(Vec::new()),
+        };
+        for name in [
+            "0000002 2021-04-12 00:00:00",
+            "0000003 2021-04-13 00:00:00",
+            "0000001 2021-04-11 00:00:00",
+        ] {
+            let backup = Backup::new(&base.join("source").to_string_lossy(), name, false).unwrap();
+            fs::create_dir_all(backup.path()).unwrap();
+            fs::write(backup.path().join("manifest.gz"), b"").unwrap();
+            client.backups.insert(backup.id, backup);
+        }
+
+        client
+            .clone_backups_to(
+                &base.join("dest"),
+                &ThreadPool::new(1),
+                &CloneOptions::default(),
+            )
+            .unwrap();
+        let bases = client.bases.borrow();
+        assert_eq!(*bases, vec![(1, None), (2, Some(1)), (3, Some(2))]);
+        assert_eq!(bases.iter().filter(|(_, base)| base.is_some()).count(), 2);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn clone_with_reflink_storage() {
+        let base = std::env::temp_dir().join(format!("bdup-reflink-clone-{}", std::process::id()));
+        let source_dir = base.join("source");
+        let backups = [
+            ("0000001 2021-04-11 00:00:00", b"old"),
+            ("0000002 2021-04-12 00:00:00", b"new"),
+        ];
+        for (name, content) in backups {
+            create_source_backup(&source_dir, name, &[("t/a", b"content"), ("t/b", content)]);
+        }
+        let mut source = LocalClient::new("client");
+        source.find_backups(&source_dir.to_string_lossy()).unwrap();
+        let options = CloneOptions {
+            storage: Arc::new(crate::storage::ReflinkBackend),
+            ..Default::default()
+        };
+
+        let dest = base.join("dest");
+        let summary = source
+            .clone_backups_to(&dest, &ThreadPool::new(2), &options)
+            .unwrap();
+        assert_eq!((summary.cloned, summary.skipped, summary.failed), (2, 0, 0));
+        assert!(summary.bytes_deduplicated > 0);
+        let summary = source
+            .clone_backups_to(&dest, &ThreadPool::new(2), &options)
+            .unwrap();
+        assert_eq!((summary.cloned, summary.skipped, summary.failed), (0, 2, 0));
+        for (name, content) in backups {
+            let backup = Backup::from_path(&dest.join(name)).unwrap();
+            assert!(backup.is_finished());
+            let mut data = Vec::new();
+            flate2::read::GzDecoder::new(fs::File::open(backup.path().join("data/t/b")).unwrap())
+                .read_to_end(&mut data)
+                .unwrap();
+            assert_eq!(data, content);
+        }
+        options.storage.delete(&base).unwrap();
+    }
+
+    #[test]
+    fn send_receives_into_staging_dir() {
+        let base = std::env::temp_dir().join(format!("bdup-send-receive-{}", std::process::id()));
+        fs::create_dir_all(&base).unwrap();
+        // "sends" the path of the backup and "receives" by copying it, fails if base/fail exists
+        let program = base.join("fake-btrfs");
+        let log = base.join("calls");
+        fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {log}\ncase \"$1\" in\n\
+                 send) for last; do :; done; echo \"$last\" ;;\n\
+                 receive) read -r src; cp -r \"$src\" \"$2/\"; test ! -e {fail} ;;\n\
+                 subvolume) rm -rf \"$3\" ;;\nesac\n",
+                log = log.display(),
+                fail = base.join("fail").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let source_dir = base.join("source");
+        let first = "0000001 2021-04-11 00:00:00";
+        let second = "0000002 2021-04-12 00:00:00";
+        create_source_backup(&source_dir, first, &[("t/a", b"a")]);
+        create_source_backup(&source_dir, second, &[("t/a", b"b")]);
+        let mut source = LocalClient::new("client");
+        source.find_backups(&source_dir.to_string_lossy()).unwrap();
+        let dest = base.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let mut cloned = LocalClient::new("client");
+        let options = CloneOptions {
+            btrfs: program.clone(),
+            ..Default::default()
+        };
+
+        let stats = source
+            .send_backup(&source.backups[&1], &dest, &mut cloned, &options)
+            .unwrap();
+        assert!(stats.is_some());
+        assert!(Backup::from_path(&dest.join(first)).unwrap().is_finished());
+        assert!(cloned.backups.contains_key(&1));
+        assert!(!dest.join(RECEIVE_DIR).exists());
+
+        // a failed receive leaves neither the backup nor the staging directory behind
+        fs::write(base.join("fail"), b"").unwrap();
+        assert!(source
+            .send_backup(&source.backups[&2], &dest, &mut cloned, &options)
+            .is_err());
+        assert!(!dest.join(second).exists());
+        assert!(!dest.join(RECEIVE_DIR).exists());
+        assert!(!cloned.backups.contains_key(&2));
+
+        // send and receive run concurrently, so their calls are logged in any order
+        let mut calls = fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        calls.sort();
+        let staging = dest.join(RECEIVE_DIR);
+        let (parent, sent) = (source.backups[&1].path(), source.backups[&2].path());
+        assert_eq!(
+            calls,
+            [
+                format!("receive {}", staging.display()),
+                format!("receive {}", staging.display()),
+                format!("send -p {} {}", parent.display(), sent.display()),
+                format!("send {}", parent.display()),
+                format!("subvolume delete {}", staging.join(second).display()),
+            ]
+        );
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn keep_last_ignores_filtered_backups() {
+        let base = std::env::temp_dir().join(format!("bdup-keep-filtered-{}", std::process::id()));
+        let source_dir = base.join("source");
+        let names = [
+            "0000001 2021-04-11 00:00:00",
+            "0000002 2021-04-12 00:00:00",
+            "0000003 2021-04-13 00:00:00",
+            "0000004 2021-04-14 00:00:00",
+        ];
+        for name in names {
+            create_source_backup(&source_dir, name, &[("t/a", b"a")]);
+        }
+        let mut source = LocalClient::new("client");
+        source.find_backups(&source_dir.to_string_lossy()).unwrap();
+        let storage = Arc::new(crate::storage::ReflinkBackend);
+        let dest = base.join("dest");
+        let options = CloneOptions {
+            storage: storage.clone(),
+            ..Default::default()
+        };
+        source
+            .clone_backups_to(&dest, &ThreadPool::new(1), &options)
+            .unwrap();
+
+        let options = CloneOptions {
+            storage: storage.clone(),
+            min_id: Some(3),
+            keep_last: Some(1),
+            ..Default::default()
+        };
+        source
+            .clone_backups_to(&dest, &ThreadPool::new(1), &options)
+            .unwrap();
+        let kept = names.map(|name| dest.join(name).exists());
+        assert_eq!(kept, [true, true, false, true]);
+        storage.delete(&base).unwrap();
+    }
+
+    /// Creates a finished backup with all metadata files, as the source of a clone
+    pub(crate) fn create_source_backup(base: &Path, name: &str, files: &[(&str, &[u8])]) -> Backup {
+        let backup = crate::backup::test::create_test_backup_at(base, name, files);
+        for file in Backup::metadata_files() {
+            fs::write(backup.path().join(file), b"").unwrap();
+        }
+        backup
+    }
+
+    /// Serves the backups of a local directory as if they were remote, with any URL. Records
+    /// the ids of the backups whose files are looked for with `has_file`.
+    pub(crate) struct SpoolClient {
+        dir: PathBuf,
+        backups: HashMap<u64, Backup>,
+        checked: RefCell<HashSet<u64>>,
+    }
+
+    impl SpoolClient {
+        pub(crate) fn new(dir: &Path) -> Self {
+            Self {
+                dir: dir.to_owned(),
+                backups: HashMap::new(),
+                checked: RefCell::new(HashSet::new()),
+            }
+        }
+    }
+
+    impl Client for SpoolClient {
+        fn find_backups(&mut self, url: &str) -> Result<(), Box<dyn Error>> {
+            for entry in fs::read_dir(&self.dir)? {
+                let backup = Backup::new(url, &entry?.file_name().to_string_lossy(), false)?;
+                self.backups.insert(backup.id, backup);
+            }
+            Ok(())
+        }
+
+        fn name(&self) -> &str {
+            "spool"
+        }
+
+        fn backups(&self) -> &HashMap<u64, Backup> {
+            &self.backups
+        }
+
+        fn backups_mut(&mut self) -> &mut HashMap<u64, Backup> {
+            &mut self.backups
+        }
+
+        fn read_file(&self, backup: u64, name: &str) -> Result<Box<dyn io::Read>, Box<dyn Error>> {
+            let backup = self.backups.get(&backup).unwrap();
+            Ok(Box::new(fs::File::open(
+                self.dir.join(backup.dir_name()).join(name),
+            )?))
+        }
+
+        fn has_file(&self, id: u64, name: &str) -> bool {
+            self.checked.borrow_mut().insert(id);
+            self.read_file(id, name).is_ok()
+        }
+    }
+
+    #[test]
+    fn clone_remote_source() {
+        let base = std::env::temp_dir().join(format!("bdup-remote-clone-{}", std::process::id()));
+        let source_dir = base.join("source");
+        create_source_backup(
+            &source_dir,
+            "0000001 2021-04-11 00:00:00",
+            &[("t/a", b"a"), ("t/b", b"b"), ("t/c", b"c")],
+        );
+        let unfinished =
+            create_source_backup(&source_dir, "0000002 2021-04-12 00:00:00", &[("t/a", b"a")]);
+        fs::write(unfinished.path().join(".bdup.partial"), b"").unwrap();
+        // the backups are not below the URL on the local filesystem
+        let mut source = SpoolClient::new(&source_dir);
+        source.find_backups("spool://client").unwrap();
+        // remote files are transferred synchronously, which must not block on a full queue
+        let options = CloneOptions {
+            storage: Arc::new(crate::storage::ReflinkBackend),
+            max_queued_transfers: 1,
+            ..Default::default()
+        };
+
+        let dest = base.join("dest");
+        let summary = source
+            .clone_backups_to(&dest, &ThreadPool::new(1), &options)
+            .unwrap();
+        assert_eq!((summary.cloned, summary.skipped, summary.failed), (1, 1, 0));
+        assert!(!dest.join("0000002 2021-04-12 00:00:00").exists());
+        let mut cloned = Backup::from_path(&dest.join("0000001 2021-04-11 00:00:00")).unwrap();
+        assert!(cloned.is_finished());
+        assert_eq!(cloned.verify(1).unwrap(), 0);
+        options.storage.delete(&base).unwrap();
+    }
+
+    #[test]
+    fn clone_uncompressed_manifest() {
+        let base = std::env::temp_dir().join(format!("bdup-plain-manifest-{}", std::process::id()));
+        let source_dir = base.join("source");
+        let name = "0000001 2021-04-11 00:00:00";
+        let backup = create_source_backup(&source_dir, name, &[("t/a", b"a"), ("t/b", b"b")]);
+        let mut manifest = Vec::new();
+        flate2::read::GzDecoder::new(fs::File::open(backup.path().join("manifest.gz")).unwrap())
+            .read_to_end(&mut manifest)
+            .unwrap();
+        fs::write(backup.path().join("manifest"), manifest).unwrap();
+        fs::remove_file(backup.path().join("manifest.gz")).unwrap();
+        assert!(backup.is_finished());
+        let mut source = SpoolClient::new(&source_dir);
+        source.find_backups("spool://client").unwrap();
+        assert!(source.is_backup_finished(&source.backups[&1]));
+        assert_eq!(source.verify_backup(1, 1).unwrap().ok, 2);
+
+        let options = CloneOptions {
+            storage: Arc::new(crate::storage::ReflinkBackend),
+            ..Default::default()
+        };
+        let dest = base.join("dest");
+        let summary = source
+            .clone_backups_to(&dest, &ThreadPool::new(1), &options)
+            .unwrap();
+        assert_eq!((summary.cloned, summary.failed), (1, 0));
+        let mut cloned = Backup::from_path(&dest.join(name)).unwrap();
+        assert!(cloned.is_finished());
+        assert!(cloned.path().join("manifest").exists());
+        assert_eq!(cloned.verify(1).unwrap(), 0);
+        options.storage.delete(&base).unwrap();
+    }
+
+    #[test]
+    fn finished_checked_after_filters() {
+        let base = std::env::temp_dir().join(format!("bdup-check-wanted-{}", std::process::id()));
+        let source_dir = base.join("source");
+        for name in [
+            "0000001 2021-04-11 00:00:00",
+            "0000002 2021-04-12 00:00:00",
+            "0000003 2021-04-13 00:00:00",
+        ] {
+            create_source_backup(&source_dir, name, &[("t/a", b"a")]);
+        }
+        let mut source = SpoolClient::new(&source_dir);
+        source.find_backups("spool://client").unwrap();
+        let options = CloneOptions {
+            storage: Arc::new(crate::storage::ReflinkBackend),
+            min_id: Some(2),
+            ..Default::default()
+        };
+        let dest = base.join("dest");
+        source
+            .clone_backups_to(&dest, &ThreadPool::new(1), &options)
+            .unwrap();
+        assert_eq!(*source.checked.borrow(), HashSet::from([2, 3]));
+
+        // filtered and already cloned backups are not checked again
+        source.checked.borrow_mut().clear();
+        create_source_backup(&source_dir, "0000004 2021-04-14 00:00:00", &[("t/a", b"a")]);
+        source.find_backups("spool://client").unwrap();
+        let summary = source
+            .clone_backups_to(&dest, &ThreadPool::new(1), &options)
+            .unwrap();
+        assert_eq!((summary.cloned, summary.skipped), (1, 3));
+        assert_eq!(*source.checked.borrow(), HashSet::from([4]));
+        options.storage.delete(&base).unwrap();
+    }
+
+    #[test]
+    fn clone_remote_source_many_files() {
+        let base = std::env::temp_dir().join(format!("bdup-remote-many-{}", std::process::id()));
+        let source_dir = base.join("source");
+        let options = CloneOptions {
+            storage: Arc::new(crate::storage::ReflinkBackend),
+            ..Default::default()
+        };
+        // more files than transfers may be queued, so results are received while reading
+        let names = (0..options.max_queued_transfers + 36)
+            .map(|num| format!("t/{}", num))
+            .collect::<Vec<String>>();
+        let files = names
+            .iter()
+            .map(|name| (name.as_str(), name.as_bytes()))
+            .collect::<Vec<(&str, &[u8])>>();
+        create_source_backup(&source_dir, "0000001 2021-04-11 00:00:00", &files);
+        let mut source = SpoolClient::new(&source_dir);
+        source.find_backups("spool://client").unwrap();
+
+        let dest = base.join("dest");
+        let summary = source
+            .clone_backups_to(&dest, &ThreadPool::new(1), &options)
+            .unwrap();
+        assert_eq!((summary.cloned, summary.failed), (1, 0));
+        let mut cloned = Backup::from_path(&dest.join("0000001 2021-04-11 00:00:00")).unwrap();
+        assert_eq!(cloned.verify(1).unwrap(), 0);
+        options.storage.delete(&base).unwrap();
+    }
+
+    #[test]
+    fn verify_backup_of_any_client() {
+        let base = std::env::temp_dir().join(format!("bdup-client-verify-{}", std::process::id()));
+        let backup = crate::backup::test::create_test_backup_at(
+            &base,
+            "0000001 2021-04-11 00:00:00",
+            &[("t/a", b"a"), ("t/b", b"b")],
+        );
+        crate::backup::test::write_gz(&backup.path().join("data/t/b"), b"x");
+        let mut local = LocalClient::new("local");
+        local.find_backups(&base.to_string_lossy()).unwrap();
+        let mut spool = SpoolClient::new(&base);
+        spool.find_backups(&base.to_string_lossy()).unwrap();
+
+        let clients: [&dyn Client; 2] = [&local, &spool];
+        for client in clients {
+            let report = client.verify_backup(1, 2).unwrap();
+            assert_eq!(report.ok, 1);
+            assert_eq!(
+                report.failed_paths(),
+                vec![backup.path().join("data/t/b").as_path()]
+            );
+            assert!(client.verify_backup(2, 2).is_err());
+        }
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn verify_on_copy() {
+        let base = std::env::temp_dir().join(format!("bdup-verify-copy-{}", std::process::id()));
+        let source_dir = base.join("source");
+        let backup = create_source_backup(
+            &source_dir,
+            "0000001 2021-04-11 00:00:00",
+            &[("t/a", b"a"), ("t/b", b"b")],
+        );
+        // same size, different content
+        crate::backup::test::write_gz(&backup.path().join("data/t/b"), b"x");
+        let mut source = LocalClient::new("client");
+        source.find_backups(&source_dir.to_string_lossy()).unwrap();
+
+        for (verify_on_copy, finished) in [(false, true), (true, false)] {
+            let options = CloneOptions {
+                storage: Arc::new(crate::storage::ReflinkBackend),
+                verify_on_copy,
+                transfer_retries: 0,
+                ..Default::default()
+            };
+            let dest = base.join(format!("dest-{}", verify_on_copy));
+            let summary = source
+                .clone_backups_to(&dest, &ThreadPool::new(1), &options)
+                .unwrap();
+            let cloned = Backup::from_path(&dest.join("0000001 2021-04-11 00:00:00")).unwrap();
+            assert_eq!(cloned.is_finished(), finished);
+            assert_eq!(summary.failed, u64::from(!finished));
+        }
+        crate::storage::ReflinkBackend.delete(&base).unwrap();
+    }
+
+    #[test]
+    fn id_range_empty_result() {
+        let options = CloneOptions {
+            include_ids: vec!["100-200".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!((1..=10).filter(|id| options.wants_backup(*id)).count(), 0);
+
+        let options = CloneOptions {
+            exclude_ids: vec!["1-10".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!((1..=10).filter(|id| options.wants_backup(*id)).count(), 0);
+    }
 }
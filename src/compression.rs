@@ -0,0 +1,419 @@
+//! Codecs for reading and writing compressed data.
+//!
+//! Data files and manifests are read with the codec detected from their magic bytes. burp
+//! stores them gzip compressed, newer builds can use zstd instead. zstd needs the "zstd" feature.
+//!
+//! The codec for writing is selected with a spec like `gzip:9`, `zstd:19`, `gzip` or `none`.
+//! Higher levels produce smaller output at the cost of CPU time for compressing, decompressing
+//! is about as fast for all levels:
+//!
+//! - gzip levels range from 0 to 9. Level 1 is several times faster than level 9 but typically
+//!   yields 10-20% larger files, level 6 (the default) is a good compromise.
+//! - zstd levels range from 1 to 22. The default level 3 compresses better than gzip:6 and is
+//!   faster, levels above 19 need much more memory and are only worth it for archiving.
+//!   zstd also decompresses several times faster than gzip.
+//! - `none` writes data as is, which is fastest but needs the most space.
+use flate2::bufread::GzDecoder;
+use flate2::write::GzEncoder;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::str::FromStr;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression of stored data, as detected from its first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects the compression from the first bytes of the data. Data without known magic bytes
+    /// is assumed to be uncompressed.
+    pub fn detect(header: &[u8]) -> Self {
+        if header.starts_with(&GZIP_MAGIC) {
+            Compression::Gzip
+        } else if header.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Wraps `input` in a decoder for the compression detected from its first bytes
+    pub fn decoder<R: BufRead>(mut input: R) -> io::Result<Decoder<R>> {
+        let compression = Self::detect(input.fill_buf()?);
+        Ok(match compression {
+            Compression::None => Decoder::Plain(input),
+            Compression::Gzip => Decoder::Gzip(GzDecoder::new(input)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Decoder::Zstd(zstd::stream::read::Decoder::with_buffer(input)?),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Unable to read zstd data, because bdup is compiled without \"zstd\" feature",
+                ))
+            }
+        })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+pub enum Decoder<R: BufRead> {
+    Plain(R),
+    Gzip(GzDecoder<R>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, R>),
+}
+
+impl<R: BufRead> Decoder<R> {
+    pub fn compression(&self) -> Compression {
+        match self {
+            Decoder::Plain(_) => Compression::None,
+            Decoder::Gzip(_) => Compression::Gzip,
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(_) => Compression::Zstd,
+        }
+    }
+
+    /// The compressed input
+    pub fn get_ref(&self) -> &R {
+        match self {
+            Decoder::Plain(input) => input,
+            Decoder::Gzip(decoder) => decoder.get_ref(),
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(decoder) => decoder.get_ref(),
+        }
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Plain(input) => input.read(buf),
+            Decoder::Gzip(decoder) => decoder.read(buf),
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Codec and compression level used when writing compressed output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionSpec {
+    pub codec: Codec,
+    pub level: u32,
+}
+
+impl Default for CompressionSpec {
+    fn default() -> Self {
+        Self {
+            codec: Codec::Gzip,
+            level: 6,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidCompressionSpec {
+    message: String,
+}
+
+impl fmt::Display for InvalidCompressionSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl Error for InvalidCompressionSpec {}
+
+impl FromStr for CompressionSpec {
+    type Err = InvalidCompressionSpec;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = |message: String| InvalidCompressionSpec { message };
+        let (name, level) = match input.split_once(':') {
+            Some((name, level)) => (
+                name,
+                Some(level.parse::<u32>().map_err(|err| {
+                    invalid(format!("Invalid compression level {:?}: {}", level, err))
+                })?),
+            ),
+            None => (input, None),
+        };
+
+        match name {
+            "none" => match level {
+                None => Ok(Self::NONE),
+                Some(_) => Err(invalid("Codec \"none\" does not take a level".to_string())),
+            },
+            "gzip" => match level {
+                Some(level) if level > 9 => Err(invalid(format!(
+                    "Invalid gzip level {}, must be between 0 and 9",
+                    level
+                ))),
+                _ => Ok(Self {
+                    codec: Codec::Gzip,
+                    level: level.unwrap_or(6),
+                }),
+            },
+            #[cfg(feature = "zstd")]
+            "zstd" => match level {
+                Some(level) if !(1..=22).contains(&level) => Err(invalid(format!(
+                    "Invalid zstd level {}, must be between 1 and 22",
+                    level
+                ))),
+                _ => Ok(Self {
+                    codec: Codec::Zstd,
+                    level: level.unwrap_or(3),
+                }),
+            },
+            #[cfg(not(feature = "zstd"))]
+            "zstd" => Err(invalid(
+                "Codec zstd is not supported, because bdup is compiled without \"zstd\" feature"
+                    .to_string(),
+            )),
+            _ => Err(invalid(format!(
+                "Unknown codec {:?}, expected one of: gzip, zstd, none",
+                name
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for CompressionSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.codec {
+            Codec::None => write!(f, "none"),
+            Codec::Gzip => write!(f, "gzip:{}", self.level),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => write!(f, "zstd:{}", self.level),
+        }
+    }
+}
+
+impl CompressionSpec {
+    /// Data written as is
+    pub const NONE: Self = Self {
+        codec: Codec::None,
+        level: 0,
+    };
+
+    /// Wraps `inner` in an encoder for this codec. Call `Encoder::finish` after writing all data.
+    pub fn encoder<W: Write>(&self, inner: W) -> io::Result<Encoder<W>> {
+        Ok(match self.codec {
+            Codec::None => Encoder::Plain(inner),
+            Codec::Gzip => {
+                Encoder::Gzip(GzEncoder::new(inner, flate2::Compression::new(self.level)))
+            }
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => {
+                Encoder::Zstd(zstd::stream::write::Encoder::new(inner, self.level as i32)?)
+            }
+        })
+    }
+
+    /// File name extension of the codec, e.g. "gz", `None` for uncompressed data
+    pub fn extension(&self) -> Option<&'static str> {
+        match self.codec {
+            Codec::None => None,
+            Codec::Gzip => Some("gz"),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Some("zst"),
+        }
+    }
+}
+
+pub enum Encoder<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Writes any pending data and trailers and returns the underlying writer
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Plain(mut inner) => {
+                inner.flush()?;
+                Ok(inner)
+            }
+            Encoder::Gzip(encoder) => encoder.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(inner) => inner.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(inner) => inner.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_spec() {
+        assert_eq!(
+            "gzip:9".parse::<CompressionSpec>().unwrap(),
+            CompressionSpec {
+                codec: Codec::Gzip,
+                level: 9
+            }
+        );
+        assert_eq!(
+            "gzip".parse::<CompressionSpec>().unwrap(),
+            CompressionSpec::default()
+        );
+        assert_eq!(
+            "none".parse::<CompressionSpec>().unwrap().codec,
+            Codec::None
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn parse_zstd_spec() {
+        assert_eq!(
+            "zstd:19".parse::<CompressionSpec>().unwrap(),
+            CompressionSpec {
+                codec: Codec::Zstd,
+                level: 19
+            }
+        );
+        assert_eq!("zstd".parse::<CompressionSpec>().unwrap().level, 3);
+        assert!("zstd:0".parse::<CompressionSpec>().is_err());
+        assert!("zstd:23".parse::<CompressionSpec>().is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "zstd"))]
+    fn zstd_needs_feature() {
+        let error = "zstd:19".parse::<CompressionSpec>().unwrap_err();
+        assert!(error.to_string().contains("\"zstd\" feature"));
+        let error = Compression::decoder(&ZSTD_MAGIC[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn parse_invalid_spec() {
+        assert!("gzip:10".parse::<CompressionSpec>().is_err());
+        assert!("gzip:x".parse::<CompressionSpec>().is_err());
+        assert!("none:1".parse::<CompressionSpec>().is_err());
+        assert!("lzma".parse::<CompressionSpec>().is_err());
+    }
+
+    #[test]
+    fn detect_compression() {
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 8]), Compression::Gzip);
+        assert_eq!(
+            Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0]),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::detect(b"t0003"), Compression::None);
+        assert_eq!(Compression::detect(&[]), Compression::None);
+    }
+
+    #[test]
+    fn decode_gzip() {
+        let mut encoder = CompressionSpec::default().encoder(Vec::new()).unwrap();
+        encoder.write_all(b"some data").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut decoder = Compression::decoder(&compressed[..]).unwrap();
+        assert_eq!(decoder.compression(), Compression::Gzip);
+        let mut decompressed = String::new();
+        decoder.read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, "some data");
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn decode_zstd() {
+        let compressed = zstd::encode_all(&b"zstd data"[..], 0).unwrap();
+
+        let mut decoder = Compression::decoder(&compressed[..]).unwrap();
+        assert_eq!(decoder.compression(), Compression::Zstd);
+        let mut decompressed = String::new();
+        decoder.read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, "zstd data");
+
+        let mut corrupt = compressed.clone();
+        corrupt.truncate(compressed.len() - 2);
+        let mut decoder = Compression::decoder(&corrupt[..]).unwrap();
+        assert!(decoder.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let mut encoder = "gzip:1"
+            .parse::<CompressionSpec>()
+            .unwrap()
+            .encoder(Vec::new())
+            .unwrap();
+        encoder.write_all(b"some data").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, "some data");
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn zstd_round_trip() {
+        let spec = "zstd:19".parse::<CompressionSpec>().unwrap();
+        assert_eq!(spec.extension(), Some("zst"));
+        let mut encoder = spec.encoder(Vec::new()).unwrap();
+        encoder.write_all(b"some data").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut decoder = Compression::decoder(&compressed[..]).unwrap();
+        assert_eq!(decoder.compression(), Compression::Zstd);
+        let mut decompressed = String::new();
+        decoder.read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, "some data");
+    }
+}
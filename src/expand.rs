@@ -0,0 +1,123 @@
+//! Expansion of environment variables and `~` in configured paths.
+//!
+//! `$VAR` and `${VAR}` are replaced by the value of environment variable VAR, `$$` by a single
+//! `$`. A leading `~` followed by `/` or the end of the string is replaced by `$HOME`. Unset
+//! variables are an error, so a typo never silently turns into a different path.
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub struct ExpandError {
+    message: String,
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl Error for ExpandError {}
+
+/// Expands `input` with the variables of the process environment
+pub fn expand(input: &str) -> Result<String, ExpandError> {
+    expand_with(input, |name| std::env::var(name).ok())
+}
+
+/// Expands `input` with the variables returned by `lookup`
+pub fn expand_with(
+    input: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, ExpandError> {
+    let var = |name: &str| {
+        lookup(name).ok_or_else(|| ExpandError {
+            message: format!("Environment variable {} in {:?} is not set", name, input),
+        })
+    };
+
+    let mut output = String::new();
+    let mut rest = input;
+    if let Some(after) = rest.strip_prefix('~') {
+        if after.is_empty() || after.starts_with('/') {
+            output += &var("HOME")?;
+            rest = after;
+        }
+    }
+    while let Some(index) = rest.find('$') {
+        output += &rest[..index];
+        rest = &rest[index + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            output.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after.find('}').ok_or_else(|| ExpandError {
+                message: format!("Missing closing brace in {:?}", input),
+            })?;
+            output += &var(&after[..end])?;
+            rest = &after[end + 1..];
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return Err(ExpandError {
+                    message: format!("Missing variable name after '$' in {:?}", input),
+                });
+            }
+            output += &var(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+    output += rest;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/burp".to_string()),
+            "ROOT" => Some("/srv/backup".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expand_variables() {
+        assert_eq!(expand_with("$ROOT/dup", lookup).unwrap(), "/srv/backup/dup");
+        assert_eq!(expand_with("${ROOT}dup", lookup).unwrap(), "/srv/backupdup");
+        assert_eq!(
+            expand_with("/a/$ROOT-x", lookup).unwrap(),
+            "/a//srv/backup-x"
+        );
+        assert_eq!(expand_with("cost$$", lookup).unwrap(), "cost$");
+        assert_eq!(expand_with("/plain/path", lookup).unwrap(), "/plain/path");
+    }
+
+    #[test]
+    fn expand_home() {
+        assert_eq!(expand_with("~", lookup).unwrap(), "/home/burp");
+        assert_eq!(expand_with("~/dup", lookup).unwrap(), "/home/burp/dup");
+        assert_eq!(expand_with("~other/dup", lookup).unwrap(), "~other/dup");
+        assert_eq!(expand_with("/a/~/b", lookup).unwrap(), "/a/~/b");
+    }
+
+    #[test]
+    fn expand_errors() {
+        assert!(expand_with("$UNSET/dup", lookup).is_err());
+        assert!(expand_with("${UNSET}", lookup).is_err());
+        assert!(expand_with("${ROOT", lookup).is_err());
+        assert!(expand_with("a$/b", lookup).is_err());
+        assert!(expand_with("~/dup", |_| None).is_err());
+    }
+
+    #[test]
+    fn expand_from_environment() {
+        // reads the environment only, setting variables is unsound while other tests run
+        assert_eq!(
+            expand("$PATH/dup").ok(),
+            std::env::var("PATH").ok().map(|path| path + "/dup")
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! Helpers for the hand-written JSON output of the binaries.
+
+/// Quotes `value` as JSON string
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if u32::from(c) < 0x20 => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// JSON array of the given, already encoded values
+pub fn array<I: IntoIterator<Item = String>>(values: I) -> String {
+    format!(
+        "[{}]",
+        values.into_iter().collect::<Vec<String>>().join(",")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quote_special_characters() {
+        assert_eq!(quote("plain"), "\"plain\"");
+        assert_eq!(quote("a \"b\"\\c"), "\"a \\\"b\\\"\\\\c\"");
+        assert_eq!(quote("line\nbreak\t\u{1}"), "\"line\\nbreak\\t\\u0001\"");
+        assert_eq!(quote("äöü"), "\"äöü\"");
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!(array(Vec::new()), "[]");
+        assert_eq!(array(vec![quote("a"), "1".to_string()]), "[\"a\",1]");
+    }
+}
@@ -1,6 +1,25 @@
 pub mod backup;
+pub mod checksum;
 pub mod client;
+pub mod compression;
+pub mod expand;
+pub mod json;
+pub mod logfilter;
 pub mod manifest;
+pub mod ratelimit;
+pub mod storage;
+
+#[cfg(feature = "cli")]
+pub mod logging;
 
 #[cfg(feature = "http")]
 pub mod remoteclient;
+
+#[cfg(feature = "ssh")]
+pub mod sftpclient;
+
+#[cfg(feature = "s3")]
+pub mod s3client;
+
+#[cfg(feature = "systemd")]
+pub mod notify;
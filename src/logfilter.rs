@@ -0,0 +1,134 @@
+//! Per-target log levels for the binaries, written like `RUST_LOG` of env_logger:
+//! `burp::manifest=trace,burp::client=debug`.
+//!
+//! A target applies to its submodules as well. The level of all other targets is the global
+//! log level.
+use log::LevelFilter;
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub struct InvalidLogFilter {
+    message: String,
+}
+
+impl fmt::Display for InvalidLogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl Error for InvalidLogFilter {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct LogFilter {
+    /// Targets with their levels, in the given order
+    pub targets: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// The same targets, with their levels lowered to at most `max`
+    pub fn capped(&self, max: LevelFilter) -> Self {
+        Self {
+            targets: self
+                .targets
+                .iter()
+                .map(|(target, level)| (target.to_owned(), (*level).min(max)))
+                .collect(),
+        }
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = InvalidLogFilter;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| InvalidLogFilter {
+            message: format!("Invalid log filter {:?}: {}", input, reason),
+        };
+        let mut targets = Vec::new();
+        for directive in input.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+            let (target, level) = directive
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected target=level, got {:?}", directive)))?;
+            let level = level
+                .trim()
+                .parse()
+                .map_err(|_| invalid(format!("unknown level {:?}", level)))?;
+            targets.push((target.trim().to_string(), level));
+        }
+        Ok(Self { targets })
+    }
+}
+
+impl TryFrom<String> for LogFilter {
+    type Error = InvalidLogFilter;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let directives = self
+            .targets
+            .iter()
+            .map(|(target, level)| format!("{}={}", target, level.as_str().to_lowercase()))
+            .collect::<Vec<String>>();
+        write!(f, "{}", directives.join(","))
+    }
+}
+
+impl From<LogFilter> for String {
+    fn from(filter: LogFilter) -> Self {
+        filter.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_filter() {
+        let filter = "burp::manifest=trace, burp::client=WARN,"
+            .parse::<LogFilter>()
+            .unwrap();
+        assert_eq!(
+            filter.targets,
+            vec![
+                ("burp::manifest".to_string(), LevelFilter::Trace),
+                ("burp::client".to_string(), LevelFilter::Warn)
+            ]
+        );
+        assert_eq!(filter.to_string(), "burp::manifest=trace,burp::client=warn");
+        assert!("".parse::<LogFilter>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn capped_filter() {
+        let filter = "burp::manifest=trace,burp::client=error"
+            .parse::<LogFilter>()
+            .unwrap();
+        assert_eq!(
+            filter.capped(LevelFilter::Warn).to_string(),
+            "burp::manifest=warn,burp::client=error"
+        );
+    }
+
+    #[test]
+    fn invalid_filter() {
+        assert!("burp::manifest".parse::<LogFilter>().is_err());
+        assert!("burp::manifest=loud".parse::<LogFilter>().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Log output of the binaries: one line per record with timestamp, target and level, where the
+//! level may be colorized.
+use clap::ValueEnum;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+/// When to colorize log levels
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Only if logs are written to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    pub fn enabled(self, is_terminal: bool) -> bool {
+        match self {
+            ColorMode::Auto => is_terminal,
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+/// `level` as shown in log lines, wrapped in ANSI color codes if `color` is set
+pub fn format_level(level: log::Level, color: bool) -> String {
+    if !color {
+        return level.to_string();
+    }
+    let code = match level {
+        log::Level::Error => 31,
+        log::Level::Warn => 33,
+        log::Level::Info => 32,
+        log::Level::Debug => 34,
+        log::Level::Trace => 35,
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, level)
+}
+
+/// Formats a log line with timestamp, target and level
+pub fn format_record(
+    out: fern::FormatCallback,
+    message: &std::fmt::Arguments,
+    record: &log::Record,
+    color: bool,
+) {
+    let tstamp = match OffsetDateTime::now_local() {
+        Ok(time) => time.format(format_description!(
+            "[year]-[month]-[day] [hour]:[minute]:[second]"
+        )),
+        _ => OffsetDateTime::now_utc().format(format_description!(
+            "[year]-[month]-[day] [hour]:[minute]:[second] UTC"
+        )),
+    }
+    .unwrap();
+    out.finish(format_args!(
+        "{} [{}][{}] {}",
+        tstamp,
+        record.target(),
+        format_level(record.level(), color),
+        message
+    ))
+}
+
+/// Log level forced by `--quiet` or `--verbose`, which is given `verbose` times
+pub fn verbosity_level(quiet: bool, verbose: u8) -> Option<log::LevelFilter> {
+    match (quiet, verbose) {
+        (true, _) => Some(log::LevelFilter::Warn),
+        (false, 0) => None,
+        (false, 1) => Some(log::LevelFilter::Debug),
+        (false, _) => Some(log::LevelFilter::Trace),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn color_mode() {
+        assert!(ColorMode::Auto.enabled(true));
+        assert!(!ColorMode::Auto.enabled(false));
+        assert!(ColorMode::Always.enabled(false));
+        assert!(!ColorMode::Never.enabled(true));
+    }
+
+    #[test]
+    fn colored_level() {
+        assert_eq!(format_level(log::Level::Warn, false), "WARN");
+        assert_eq!(format_level(log::Level::Warn, true), "\x1b[33mWARN\x1b[0m");
+    }
+
+    #[test]
+    fn verbosity() {
+        assert_eq!(verbosity_level(false, 0), None);
+        assert_eq!(verbosity_level(false, 1), Some(log::LevelFilter::Debug));
+        assert_eq!(verbosity_level(false, 3), Some(log::LevelFilter::Trace));
+        assert_eq!(verbosity_level(true, 0), Some(log::LevelFilter::Warn));
+    }
+}
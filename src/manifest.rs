@@ -1,11 +1,14 @@
 use derive_more::{Display, Error};
 use std::convert::TryInto;
 use std::error::Error;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::io;
 use std::io::BufRead;
+use std::ops::ControlFlow;
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Display, Error)]
 #[display(fmt = "Manifest read error: {}", details)]
@@ -26,7 +29,7 @@ impl ManifestReadError {
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum FileType {
     Unknown,
     Plain,
@@ -51,19 +54,21 @@ pub struct Stat {
     pub mod_time: i64,
     pub change_time: i64,
     pub ch_flags: u64,
+    /// Windows file attributes (read-only, hidden, system, ...), 0 for other clients
+    pub win_attr: u64,
     pub compression: i32,
 }
 
 #[derive(Display, Debug, Error)]
 #[display(fmt = "Invalid char in base64 string: {}", c)]
-struct InvalidBase64Char {
+pub struct InvalidBase64Char {
     c: char,
 }
 
 /// burp's (or bacula's?) own version of base64 encoding integer types. An encoded value consists
 /// of an optional leading '-' for negative values followed by one or more characters from the
 /// alphabet. Each character is worth 6 bits, there is no trailing padding.
-fn burp_decode_base64(value: &str) -> Result<i64, InvalidBase64Char> {
+pub fn burp_decode_base64(value: &str) -> Result<i64, InvalidBase64Char> {
     let mut result: i64 = 0;
     let mut negative = false;
 
@@ -79,7 +84,7 @@ fn burp_decode_base64(value: &str) -> Result<i64, InvalidBase64Char> {
         match c {
             'A'..='Z' => result += (c as u8 - b'A') as i64,
             'a'..='z' => result += (c as u8 - b'a') as i64 + 26,
-            '0'..='9' => result += (c as u8 - b'0') as i64 + 32,
+            '0'..='9' => result += (c as u8 - b'0') as i64 + 52,
             '+' => result += 62,
             '/' => result += 63,
             _ => return Err(InvalidBase64Char { c }),
@@ -92,6 +97,24 @@ fn burp_decode_base64(value: &str) -> Result<i64, InvalidBase64Char> {
     Ok(result)
 }
 
+/// Inverse of `burp_decode_base64`
+pub fn burp_encode_base64(value: i64) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut remaining = value.unsigned_abs();
+    let mut digits = Vec::new();
+    loop {
+        digits.push(ALPHABET[(remaining & 0x3f) as usize]);
+        remaining >>= 6;
+        if remaining == 0 {
+            break;
+        }
+    }
+    if value < 0 {
+        digits.push(b'-');
+    }
+    digits.iter().rev().map(|c| *c as char).collect()
+}
+
 impl Stat {
     fn from_burp_string(line: &[u8]) -> Result<Self, Box<dyn Error>> {
         let source = str::from_utf8(line)?;
@@ -115,16 +138,104 @@ impl Stat {
             mod_time: burp_decode_base64(stat[11])?,
             change_time: burp_decode_base64(stat[12])?,
             ch_flags: burp_decode_base64(stat[13])?.try_into()?,
-            // stat[14] is namen "win_attr" in burp's source code. Never saw this one in real life
+            win_attr: burp_decode_base64(stat[14])?.try_into()?,
             compression: burp_decode_base64(stat[15])?.try_into()?,
         })
     }
+
+    /// Major number of the device a special file refers to, decoded like glibc's `major()`
+    pub fn device_major(&self) -> u32 {
+        (((self.device_id >> 8) & 0xfff) | ((self.device_id >> 32) & !0xfff)) as u32
+    }
+
+    /// Minor number of the device a special file refers to, decoded like glibc's `minor()`
+    pub fn device_minor(&self) -> u32 {
+        ((self.device_id & 0xff) | ((self.device_id >> 12) & !0xff)) as u32
+    }
+
+    /// Time of the last access, `None` if unknown (0)
+    pub fn accessed(&self) -> Option<SystemTime> {
+        epoch_time(self.access_time)
+    }
+
+    /// Time of the last modification, `None` if unknown (0)
+    pub fn modified(&self) -> Option<SystemTime> {
+        epoch_time(self.mod_time)
+    }
+
+    /// Time of the last status change, `None` if unknown (0)
+    pub fn changed(&self) -> Option<SystemTime> {
+        epoch_time(self.change_time)
+    }
+
+    pub fn is_setuid(&self) -> bool {
+        self.mode & 0o4000 != 0
+    }
+
+    pub fn is_setgid(&self) -> bool {
+        self.mode & 0o2000 != 0
+    }
+
+    pub fn is_sticky(&self) -> bool {
+        self.mode & 0o1000 != 0
+    }
+
+    /// File type character of `ls -l`, e.g. 'd' for directories and '-' for regular files
+    pub fn file_type_char(&self) -> char {
+        match self.mode & 0o170000 {
+            0o140000 => 's',
+            0o120000 => 'l',
+            0o100000 => '-',
+            0o060000 => 'b',
+            0o040000 => 'd',
+            0o020000 => 'c',
+            0o010000 => 'p',
+            _ => '?',
+        }
+    }
+
+    /// Mode like `ls -l` shows it, e.g. "drwxr-xr-x". Setuid, setgid and sticky bits replace
+    /// the execute bits with 's' and 't', or 'S' and 'T' if the execute bit is not set.
+    pub fn permission_string(&self) -> String {
+        let bit = |mask: u32, c: char| if self.mode & mask != 0 { c } else { '-' };
+        let special = |exec_mask: u32, set: bool, c: char| match (self.mode & exec_mask != 0, set) {
+            (true, true) => c,
+            (false, true) => c.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        };
+        [
+            self.file_type_char(),
+            bit(0o400, 'r'),
+            bit(0o200, 'w'),
+            special(0o100, self.is_setuid(), 's'),
+            bit(0o040, 'r'),
+            bit(0o020, 'w'),
+            special(0o010, self.is_setgid(), 's'),
+            bit(0o004, 'r'),
+            bit(0o002, 'w'),
+            special(0o001, self.is_sticky(), 't'),
+        ]
+        .iter()
+        .collect()
+    }
+}
+
+/// Converts seconds since the epoch, which may be negative. 0 means unknown, as do values that
+/// cannot be represented.
+fn epoch_time(secs: i64) -> Option<SystemTime> {
+    let offset = Duration::from_secs(secs.unsigned_abs());
+    match secs {
+        0 => None,
+        1.. => UNIX_EPOCH.checked_add(offset),
+        _ => UNIX_EPOCH.checked_sub(offset),
+    }
 }
 
 #[derive(Default)]
 pub struct ManifestEntryData {
     pub path: PathBuf,
-    pub size: usize,
+    pub size: u64,
     pub md5: String,
 }
 
@@ -133,6 +244,10 @@ pub struct ManifestEntry {
     pub path: PathBuf,
     pub stat: Option<Stat>,
     pub data: Option<ManifestEntryData>,
+    /// Extended attributes as (name, value) pairs
+    pub xattrs: Vec<(OsString, Vec<u8>)>,
+    /// Raw POSIX ACL
+    pub acl: Option<Vec<u8>>,
     link_target: Option<PathBuf>,
 }
 
@@ -143,9 +258,20 @@ impl ManifestEntry {
             path: PathBuf::new(),
             stat: None,
             data: None,
+            xattrs: Vec::new(),
+            acl: None,
             link_target: None,
         }
     }
+
+    pub fn file_type(&self) -> &FileType {
+        &self.file_type
+    }
+
+    /// Target of a soft link, `None` for all other file types
+    pub fn link_target(&self) -> Option<&Path> {
+        self.link_target.as_deref()
+    }
 }
 
 fn add_manifest_line(
@@ -171,6 +297,18 @@ fn add_manifest_line(
                 .get_or_insert_with(ManifestEntryData::default)
                 .path = PathBuf::from(OsStr::from_bytes(data))
         }
+        'X' => {
+            // extended attribute: name and value separated by a null byte
+            let separator = data
+                .iter()
+                .position(|c| *c == 0)
+                .ok_or_else(|| ManifestReadError::new("malformed extended attribute"))?;
+            entry.xattrs.push((
+                OsStr::from_bytes(&data[..separator]).to_owned(),
+                data[separator + 1..].to_vec(),
+            ));
+        }
+        'A' => entry.acl = Some(data.to_vec()),
         'L' => {
             // hard links are only relevant on the client side, so we will just ignore them
         }
@@ -202,7 +340,7 @@ fn add_manifest_line(
                 .size = val
                 .next()
                 .ok_or_else(|| ManifestReadError::new("malformed checksum"))?
-                .parse::<usize>()?;
+                .parse::<u64>()?;
             entry
                 .data
                 .get_or_insert_with(ManifestEntryData::default)
@@ -227,6 +365,23 @@ fn add_manifest_line(
     Ok(finished)
 }
 
+fn read_exact_or_eof<R: BufRead>(
+    reader: &mut R,
+    buf: &mut [u8],
+    what: &str,
+) -> Result<(), Box<dyn Error>> {
+    reader.read_exact(buf).map_err(|err| -> Box<dyn Error> {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            Box::new(ManifestReadError::new(&format!(
+                "Premature end of manifest while reading {}",
+                what
+            )))
+        } else {
+            Box::new(err)
+        }
+    })
+}
+
 struct ManifestLine {
     kind: char,
     data: Vec<u8>,
@@ -234,55 +389,168 @@ struct ManifestLine {
 
 impl ManifestLine {
     fn read<R: BufRead>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
-        let kind = reader.fill_buf()?[0];
-        reader.consume(1);
+        let mut kind: [u8; 1] = [0; 1];
+        read_exact_or_eof(reader, &mut kind, "line type")?;
 
         let mut length_string: [u8; 4] = [0; 4];
-        reader.read_exact(&mut length_string)?;
+        read_exact_or_eof(reader, &mut length_string, "line length")?;
         let data_length = usize::from_str_radix(str::from_utf8(&length_string)?, 16)?;
         let mut data = vec![0_u8; data_length];
-        reader.read_exact(&mut data)?;
+        read_exact_or_eof(reader, &mut data, "line data")?;
 
-        // remove trailing line break
-        reader.fill_buf()?;
-        reader.consume(1);
+        // remove trailing line break. It may be missing on the last line
+        if reader.fill_buf()?.first() == Some(&b'\n') {
+            reader.consume(1);
+        }
         Ok(Self {
-            kind: kind as char,
+            kind: kind[0] as char,
             data,
         })
     }
 }
 
-pub fn read_manifest<R: BufRead, T, F: FnMut(ManifestEntry) -> Result<T, Box<dyn Error>>>(
-    reader: &mut R,
-    callback: &mut F,
-) -> Result<(), Box<dyn Error>> {
-    let mut entry = ManifestEntry::new();
+/// Iterator over the entries of a manifest. Iteration stops after the first error.
+/// ```
+/// use burp::manifest::ManifestReader;
+/// let reader = ManifestReader::new(std::io::Cursor::new("d0004/dir\n"));
+/// assert_eq!(reader.count(), 1);
+/// ```
+pub struct ManifestReader<R: BufRead> {
+    reader: R,
+    lineno: usize,
+    done: bool,
+}
 
-    let mut entryno = 0;
-    loop {
-        entryno += 1;
-        let buffer = reader.fill_buf()?;
-        if buffer.is_empty() {
-            break;
+impl<R: BufRead> ManifestReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            lineno: 0,
+            done: false,
         }
+    }
 
-        let line = ManifestLine::read(reader)?;
-        match add_manifest_line(&mut entry, &line.kind, &line.data) {
-            Ok(false) => (),
-            Ok(true) => {
-                callback(entry)?;
-                entry = ManifestEntry::new();
+    fn next_entry(&mut self) -> Result<Option<ManifestEntry>, Box<dyn Error>> {
+        let mut entry = ManifestEntry::new();
+        loop {
+            self.lineno += 1;
+            let buffer = self.reader.fill_buf()?;
+            if buffer.is_empty() {
+                // stat and hard link lines alone do not start an entry, anything else has to be
+                // finished by a path or checksum line
+                if entry.file_type != FileType::Unknown || entry.data.is_some() {
+                    return Err(Box::new(ManifestReadError::new(&format!(
+                        "{}: Manifest ends with incomplete entry {:?}",
+                        self.lineno, entry.path
+                    ))));
+                }
+                return Ok(None);
             }
-            Err(err) => {
-                log::debug!("Error in line {}: {:?}", entryno, err);
-                return Err(Box::new(ManifestReadError::new(&format!(
-                    "{}: Corrupt line in manifest: {:?}",
-                    entryno, err
-                ))));
+
+            let line = ManifestLine::read(&mut self.reader)?;
+            match add_manifest_line(&mut entry, &line.kind, &line.data) {
+                Ok(false) => (),
+                Ok(true) => return Ok(Some(entry)),
+                Err(err) => {
+                    log::debug!("Error in line {}: {:?}", self.lineno, err);
+                    return Err(Box::new(ManifestReadError::new(&format!(
+                        "{}: Corrupt line in manifest: {:?}",
+                        self.lineno, err
+                    ))));
+                }
             }
         }
     }
+}
+
+impl<R: BufRead> Iterator for ManifestReader<R> {
+    type Item = Result<ManifestEntry, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.next_entry().transpose();
+        // stop after the end of the manifest or the first error
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// Calls `callback` for each entry of the manifest, until it returns `ControlFlow::Break`. See
+/// `ManifestReader` for an iterator based interface.
+pub fn read_manifest<
+    R: BufRead,
+    F: FnMut(ManifestEntry) -> Result<ControlFlow<()>, Box<dyn Error>>,
+>(
+    reader: &mut R,
+    callback: &mut F,
+) -> Result<(), Box<dyn Error>> {
+    for entry in ManifestReader::new(reader) {
+        if callback(entry?)?.is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Quotes `value` as CSV field, if it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Writes one CSV row per manifest entry to `out`: path, type, size, md5, mode (octal), mtime
+/// (seconds since the epoch) and permissions like `ls -l` shows them, preceded by a header row.
+/// Fields that do not apply to an entry are left empty. Entries are written while the manifest is
+/// read.
+pub fn write_csv<R: BufRead, W: io::Write>(
+    reader: &mut R,
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(out, "path,type,size,md5,mode,mtime,permissions")?;
+    read_manifest(reader, &mut |entry: ManifestEntry| {
+        let file_type = match entry.file_type {
+            FileType::Unknown => "unknown",
+            FileType::Plain => "file",
+            FileType::Directory => "directory",
+            FileType::SoftLink => "symlink",
+            FileType::Metadata => "metadata",
+            FileType::Special => "special",
+        };
+        let size = match (&entry.data, &entry.stat) {
+            (Some(data), _) => data.size.to_string(),
+            (None, Some(stat)) => stat.size.to_string(),
+            (None, None) => String::new(),
+        };
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{}",
+            csv_field(&entry.path.to_string_lossy()),
+            file_type,
+            size,
+            entry.data.as_ref().map_or("", |data| data.md5.as_str()),
+            entry
+                .stat
+                .as_ref()
+                .map_or(String::new(), |stat| format!("{:o}", stat.mode)),
+            entry
+                .stat
+                .as_ref()
+                .map_or(String::new(), |stat| stat.mod_time.to_string()),
+            entry
+                .stat
+                .as_ref()
+                .map_or(String::new(), Stat::permission_string),
+        )?;
+        Ok(ControlFlow::Continue(()))
+    })?;
+    out.flush()?;
     Ok(())
 }
 
@@ -290,6 +558,14 @@ pub fn read_manifest<R: BufRead, T, F: FnMut(ManifestEntry) -> Result<T, Box<dyn
 mod tests {
     use super::*;
 
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("/plain/path"), "/plain/path");
+        assert_eq!(csv_field("/a,b"), "\"/a,b\"");
+        assert_eq!(csv_field("/say \"hi\""), "\"/say \"\"hi\"\"\"");
+        assert_eq!(csv_field("/line\nbreak"), "\"/line\nbreak\"");
+    }
+
     #[test]
     fn manifest_simple() {
         let mut buf = std::io::Cursor::new("a0004ASDF\n");
@@ -297,6 +573,61 @@ mod tests {
         assert_eq!(line.data, b"ASDF");
     }
 
+    #[test]
+    fn manifest_line_without_newline() {
+        let mut buf = std::io::Cursor::new("f0004path");
+        let line = ManifestLine::read(&mut buf).unwrap();
+        assert_eq!(line.kind, 'f');
+        assert_eq!(line.data, b"path");
+        assert!(buf.fill_buf().unwrap().is_empty());
+    }
+
+    #[test]
+    fn manifest_line_keeps_next_line() {
+        let mut buf = std::io::Cursor::new("f0004pathd0004/dir\n");
+        assert_eq!(ManifestLine::read(&mut buf).unwrap().data, b"path");
+        assert_eq!(ManifestLine::read(&mut buf).unwrap().data, b"/dir");
+    }
+
+    /// Reader that returns at most one byte per call
+    struct ByteReader<'a> {
+        data: &'a [u8],
+    }
+
+    impl std::io::Read for ByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.data.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.data[0];
+            self.data = &self.data[1..];
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn manifest_line_byte_reader() {
+        let mut reader = std::io::BufReader::with_capacity(
+            1,
+            ByteReader {
+                data: b"f0004path\nd0004/dir\n",
+            },
+        );
+        assert_eq!(ManifestLine::read(&mut reader).unwrap().data, b"path");
+        let line = ManifestLine::read(&mut reader).unwrap();
+        assert_eq!(line.kind, 'd');
+        assert_eq!(line.data, b"/dir");
+    }
+
+    #[test]
+    fn manifest_line_premature_eof() {
+        for input in ["", "f00", "f0004pa"] {
+            let mut buf = std::io::Cursor::new(input);
+            let err = ManifestLine::read(&mut buf).err().unwrap();
+            assert!(err.is::<ManifestReadError>());
+        }
+    }
+
     #[test]
     fn manifest_short_line() {
         let mut buf = std::io::Cursor::new("t0004a\n"); // length 4 != "a".length()
@@ -323,6 +654,30 @@ mod tests {
         assert_eq!(burp_decode_base64("Po").unwrap(), 1000);
         assert_eq!(burp_decode_base64("-/").unwrap(), -63);
         assert_eq!(burp_decode_base64("-B").unwrap(), -1);
+        assert_eq!(burp_decode_base64("0").unwrap(), 52);
+        assert_eq!(burp_decode_base64("9").unwrap(), 61);
+    }
+
+    #[test]
+    fn encode_base64() {
+        assert_eq!(burp_encode_base64(1000), "Po");
+        assert_eq!(burp_encode_base64(-63), "-/");
+        assert_eq!(burp_encode_base64(-1), "-B");
+        assert_eq!(burp_encode_base64(0), "A");
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        let values = (-5000..5000)
+            .chain((0..63).map(|shift| 1_i64 << shift))
+            .chain((0..63).map(|shift| -(1_i64 << shift)))
+            .chain([i64::MAX, i64::MIN + 1]);
+        for value in values {
+            assert_eq!(
+                burp_decode_base64(&burp_encode_base64(value)).unwrap(),
+                value
+            );
+        }
     }
 
     #[test]
@@ -354,6 +709,7 @@ mod tests {
         assert_eq!(stat.mod_time, 11);
         assert_eq!(stat.change_time, 12);
         assert_eq!(stat.ch_flags, 13);
+        assert_eq!(stat.win_attr, 14);
         assert_eq!(stat.compression, 15);
     }
 
@@ -416,6 +772,26 @@ mod tests {
         assert!(finished);
     }
 
+    #[test]
+    fn manifest_entry_xattr() {
+        let mut entry = ManifestEntry::new();
+        let finished = add_manifest_line(&mut entry, &'X', b"security.selinux\0label").unwrap();
+        assert!(!finished);
+        assert_eq!(
+            entry.xattrs,
+            vec![(OsString::from("security.selinux"), b"label".to_vec())]
+        );
+        assert!(add_manifest_line(&mut entry, &'X', b"no separator").is_err());
+    }
+
+    #[test]
+    fn manifest_entry_acl() {
+        let mut entry = ManifestEntry::new();
+        let finished = add_manifest_line(&mut entry, &'A', b"user::rw-").unwrap();
+        assert!(!finished);
+        assert_eq!(entry.acl, Some(b"user::rw-".to_vec()));
+    }
+
     #[test]
     fn manifest_malformed_checksum() {
         let mut entry = ManifestEntry::new();
@@ -431,6 +807,91 @@ mod tests {
         assert_eq!(data.md5, "asdfgh");
     }
 
+    #[test]
+    fn manifest_large_file() {
+        let mut entry = ManifestEntry::new();
+        let line = format!("{}:asdfgh", u64::from(u32::MAX) + 1);
+        assert!(add_manifest_line(&mut entry, &'x', line.as_bytes()).unwrap());
+        assert_eq!(entry.data.unwrap().size, 4_294_967_296);
+    }
+
+    #[test]
+    fn device_numbers() {
+        let stat_with_device = |device_id: u64| {
+            let mut values = vec!["A"; 16];
+            let encoded = burp_encode_base64(device_id as i64);
+            values[6] = &encoded;
+            Stat::from_burp_string(values.join(" ").as_bytes()).unwrap()
+        };
+        // /dev/sda1
+        let stat = stat_with_device(0x801);
+        assert_eq!((stat.device_major(), stat.device_minor()), (8, 1));
+        // /dev/null
+        let stat = stat_with_device(0x103);
+        assert_eq!((stat.device_major(), stat.device_minor()), (1, 3));
+        // minor numbers above 255 use the upper bits, e.g. /dev/nvme0n1p300 (259:300)
+        let stat = stat_with_device(0x11_032c);
+        assert_eq!((stat.device_major(), stat.device_minor()), (259, 300));
+    }
+
+    #[test]
+    fn timestamps() {
+        let values = [
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            1_600_000_000,
+            0,
+            -86400,
+            0,
+            0,
+            0,
+        ]
+        .iter()
+        .map(|value| burp_encode_base64(*value))
+        .collect::<Vec<String>>()
+        .join(" ");
+        let stat = Stat::from_burp_string(values.as_bytes()).unwrap();
+        assert_eq!(
+            stat.accessed(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+        );
+        assert_eq!(stat.modified(), None);
+        assert_eq!(
+            stat.changed(),
+            Some(UNIX_EPOCH - Duration::from_secs(86400))
+        );
+    }
+
+    #[test]
+    fn permission_string() {
+        let stat_with_mode = |mode: u32| {
+            let mut values = vec!["A"; 16];
+            let encoded = burp_encode_base64(mode as i64);
+            values[2] = &encoded;
+            Stat::from_burp_string(values.join(" ").as_bytes()).unwrap()
+        };
+        let stat = stat_with_mode(0o100755);
+        assert_eq!(stat.permission_string(), "-rwxr-xr-x");
+        assert!(!stat.is_setuid() && !stat.is_setgid() && !stat.is_sticky());
+        let stat = stat_with_mode(0o104755);
+        assert_eq!(stat.permission_string(), "-rwsr-xr-x");
+        assert!(stat.is_setuid());
+        let stat = stat_with_mode(0o041777);
+        assert_eq!(stat.permission_string(), "drwxrwxrwt");
+        assert!(stat.is_sticky());
+        assert_eq!(stat_with_mode(0o102644).permission_string(), "-rw-r-Sr--");
+        assert_eq!(stat_with_mode(0o120777).file_type_char(), 'l');
+        assert_eq!(stat_with_mode(0o020620).permission_string(), "crw--w----");
+    }
+
     #[test]
     fn manifest_invalid_entry_type() {
         let mut entry = ManifestEntry::new();
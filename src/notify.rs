@@ -0,0 +1,125 @@
+//! Notifications for systemd services of `Type=notify`.
+//!
+//! Messages are sent to the socket in `NOTIFY_SOCKET`, see sd_notify(3). Without it, bdup was
+//! not started by systemd and all functions do nothing.
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time of the last watchdog notification
+static LAST_WATCHDOG: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Sends `state` to the service manager, e.g. "READY=1"
+pub fn notify(state: &str) -> io::Result<()> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => send_to(&path, state),
+        None => Ok(()),
+    }
+}
+
+/// Sends `state` to the socket at `path`. A leading '@' refers to an abstract socket.
+fn send_to(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Tells the service manager that startup is finished
+pub fn ready() {
+    if let Err(error) = notify("READY=1") {
+        log::warn!("Could not notify systemd about readiness: {}", error);
+    }
+}
+
+/// Watchdog timeout requested by the service manager for this process, see
+/// sd_watchdog_enabled(3)
+fn watchdog_timeout() -> Option<Duration> {
+    parse_watchdog_timeout(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+/// Timeout from the values of `WATCHDOG_USEC` and `WATCHDOG_PID`. A timeout for another
+/// process than `pid` does not apply.
+fn parse_watchdog_timeout(
+    usec: Option<&str>,
+    watchdog_pid: Option<&str>,
+    pid: u32,
+) -> Option<Duration> {
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid.parse::<u32>().ok()? != pid {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec?.parse().ok()?))
+}
+
+/// Keeps the service watchdog from expiring. Called for every finished transfer, but sends at
+/// most one notification per half of the watchdog timeout. A transfer that hangs stops the
+/// notifications, so the service manager notices it.
+pub fn watchdog() {
+    let Some(timeout) = watchdog_timeout() else {
+        return;
+    };
+    let mut last = LAST_WATCHDOG.lock().unwrap();
+    if last.is_some_and(|last| last.elapsed() < timeout / 2) {
+        return;
+    }
+    match notify("WATCHDOG=1") {
+        Ok(()) => *last = Some(Instant::now()),
+        Err(error) => log::warn!("Could not notify systemd watchdog: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn notify_socket() {
+        let path = env::temp_dir().join(format!("bdup-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        send_to(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn watchdog_timeout_pid() {
+        let usec = Some("60000000");
+        assert_eq!(
+            parse_watchdog_timeout(usec, None, 42),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_watchdog_timeout(usec, Some("42"), 42),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(parse_watchdog_timeout(usec, Some("1"), 42), None);
+        assert_eq!(parse_watchdog_timeout(usec, Some("x"), 42), None);
+        assert_eq!(parse_watchdog_timeout(None, Some("42"), 42), None);
+        assert_eq!(parse_watchdog_timeout(Some("soon"), None, 42), None);
+    }
+}
@@ -0,0 +1,112 @@
+//! Bandwidth limit for data transfers.
+//!
+//! All transfer threads share one token bucket. It holds at most one second worth of bytes, so
+//! short bursts are allowed after idle periods. A transfer that takes more bytes than available
+//! sleeps until the deficit is paid back, and transfers started in the meantime wait for it, too.
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket limiting the throughput of all transfers that share it
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_second as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Takes `bytes` from the bucket and returns how long to wait before they may be transferred
+    fn take(&self, bytes: u64) -> Duration {
+        let rate = self.bytes_per_second as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+        bucket.updated = now;
+        if bucket.tokens < 0.0 {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Blocks until `bytes` may be transferred
+    pub fn consume(&self, bytes: u64) {
+        let wait = self.take(bytes);
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Reader that consumes the bytes it reads from a `RateLimiter`
+pub struct LimitedReader<'a, R: io::Read> {
+    inner: R,
+    limiter: &'a RateLimiter,
+}
+
+impl<'a, R: io::Read> LimitedReader<'a, R> {
+    pub fn new(inner: R, limiter: &'a RateLimiter) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+impl<R: io::Read> io::Read for LimitedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.limiter.consume(len as u64);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn burst_then_wait() {
+        let limiter = RateLimiter::new(1000);
+        assert_eq!(limiter.take(600), Duration::ZERO);
+        assert_eq!(limiter.take(400), Duration::ZERO);
+        let wait = limiter.take(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+        // the deficit is shared by all users of the limiter
+        assert!(limiter.take(100) > wait);
+    }
+
+    #[test]
+    fn limited_read() {
+        let limiter = RateLimiter::new(10_000);
+        let data = vec![0_u8; 15_000];
+        let start = Instant::now();
+        let mut output = Vec::new();
+        LimitedReader::new(&data[..], &limiter)
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output.len(), data.len());
+        // the first 10000 bytes are a burst, the rest takes half a second
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+}
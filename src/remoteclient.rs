@@ -1,9 +1,14 @@
 use serde_derive::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::backup::Backup;
+use crate::backup::{Backup, VerifyOptions, VerifyReport, MANIFEST_FILES};
 use crate::client::Client;
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
@@ -13,28 +18,665 @@ struct FileListItem {
     pub name: String,
     #[serde(rename = "type")]
     pub filetype: String,
-    // pub mtime: String,
-    // pub size: Option<usize>,
+    #[serde(default)]
+    pub mtime: Option<String>,
+    /// Not reported for directories
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+/// A file list is either a plain array or, if the server paginates it, one page of it with the
+/// URL of the next page
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileList {
+    Items(Vec<FileListItem>),
+    Page {
+        items: Vec<FileListItem>,
+        #[serde(default)]
+        next: Option<String>,
+    },
+}
+
+/// Upper bound for the pages of one file list, in case a server keeps sending next links
+const MAX_LIST_PAGES: usize = 10000;
+
+/// URL of the `rel="next"` link in a `Link` header (RFC 8288)
+fn next_link(header: &str) -> Option<&str> {
+    header.split(',').find_map(|link| {
+        let mut parts = link.split(';');
+        let target = parts.next()?.trim().strip_prefix('<')?.strip_suffix('>')?;
+        parts
+            .any(|param| {
+                matches!(
+                    param.trim().split_once('='),
+                    Some(("rel", "next" | "\"next\""))
+                )
+            })
+            .then_some(target)
+    })
+}
+
+/// Connection settings for `RemoteClient::with_options`
+#[derive(Clone)]
+pub struct RemoteOptions {
+    /// User name for HTTP basic auth
+    pub username: Option<String>,
+    /// Password for HTTP basic auth
+    pub password: Option<String>,
+    /// Bearer token sent with each request
+    pub token: Option<String>,
+    /// PEM file with an additional root certificate, e.g. of an internal CA
+    pub ca_cert: Option<PathBuf>,
+    /// PEM file with the certificate used for TLS client authentication
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the (PKCS #8) private key of `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// Number of attempts for each request, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry. It doubles with each further attempt.
+    pub retry_delay: Duration,
+    /// Maximum time for establishing a connection
+    pub connect_timeout: Duration,
+    /// Maximum time to wait for a response or, while downloading, for the next data. Downloads
+    /// may take any time as long as data keeps arriving. No limit if unset.
+    pub read_timeout: Option<Duration>,
+    /// Directory for copies of downloaded manifests, which are reused as long as the server
+    /// reports the same mtime and size for them. Manifests are not cached if unset.
+    pub manifest_cache: Option<PathBuf>,
+    /// URL of the files of backups, `DEFAULT_URL_TEMPLATE` if unset. See `UrlTemplate` for the
+    /// placeholders.
+    pub url_template: Option<String>,
+}
+
+impl Default for RemoteOptions {
+    fn default() -> Self {
+        Self {
+            username: None,
+            password: None,
+            token: None,
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+            max_attempts: 3,
+            retry_delay: Duration::from_secs(1),
+            connect_timeout: Duration::from_secs(30),
+            read_timeout: Some(Duration::from_secs(60)),
+            manifest_cache: None,
+            url_template: None,
+        }
+    }
+}
+
+/// Layout of burp's status server: backups below the client URL, their files below them
+pub const DEFAULT_URL_TEMPLATE: &str = "{base}/{backup}/{file}";
+
+#[derive(Debug)]
+pub struct UrlTemplateError {
+    message: String,
+}
+
+impl fmt::Display for UrlTemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl Error for UrlTemplateError {}
+
+/// URL of a file of a backup, with the placeholders
+/// - `{base}`: the storage URL of the client
+/// - `{client}`: the client name
+/// - `{backup}`: the directory name of the backup, e.g. "0000001 2021-04-11 00:00:00"
+/// - `{id}`: the backup id
+/// - `{file}`: the path of the file in the backup, e.g. "manifest.gz"
+///
+/// The backups are listed at the part before the first backup placeholder, and the files of a
+/// backup at the part before `{file}`, both without trailing slashes.
+struct UrlTemplate {
+    template: String,
+}
+
+impl UrlTemplate {
+    fn parse(template: &str) -> Result<Self, UrlTemplateError> {
+        let file = template.find("{file}");
+        let backup = ["{backup}", "{id}"]
+            .iter()
+            .filter_map(|placeholder| template.find(placeholder))
+            .min();
+        match (backup, file) {
+            (Some(backup), Some(file)) if backup < file => Ok(Self {
+                template: template.to_owned(),
+            }),
+            _ => Err(UrlTemplateError {
+                message: format!(
+                    "URL template {:?} needs {{backup}} or {{id}} followed by {{file}}",
+                    template
+                ),
+            }),
+        }
+    }
+
+    /// Replaces the placeholders in `part` of the template
+    fn fill(part: &str, client: &str, backup: &Backup, file: &str) -> String {
+        part.replace("{base}", backup.base_url().trim_end_matches('/'))
+            .replace("{client}", client)
+            .replace("{backup}", &backup.dir_name())
+            .replace("{id}", &backup.id.to_string())
+            .replace("{file}", file)
+    }
+
+    /// URL of the list of backups of a client
+    fn listing_url(&self, base: &str, client: &str) -> String {
+        let end = ["{backup}", "{id}"]
+            .iter()
+            .filter_map(|placeholder| self.template.find(placeholder))
+            .min()
+            .unwrap_or(self.template.len());
+        self.template[..end]
+            .replace("{base}", base.trim_end_matches('/'))
+            .replace("{client}", client)
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    /// URL of the list of files of `backup`
+    fn backup_url(&self, client: &str, backup: &Backup) -> String {
+        let part = self.template.split("{file}").next().unwrap_or_default();
+        Self::fill(part, client, backup, "")
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    fn file_url(&self, client: &str, backup: &Backup, file: &str) -> String {
+        Self::fill(&self.template, client, backup, file)
+    }
+}
+
+#[derive(Debug)]
+pub struct TlsConfigError {
+    message: String,
+}
+
+impl fmt::Display for TlsConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl Error for TlsConfigError {}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, TlsConfigError> {
+    fs::read(path).map_err(|err| TlsConfigError {
+        message: format!("Could not read {}: {}", path.display(), err),
+    })
+}
+
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+/// Standard base64 encoding with padding, as used by the Content-MD5 header
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let value = chunk.iter().enumerate().fold(0u32, |value, (index, byte)| {
+            value | (u32::from(*byte) << (16 - 8 * index))
+        });
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(ALPHABET[((value >> (18 - 6 * index)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Checks a downloaded body against the Content-Length and Content-MD5 headers of its response
+/// when the end of the body is reached. A mismatch is reported as `io::ErrorKind::InvalidData`,
+/// so a truncated or corrupted download fails as transfer instead of leaving a damaged file.
+struct CheckedReader<R: io::Read> {
+    inner: R,
+    url: String,
+    received: u64,
+    expected_len: Option<u64>,
+    /// base64 encoded md5 digest
+    expected_md5: Option<String>,
+    md5: md5::Context,
+}
+
+impl<R: io::Read> CheckedReader<R> {
+    fn check(&self) -> io::Result<()> {
+        if let Some(expected) = self.expected_len {
+            if self.received != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Received {} of {} bytes of {}",
+                        self.received, expected, self.url
+                    ),
+                ));
+            }
+        }
+        if let Some(expected) = &self.expected_md5 {
+            let digest = base64_encode(&self.md5.clone().compute().0);
+            if digest != *expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Content-MD5 of {} is {}, but received data has {}",
+                        self.url, expected, digest
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: io::Read> io::Read for CheckedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            self.check()?;
+        }
+        self.received += len as u64;
+        if self.expected_md5.is_some() {
+            self.md5.consume(&buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+/// Wraps the body of `response` in a `CheckedReader`
+fn checked_body(url: &str, response: reqwest::blocking::Response) -> Box<dyn io::Read> {
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string())
+    };
+    let expected_len = header("content-length").and_then(|len| len.parse().ok());
+    let expected_md5 = header("content-md5");
+    Box::new(CheckedReader {
+        inner: response,
+        url: url.to_owned(),
+        received: 0,
+        expected_len,
+        expected_md5,
+        md5: md5::Context::new(),
+    })
+}
+
+/// HTTP client and the settings applied to each request
+struct Session {
+    http_client: reqwest::blocking::Client,
+    credentials: Option<Credentials>,
+    token: Option<String>,
+    max_attempts: u32,
+    retry_delay: Duration,
+}
+
+/// Returns whether a failed request might succeed if it is sent again
+fn is_transient(result: &reqwest::Result<reqwest::blocking::Response>) -> bool {
+    match result {
+        Ok(response) => matches!(
+            response.status(),
+            reqwest::StatusCode::BAD_GATEWAY
+                | reqwest::StatusCode::SERVICE_UNAVAILABLE
+                | reqwest::StatusCode::GATEWAY_TIMEOUT
+        ),
+        Err(err) => err.is_connect() || err.is_timeout() || err.is_request(),
+    }
+}
+
+/// Exponential backoff with up to 50% random jitter, so parallel transfers do not retry in lock
+/// step
+fn backoff(base: Duration, attempt: u32) -> Duration {
+    let delay = base.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    delay + delay.mul_f64(f64::from(nanos % 1000) / 2000.0)
+}
+
+impl Session {
+    fn get(&self, url: &str) -> reqwest::blocking::RequestBuilder {
+        self.request(reqwest::Method::GET, url)
+    }
+
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::blocking::RequestBuilder {
+        let mut request = self.http_client.request(method, url);
+        if let Some(credentials) = &self.credentials {
+            request = request.basic_auth(&credentials.username, Some(&credentials.password));
+        }
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        request
+    }
+
+    /// Starts downloading `url`. The body is not buffered, it is read from the connection while
+    /// the returned reader is consumed. A 404 response is reported as `io::ErrorKind::NotFound`.
+    fn fetch(&self, url: &str) -> Result<Box<dyn io::Read>, Box<dyn Error>> {
+        Ok(self.fetch_from(url, 0)?.0)
+    }
+
+    /// Like `fetch`, but requests the content from byte `offset` on. Returns the reader and the
+    /// offset it starts at, which is 0 if the server sent the whole content.
+    fn fetch_from(
+        &self,
+        url: &str,
+        offset: u64,
+    ) -> Result<(Box<dyn io::Read>, u64), Box<dyn Error>> {
+        let response = self.send_from(url, offset)?;
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => {
+                Err(Box::new(io::Error::new(io::ErrorKind::NotFound, url)))
+            }
+            // e.g. the partial download is already complete
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => self.fetch_from(url, 0),
+            reqwest::StatusCode::PARTIAL_CONTENT => Ok((checked_body(url, response), offset)),
+            _ => {
+                if offset > 0 {
+                    log::debug!("{} cannot be resumed, downloading all of it", url);
+                }
+                Ok((checked_body(url, response.error_for_status()?), 0))
+            }
+        }
+    }
+
+    /// Whether `url` exists, asked with a HEAD request so no content is transferred
+    fn exists(&self, url: &str) -> bool {
+        self.request(reqwest::Method::HEAD, url)
+            .send()
+            .is_ok_and(|response| response.status().is_success())
+    }
+
+    /// Fetches the file list at `url`, following the next links of paginated lists
+    fn file_list(&self, url: &str) -> Result<Vec<FileListItem>, Box<dyn Error>> {
+        let mut items = Vec::new();
+        let mut seen = HashSet::new();
+        let mut page_url = reqwest::Url::parse(url)?;
+        loop {
+            if !seen.insert(page_url.to_owned()) {
+                return Err(format!("File list {} links to page {} again", url, page_url).into());
+            }
+            if seen.len() > MAX_LIST_PAGES {
+                return Err(
+                    format!("File list {} has more than {} pages", url, MAX_LIST_PAGES).into(),
+                );
+            }
+            let response = self.send(page_url.as_str())?.error_for_status()?;
+            let link = response
+                .headers()
+                .get(reqwest::header::LINK)
+                .and_then(|value| value.to_str().ok())
+                .and_then(next_link)
+                .map(str::to_string);
+            let base = response.url().to_owned();
+            let next = match response.json::<FileList>()? {
+                FileList::Items(page) => {
+                    items.extend(page);
+                    link
+                }
+                FileList::Page { items: page, next } => {
+                    items.extend(page);
+                    next.or(link)
+                }
+            };
+            match next {
+                Some(next) => page_url = base.join(&next)?,
+                None => return Ok(items),
+            }
+        }
+    }
+
+    /// Sends a GET request, retrying on connection errors and 502/503/504 responses
+    fn send(&self, url: &str) -> reqwest::Result<reqwest::blocking::Response> {
+        self.send_from(url, 0)
+    }
+
+    /// Sends a GET request for the content of `url` from byte `offset` on, see `send`
+    fn send_from(&self, url: &str, offset: u64) -> reqwest::Result<reqwest::blocking::Response> {
+        let mut attempt = 1;
+        loop {
+            let mut request = self.get(url);
+            if offset > 0 {
+                request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+            }
+            let result = request.send();
+            if attempt >= self.max_attempts || !is_transient(&result) {
+                return result;
+            }
+            let delay = backoff(self.retry_delay, attempt);
+            let reason = match &result {
+                Ok(response) => response.status().to_string(),
+                Err(err) => err.to_string(),
+            };
+            log::warn!(
+                "Request to {} failed (attempt {}/{}): {}. Retrying in {:?}",
+                url,
+                attempt,
+                self.max_attempts,
+                reason,
+                delay
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+/// Downloaded manifests in `<dir>/<client>/<backup id>/manifest.gz`. Next to each manifest,
+/// `manifest.gz.stamp` holds the mtime and size the server reported for it.
+struct ManifestCache {
+    dir: PathBuf,
+}
+
+impl ManifestCache {
+    /// mtime and size of the manifest of the backup at `backup_url`, `None` if the server
+    /// reports neither
+    fn remote_stamp(session: &Session, backup_url: &str) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(session
+            .file_list(backup_url)?
+            .into_iter()
+            .find(|item| item.name == "manifest.gz")
+            .filter(|item| item.mtime.is_some() || item.size.is_some())
+            .map(|item| format!("{:?} {:?}", item.mtime, item.size)))
+    }
+
+    /// Opens the cached manifest of backup `id`, after downloading it if the cached copy is
+    /// missing or outdated
+    fn read(
+        &self,
+        session: &Session,
+        client: &str,
+        id: u64,
+        backup_url: &str,
+        manifest_url: &str,
+    ) -> Result<Box<dyn io::Read>, Box<dyn Error>> {
+        let dir = self.dir.join(client).join(id.to_string());
+        let path = dir.join("manifest.gz");
+        let stamp_path = dir.join("manifest.gz.stamp");
+        let stamp = Self::remote_stamp(session, backup_url)?;
+        if stamp.is_some() && fs::read_to_string(&stamp_path).ok() == stamp && path.exists() {
+            log::debug!("Using cached manifest {}", path.display());
+            return Ok(Box::new(fs::File::open(path)?));
+        }
+
+        log::debug!("Caching manifest of {} in {}", backup_url, path.display());
+        fs::create_dir_all(&dir)?;
+        // an interrupted download must not leave a valid stamp
+        if let Err(err) = fs::remove_file(&stamp_path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(Box::new(err));
+            }
+        }
+        let tmp_path = dir.join("manifest.gz.tmp");
+        io::copy(
+            &mut session.fetch(manifest_url)?,
+            &mut fs::File::create(&tmp_path)?,
+        )?;
+        fs::rename(&tmp_path, &path)?;
+        if let Some(stamp) = stamp {
+            fs::write(&stamp_path, stamp)?;
+        }
+        Ok(Box::new(fs::File::open(path)?))
+    }
 }
 
 pub struct RemoteClient {
     pub name: String,
     backups: HashMap<u64, Backup>,
-    http_client: reqwest::blocking::Client,
+    session: Session,
+    manifest_cache: Option<ManifestCache>,
+    url_template: UrlTemplate,
 }
 
 impl RemoteClient {
     pub fn new(name: &str) -> Self {
-        let client = reqwest::blocking::Client::builder()
+        Self::with_options(name, &RemoteOptions::default()).unwrap()
+    }
+
+    /// Creates a client which sends HTTP basic auth credentials with every request
+    pub fn with_credentials(name: &str, username: &str, password: &str) -> Self {
+        let options = RemoteOptions {
+            username: Some(username.to_owned()),
+            password: Some(password.to_owned()),
+            ..Default::default()
+        };
+        Self::with_options(name, &options).unwrap()
+    }
+
+    /// Creates a client which sends `token` as bearer token with every request
+    pub fn with_token(name: &str, token: &str) -> Self {
+        let options = RemoteOptions {
+            token: Some(token.to_owned()),
+            ..Default::default()
+        };
+        Self::with_options(name, &options).unwrap()
+    }
+
+    /// Creates a client with the given authentication and TLS settings. Fails if a certificate
+    /// or key cannot be loaded.
+    pub fn with_options(name: &str, options: &RemoteOptions) -> Result<Self, Box<dyn Error>> {
+        // the timeout of the blocking client applies to each read, not to whole downloads
+        let mut builder = reqwest::blocking::Client::builder()
             .user_agent(APP_USER_AGENT)
-            .build()
-            .unwrap();
-        Self {
+            .connect_timeout(options.connect_timeout)
+            .timeout(options.read_timeout);
+        if let Some(path) = &options.ca_cert {
+            let cert =
+                reqwest::Certificate::from_pem(&read_pem(path)?).map_err(|err| TlsConfigError {
+                    message: format!("Invalid CA certificate {}: {}", path.display(), err),
+                })?;
+            builder = builder.add_root_certificate(cert);
+        }
+        match (&options.client_cert, &options.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = reqwest::Identity::from_pkcs8_pem(&read_pem(cert)?, &read_pem(key)?)
+                    .map_err(|err| TlsConfigError {
+                        message: format!(
+                            "Invalid client certificate {} or key {}: {}",
+                            cert.display(),
+                            key.display(),
+                            err
+                        ),
+                    })?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => (),
+            _ => {
+                return Err(Box::new(TlsConfigError {
+                    message: "Client certificate and key have to be set together".to_string(),
+                }))
+            }
+        }
+
+        Ok(Self {
             name: name.to_owned(),
             backups: HashMap::new(),
-            http_client: client,
+            session: Session {
+                http_client: builder.build()?,
+                credentials: options.username.as_ref().map(|username| Credentials {
+                    username: username.to_owned(),
+                    password: options.password.to_owned().unwrap_or_default(),
+                }),
+                token: options.token.to_owned(),
+                max_attempts: options.max_attempts.max(1),
+                retry_delay: options.retry_delay,
+            },
+            manifest_cache: options.manifest_cache.as_ref().map(|dir| ManifestCache {
+                dir: dir.to_owned(),
+            }),
+            url_template: UrlTemplate::parse(
+                options
+                    .url_template
+                    .as_deref()
+                    .unwrap_or(DEFAULT_URL_TEMPLATE),
+            )?,
+        })
+    }
+
+    /// Which of `names` backup `id` contains, looked up in a single file list of the backup.
+    /// Servers without file lists of backups are asked for each file with a HEAD request.
+    fn existing_files<'a>(&self, id: u64, names: &[&'a str]) -> Vec<&'a str> {
+        let backup = self.backups.get(&id).unwrap();
+        let backup_url = self.url_template.backup_url(&self.name, backup);
+        match self.session.file_list(&backup_url) {
+            Ok(items) => names
+                .iter()
+                .filter(|name| items.iter().any(|item| item.name == **name))
+                .copied()
+                .collect(),
+            Err(error) => {
+                log::debug!("Could not list files of {}: {}", backup_url, error);
+                names
+                    .iter()
+                    .filter(|name| {
+                        self.session
+                            .exists(&self.url_template.file_url(&self.name, backup, name))
+                    })
+                    .copied()
+                    .collect()
+            }
         }
     }
+
+    /// Verifies backup `id` on the server. Data files are streamed and checksummed in memory,
+    /// nothing is written to the local disk.
+    pub fn verify_backup_with_options(
+        &self,
+        id: u64,
+        options: &VerifyOptions,
+    ) -> Result<VerifyReport, Box<dyn Error>> {
+        let backup = self.backups.get(&id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No backup with id {}", id))
+        })?;
+        // {file} is replaced last, so it is kept as placeholder for the files read while verifying
+        let url = self.url_template.file_url(&self.name, backup, "{file}");
+        Backup::new(backup.base_url(), &backup.dir_name(), false)?.verify_with_reader(
+            options,
+            &|name: &Path| {
+                self.session
+                    .fetch(&url.replace("{file}", &name.to_string_lossy()))
+            },
+        )
+    }
 }
 
 impl Client for RemoteClient {
@@ -51,16 +693,14 @@ impl Client for RemoteClient {
     }
 
     fn find_backups(&mut self, url: &str) -> Result<(), Box<dyn Error>> {
-        log::debug!("Fetching backup list from {:?}", url);
+        let listing_url = self.url_template.listing_url(url, &self.name);
+        log::debug!("Fetching backup list from {:?}", listing_url);
 
-        let filelist = self
-            .http_client
-            .get(url)
-            .send()?
-            .json::<Vec<FileListItem>>()?;
+        let filelist = self.session.file_list(&listing_url)?;
         for item in filelist.iter().filter(|item| item.filetype == "directory") {
             match Backup::new(url, &item.name, false) {
-                Ok(backup) => {
+                Ok(mut backup) => {
+                    backup.set_listing_metadata(item.size, item.mtime.to_owned());
                     self.backups.insert(backup.id, backup);
                 }
                 Err(error) => log::debug!(
@@ -70,19 +710,476 @@ impl Client for RemoteClient {
                 ),
             };
         }
-        // self.backups
-        //     .sort_unstable_by(|a, b| a.id.partial_cmp(&b.id).unwrap());
         Ok(())
     }
 
     fn read_file(&self, backup: u64, name: &str) -> Result<Box<dyn io::Read>, Box<dyn Error>> {
-        let url = format!(
-            "{}/{}",
-            self.backups.get(&backup).unwrap().path().to_string_lossy(),
-            name
+        let source = self.backups.get(&backup).unwrap();
+        let url = self.url_template.file_url(&self.name, source, name);
+        match &self.manifest_cache {
+            Some(cache) if name == "manifest.gz" => {
+                let backup_url = self.url_template.backup_url(&self.name, source);
+                cache.read(&self.session, &self.name, backup, &backup_url, &url)
+            }
+            _ => self.session.fetch(&url),
+        }
+    }
+
+    fn read_file_from(
+        &self,
+        backup: u64,
+        name: &str,
+        offset: u64,
+    ) -> Result<(Box<dyn io::Read>, u64), Box<dyn Error>> {
+        let source = self.backups.get(&backup).unwrap();
+        let url = self.url_template.file_url(&self.name, source, name);
+        self.session.fetch_from(&url, offset)
+    }
+
+    /// Looks for `name` in the file list of the backup, see `existing_files`
+    fn has_file(&self, id: u64, name: &str) -> bool {
+        !self.existing_files(id, &[name]).is_empty()
+    }
+
+    fn manifest_name(&self, id: u64) -> Option<&'static str> {
+        self.existing_files(id, &MANIFEST_FILES).first().copied()
+    }
+
+    /// Like the default implementation, but with a single file list of the backup
+    fn is_backup_finished(&self, backup: &Backup) -> bool {
+        let names = [MANIFEST_FILES[0], MANIFEST_FILES[1], ".bdup.partial"];
+        let found = self.existing_files(backup.id, &names);
+        MANIFEST_FILES.iter().any(|name| found.contains(name)) && !found.contains(&".bdup.partial")
+    }
+
+    /// Verifies with `threads` parallel downloads, see `verify_backup_with_options`
+    fn verify_backup(&self, id: u64, threads: usize) -> Result<VerifyReport, Box<dyn Error>> {
+        self.verify_backup_with_options(id, &VerifyOptions::new(threads))
+    }
+
+    fn load_checksums(&mut self, id: u64) -> Result<(), Box<dyn Error>> {
+        let (session, cache) = (&self.session, &self.manifest_cache);
+        let backup = self.backups.get_mut(&id).unwrap();
+        let backup_url = self.url_template.backup_url(&self.name, backup);
+        let manifest_url = self
+            .url_template
+            .file_url(&self.name, backup, "manifest.gz");
+        backup.load_checksums_with(|| match cache {
+            Some(cache) => cache.read(session, &self.name, id, &backup_url, &manifest_url),
+            None => session.fetch(&manifest_url),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+
+    /// Answers one HTTP request per given (status, body) response. Returns the server's base URL
+    /// and a receiver for the heads of the requests.
+    fn serve(responses: Vec<(&'static str, Vec<u8>)>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 4096];
+                let len = stream.read(&mut request).unwrap();
+                let _ = tx.send(String::from_utf8_lossy(&request[..len]).to_string());
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn serve_once(body: Vec<u8>) -> (String, Receiver<String>) {
+        serve(vec![("200 OK", body)])
+    }
+
+    #[test]
+    fn read_file_keeps_binary_data() {
+        let mut encoder = GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(b"binary content \xff\xfe").unwrap();
+        let blob = encoder.finish().unwrap();
+        let (url, _) = serve_once(blob.to_vec());
+        let mut client = RemoteClient::new("test");
+        client.backups.insert(
+            1,
+            Backup::new(&url, "0000001 2021-04-11 00:00:00", false).unwrap(),
+        );
+
+        let mut content = Vec::new();
+        client
+            .read_file(1, "manifest.gz")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, blob);
+    }
+
+    #[test]
+    fn read_streamed_manifest() {
+        let manifest = "t0003t/a\nf0004/t/a\nx00227:9a0364b9e99bb480dd25e1f0284c8555\n";
+        let mut encoder = GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(manifest.as_bytes()).unwrap();
+        let (url, _) = serve_once(encoder.finish().unwrap());
+        let mut client = RemoteClient::new("test");
+        client.backups.insert(
+            1,
+            Backup::new(&url, "0000001 2021-04-11 00:00:00", false).unwrap(),
         );
-        Ok(Box::new(io::Cursor::new(
-            self.http_client.get(url).send()?.text()?,
-        )))
+
+        let mut reader = io::BufReader::new(flate2::read::GzDecoder::new(
+            client.read_file(1, "manifest.gz").unwrap(),
+        ));
+        let mut paths = Vec::new();
+        crate::manifest::read_manifest(&mut reader, &mut |entry| {
+            paths.push(entry.path);
+            Ok(std::ops::ControlFlow::Continue(()))
+        })
+        .unwrap();
+        assert_eq!(paths, vec![std::path::PathBuf::from("/t/a")]);
+    }
+
+    #[test]
+    fn basic_auth_header() {
+        let (url, request) = serve_once(b"[]".to_vec());
+        let mut client = RemoteClient::with_credentials("test", "user", "secret");
+        client.find_backups(&url).unwrap();
+        assert!(request
+            .recv()
+            .unwrap()
+            .to_lowercase()
+            .contains("authorization: basic dxnlcjpzzwnyzxq="));
+    }
+
+    #[test]
+    fn bearer_token_header() {
+        let (url, request) = serve_once(b"[]".to_vec());
+        let mut client = RemoteClient::with_token("test", "abc.def.ghi");
+        client.find_backups(&url).unwrap();
+        assert!(request
+            .recv()
+            .unwrap()
+            .to_lowercase()
+            .contains("authorization: bearer abc.def.ghi"));
+    }
+
+    #[test]
+    fn retry_unavailable() {
+        let (url, requests) = serve(vec![
+            ("503 Service Unavailable", Vec::new()),
+            ("502 Bad Gateway", Vec::new()),
+            ("200 OK", b"[]".to_vec()),
+        ]);
+        let options = RemoteOptions {
+            retry_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let mut client = RemoteClient::with_options("test", &options).unwrap();
+        client.find_backups(&url).unwrap();
+        assert_eq!(requests.iter().count(), 3);
+    }
+
+    #[test]
+    fn retry_gives_up() {
+        let (url, _requests) = serve(vec![
+            ("503 Service Unavailable", Vec::new()),
+            ("503 Service Unavailable", Vec::new()),
+        ]);
+        let options = RemoteOptions {
+            max_attempts: 2,
+            retry_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let mut client = RemoteClient::with_options("test", &options).unwrap();
+        assert!(client.find_backups(&url).is_err());
+    }
+
+    #[test]
+    fn listing_metadata() {
+        let listing = r#"[
+            {"name": "0000001 2021-04-11 00:00:00", "type": "directory", "mtime": "Sun, 11 Apr 2021 00:00:00 GMT"},
+            {"name": "0000002 2021-04-12 00:00:00", "type": "directory", "mtime": "Mon, 12 Apr 2021 00:00:00 GMT", "size": 4096}
+        ]"#;
+        let (url, _requests) = serve_once(listing.as_bytes().to_vec());
+        let mut client = RemoteClient::new("test");
+        client.find_backups(&url).unwrap();
+        assert_eq!(client.backups[&1].listed_size(), None);
+        assert_eq!(
+            client.backups[&1].listed_mtime(),
+            Some("Sun, 11 Apr 2021 00:00:00 GMT")
+        );
+        assert_eq!(client.backups[&2].listed_size(), Some(4096));
+    }
+
+    #[test]
+    fn finished_from_file_list() {
+        let finished =
+            br#"[{"name": "manifest.gz", "type": "file"}, {"name": "data", "type": "directory"}]"#;
+        let plain =
+            br#"[{"name": "manifest", "type": "file"}, {"name": "data", "type": "directory"}]"#;
+        let partial = br#"[{"name": "manifest.gz", "type": "file"}, {"name": ".bdup.partial", "type": "file"}]"#;
+        let (url, requests) = serve(vec![
+            ("200 OK", finished.to_vec()),
+            ("200 OK", partial.to_vec()),
+            ("200 OK", plain.to_vec()),
+            ("404 Not Found", Vec::new()),
+            ("200 OK", Vec::new()),
+        ]);
+        let mut client = RemoteClient::new("test");
+        client.backups.insert(
+            1,
+            Backup::new(&url, "0000001 2021-04-11 00:00:00", false).unwrap(),
+        );
+        let backup = &client.backups[&1];
+        // a single file list per backup
+        assert!(client.is_backup_finished(backup));
+        assert!(!client.is_backup_finished(backup));
+        assert_eq!(client.manifest_name(1), Some("manifest"));
+        let listing = "GET /0000001%202021-04-11%2000:00:00 ";
+        for _ in 0..3 {
+            assert!(requests.recv().unwrap().starts_with(listing));
+        }
+        // without a file list, the file itself is asked for, without downloading it
+        assert!(client.has_file(1, "manifest.gz"));
+        let requests = requests.iter().skip(1).collect::<Vec<String>>();
+        assert!(requests[0].starts_with("HEAD /0000001%202021-04-11%2000:00:00/manifest.gz "));
+    }
+
+    #[test]
+    fn resume_with_range() {
+        let (url, requests) = serve(vec![
+            ("206 Partial Content", b"456789".to_vec()),
+            ("200 OK", b"0123456789".to_vec()),
+        ]);
+        let mut client = RemoteClient::new("test");
+        client.backups.insert(
+            1,
+            Backup::new(&url, "0000001 2021-04-11 00:00:00", false).unwrap(),
+        );
+
+        let (mut reader, offset) = client.read_file_from(1, "data/t/a", 4).unwrap();
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!((offset, content.as_slice()), (4, b"456789".as_slice()));
+        assert!(requests
+            .recv()
+            .unwrap()
+            .to_lowercase()
+            .contains("range: bytes=4-"));
+
+        // the server ignores the range
+        let (mut reader, offset) = client.read_file_from(1, "data/t/a", 4).unwrap();
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!((offset, content.as_slice()), (0, b"0123456789".as_slice()));
+    }
+
+    #[test]
+    fn cached_manifest() {
+        let mut encoder = GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(b"t0003t/a\n").unwrap();
+        let manifest = encoder.finish().unwrap();
+        let listing = br#"[{"name": "manifest.gz", "type": "file", "mtime": "Sun, 11 Apr 2021 00:00:00 GMT", "size": 42}]"#;
+        let (url, requests) = serve(vec![
+            ("200 OK", listing.to_vec()),
+            ("200 OK", manifest.to_vec()),
+            ("200 OK", listing.to_vec()),
+        ]);
+        let cache_dir =
+            std::env::temp_dir().join(format!("bdup-manifest-cache-{}", std::process::id()));
+        let options = RemoteOptions {
+            manifest_cache: Some(cache_dir.to_owned()),
+            ..Default::default()
+        };
+        let mut client = RemoteClient::with_options("test", &options).unwrap();
+        client.backups.insert(
+            1,
+            Backup::new(&url, "0000001 2021-04-11 00:00:00", false).unwrap(),
+        );
+
+        for _ in 0..2 {
+            let mut content = Vec::new();
+            client
+                .read_file(1, "manifest.gz")
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert_eq!(content, manifest);
+        }
+        // the second read only fetched the listing
+        assert_eq!(requests.iter().count(), 3);
+        assert!(cache_dir.join("test/1/manifest.gz").exists());
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn paginated_listing() {
+        let (url, requests) = serve(vec![
+            (
+                "200 OK",
+                br#"{"items": [{"name": "0000002 2021-04-12 00:00:00", "type": "directory"}], "next": "/?page=2"}"#.to_vec(),
+            ),
+            (
+                "200 OK",
+                br#"{"items": [{"name": "0000001 2021-04-11 00:00:00", "type": "directory"}]}"#
+                    .to_vec(),
+            ),
+        ]);
+        let mut client = RemoteClient::new("test");
+        client.find_backups(&url).unwrap();
+        let mut ids = client.backups.keys().copied().collect::<Vec<u64>>();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(requests.iter().nth(1).unwrap().starts_with("GET /?page=2 "));
+    }
+
+    #[test]
+    fn pagination_cycle() {
+        let page = br#"{"items": [], "next": "/"}"#;
+        let (url, _requests) = serve(vec![("200 OK", page.to_vec()), ("200 OK", page.to_vec())]);
+        let mut client = RemoteClient::new("test");
+        assert!(client.find_backups(&format!("{}/", url)).is_err());
+    }
+
+    #[test]
+    fn url_templates() {
+        let backup =
+            Backup::new("https://proxy/burp/", "0000001 2021-04-11 00:00:00", false).unwrap();
+        let default = UrlTemplate::parse(DEFAULT_URL_TEMPLATE).unwrap();
+        assert_eq!(
+            default.listing_url("https://proxy/burp/", "c"),
+            "https://proxy/burp"
+        );
+        assert_eq!(
+            default.file_url("c", &backup, "manifest.gz"),
+            "https://proxy/burp/0000001 2021-04-11 00:00:00/manifest.gz"
+        );
+
+        let custom = UrlTemplate::parse("{base}/clients/{client}/{id}/files/{file}?raw=1").unwrap();
+        assert_eq!(
+            custom.listing_url("https://proxy/burp", "c"),
+            "https://proxy/burp/clients/c"
+        );
+        assert_eq!(
+            custom.backup_url("c", &backup),
+            "https://proxy/burp/clients/c/1/files"
+        );
+        assert_eq!(
+            custom.file_url("c", &backup, "data/t/a"),
+            "https://proxy/burp/clients/c/1/files/data/t/a?raw=1"
+        );
+
+        assert!(UrlTemplate::parse("{base}/{backup}").is_err());
+        assert!(UrlTemplate::parse("{base}/{file}/{backup}").is_err());
+    }
+
+    #[test]
+    fn link_header() {
+        assert_eq!(
+            next_link(r#"<https://example.com/?page=1>; rel="prev", </c/?page=3>; rel="next""#),
+            Some("/c/?page=3")
+        );
+        assert_eq!(next_link("</c/?page=3>; rel=next"), Some("/c/?page=3"));
+        assert_eq!(next_link(r#"</c/?page=1>; rel="prev""#), None);
+    }
+
+    #[test]
+    fn read_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        // accepts the connection, but never answers
+        thread::spawn(move || {
+            let _connection = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(10));
+        });
+        let options = RemoteOptions {
+            max_attempts: 1,
+            read_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let mut client = RemoteClient::with_options("test", &options).unwrap();
+        let err = client.find_backups(&url).unwrap_err();
+        assert!(err
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_timeout));
+    }
+
+    #[test]
+    fn base64_digest() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(
+            base64_encode(&md5::compute(b"content").0),
+            "mgNkuembtIDdJeHwKEyFVQ=="
+        );
+    }
+
+    #[test]
+    fn checked_download() {
+        let read = |expected_len, expected_md5: Option<&str>| {
+            let mut reader = CheckedReader {
+                inner: &b"content"[..],
+                url: "http://server/file".to_string(),
+                received: 0,
+                expected_len,
+                expected_md5: expected_md5.map(str::to_string),
+                md5: md5::Context::new(),
+            };
+            io::copy(&mut reader, &mut io::sink())
+        };
+        assert_eq!(read(None, None).unwrap(), 7);
+        assert_eq!(read(Some(7), Some("mgNkuembtIDdJeHwKEyFVQ==")).unwrap(), 7);
+        assert_eq!(
+            read(Some(10), None).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            read(None, Some("1B2M2Y8AsgTpgAmY7PhCfg=="))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn backoff_grows() {
+        let base = Duration::from_millis(100);
+        assert!(backoff(base, 1) >= base && backoff(base, 1) < base * 2);
+        assert!(backoff(base, 3) >= base * 4 && backoff(base, 3) < base * 8);
+    }
+
+    #[test]
+    fn missing_ca_cert() {
+        let options = RemoteOptions {
+            ca_cert: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..Default::default()
+        };
+        let err = RemoteClient::with_options("test", &options).err().unwrap();
+        assert!(err.to_string().contains("/nonexistent/ca.pem"));
+    }
+
+    #[test]
+    fn credentials_debug_hides_password() {
+        let credentials = Credentials {
+            username: "user".to_string(),
+            password: "secret".to_string(),
+        };
+        assert!(!format!("{:?}", credentials).contains("secret"));
     }
 }
@@ -0,0 +1,573 @@
+//! Upload of backups to an S3-compatible object store, e.g. as off-site cold storage.
+//!
+//! Object keys mirror the layout of a cloned client directory: `<prefix>/<client>/<backup>/...`.
+//! Data files are uploaded first and the manifest last, so a backup is complete in the bucket
+//! once its manifest exists there.
+use s3::bucket::Bucket;
+use s3::creds::Credentials;
+use s3::region::Region;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use threadpool::ThreadPool;
+
+use crate::backup::{decompress_manifest, format_bytes, Backup, MANIFEST_FILES};
+use crate::client::{with_retries, Client, CloneOptions, CloneSummary};
+use crate::manifest;
+use crate::ratelimit::LimitedReader;
+
+/// Connection settings for `S3Client::new`
+#[derive(Clone, Debug)]
+pub struct S3Options {
+    /// Base URL of the object store, e.g. "https://s3.eu-central-1.amazonaws.com"
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Prepended to all object keys
+    pub prefix: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Address buckets as part of the path instead of the host name, as required by MinIO
+    pub path_style: bool,
+}
+
+#[derive(Debug)]
+pub struct S3Error {
+    message: String,
+}
+
+impl fmt::Display for S3Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl Error for S3Error {}
+
+/// Size of the ranges in which `S3Client::read_file` downloads objects
+const READ_RANGE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Uploads the backups of `source` to a bucket instead of cloning them to a local directory.
+///
+/// Backups are found and read by the source client. `read_file` reads the uploaded copies.
+/// Retention settings (`keep_last` and `retention`) are not applied to uploaded backups, the
+/// bucket's lifecycle rules can expire them instead.
+pub struct S3Client {
+    source: Box<dyn Client>,
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+impl S3Client {
+    pub fn new(source: Box<dyn Client>, options: &S3Options) -> Result<Self, Box<dyn Error>> {
+        let region = Region::Custom {
+            region: options.region.to_owned(),
+            endpoint: options.endpoint.to_owned(),
+        };
+        let credentials = Credentials::new(
+            Some(&options.access_key),
+            Some(&options.secret_key),
+            None,
+            None,
+            None,
+        )?;
+        let mut bucket = Bucket::new(&options.bucket, region, credentials)?;
+        if options.path_style {
+            bucket.set_path_style();
+        }
+        Ok(Self {
+            prefix: format!("{}/{}", options.prefix.trim_matches('/'), source.name())
+                .trim_start_matches('/')
+                .to_string(),
+            source,
+            bucket,
+        })
+    }
+
+    fn object_key(&self, backup: &Backup, name: &str) -> String {
+        format!("{}/{}/{}", self.prefix, backup.dir_name(), name)
+    }
+
+    fn is_uploaded(&self, backup: &Backup) -> bool {
+        MANIFEST_FILES.iter().any(|name| {
+            self.bucket
+                .head_object(self.object_key(backup, name))
+                .is_ok_and(|(_, status)| status == 200)
+        })
+    }
+
+    /// Streams file `name` of `backup` from the source client to the bucket
+    fn upload_file(
+        &self,
+        backup: &Backup,
+        name: &str,
+        options: &CloneOptions,
+    ) -> Result<u64, Box<dyn Error>> {
+        let key = self.object_key(backup, name);
+        let upload = || {
+            let input = self
+                .source
+                .read_file(backup.id, name)
+                .map_err(|error| io::Error::other(error.to_string()))?;
+            let mut counted = CountingReader {
+                inner: input,
+                count: 0,
+            };
+            let result = match options.bandwidth_limit.as_deref() {
+                Some(limiter) => self
+                    .bucket
+                    .put_object_stream(&mut LimitedReader::new(&mut counted, limiter), &key),
+                None => self.bucket.put_object_stream(&mut counted, &key),
+            };
+            match result.map_err(|error| io::Error::other(error.to_string()))? {
+                status if status >= 300 => Err(io::Error::other(format!("HTTP status {}", status))),
+                _ => Ok(counted.count),
+            }
+        };
+        Ok(with_retries(options.transfer_retries, &key, upload)?)
+    }
+
+    /// Uploads all files of `backup`. Returns the uploaded size.
+    fn upload_backup(
+        &self,
+        backup: &Backup,
+        options: &CloneOptions,
+    ) -> Result<u64, Box<dyn Error>> {
+        let manifest_name = self
+            .source
+            .manifest_name(backup.id)
+            .unwrap_or(MANIFEST_FILES[0]);
+        let mut data_files = Vec::new();
+        manifest::read_manifest(
+            &mut decompress_manifest(self.source.read_file(backup.id, manifest_name)?)?,
+            &mut |entry: manifest::ManifestEntry| {
+                if let Some(data) = entry
+                    .data
+                    .filter(|_| options.includes(&entry.path) && !options.excludes(&entry.path))
+                {
+                    data_files.push(PathBuf::from("data").join(data.path));
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+        if options.dry_run {
+            log::info!(
+                "Would upload backup {}/{} with {} data files",
+                self.name(),
+                backup.dir_name(),
+                data_files.len()
+            );
+            return Ok(0);
+        }
+        log::info!("Uploading backup {}/{}", self.name(), backup.dir_name());
+
+        let mut errors = 0;
+        let mut size = 0;
+        let names = Backup::metadata_files()
+            .iter()
+            .map(|name| name.to_string())
+            .chain(
+                data_files
+                    .iter()
+                    .map(|path| path.to_string_lossy().to_string()),
+            );
+        for name in names {
+            if options.is_cancelled() {
+                return Err(Box::new(S3Error {
+                    message: format!("Upload of backup {} interrupted", backup.dir_name()),
+                }));
+            }
+            match self.upload_file(backup, &name, options) {
+                Ok(uploaded) => size += uploaded,
+                Err(error) => {
+                    log::warn!("Could not upload {}: {:?}", name, error);
+                    errors += 1;
+                }
+            }
+        }
+        if errors > 0 {
+            // without its manifest, the backup is uploaded again by the next run
+            return Err(Box::new(S3Error {
+                message: format!(
+                    "{} files of backup {} could not be uploaded",
+                    errors,
+                    backup.dir_name()
+                ),
+            }));
+        }
+        size += self.upload_file(backup, manifest_name, options)?;
+        log::info!(
+            "Uploaded {} files ({})",
+            Backup::metadata_files().len() + data_files.len() + 1,
+            format_bytes(size)
+        );
+        Ok(size)
+    }
+}
+
+/// Reads an object in ranges of `READ_RANGE_SIZE`, so only one range is held in memory
+struct ObjectReader {
+    bucket: Box<Bucket>,
+    key: String,
+    size: u64,
+    /// Offset of the next range
+    offset: u64,
+    range: io::Cursor<Vec<u8>>,
+}
+
+impl io::Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.range.position() == self.range.get_ref().len() as u64 && self.offset < self.size {
+            let end = (self.offset + READ_RANGE_SIZE).min(self.size) - 1;
+            let response = self
+                .bucket
+                .get_object_range(&self.key, self.offset, Some(end))
+                .map_err(|error| io::Error::other(error.to_string()))?;
+            if !matches!(response.status_code(), 200 | 206) || response.bytes().is_empty() {
+                return Err(io::Error::other(format!(
+                    "Could not read {} from byte {}: HTTP status {}",
+                    self.key,
+                    self.offset,
+                    response.status_code()
+                )));
+            }
+            self.offset += response.bytes().len() as u64;
+            self.range = io::Cursor::new(response.bytes().to_vec());
+        }
+        io::Read::read(&mut self.range, buf)
+    }
+}
+
+/// Counts the bytes read from `inner`, because streaming uploads do not report their size
+struct CountingReader<R: io::Read> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: io::Read> io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
+
+impl Client for S3Client {
+    fn find_backups(&mut self, url: &str) -> Result<(), Box<dyn Error>> {
+        self.source.find_backups(url)
+    }
+
+    fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    fn backups(&self) -> &HashMap<u64, Backup> {
+        self.source.backups()
+    }
+
+    fn backups_mut(&mut self) -> &mut HashMap<u64, Backup> {
+        self.source.backups_mut()
+    }
+
+    fn read_file(&self, backup: u64, name: &str) -> Result<Box<dyn io::Read>, Box<dyn Error>> {
+        let key = self.object_key(self.backups().get(&backup).unwrap(), name);
+        let (head, status) = self.bucket.head_object(&key)?;
+        if status != 200 {
+            return Err(Box::new(S3Error {
+                message: format!("Could not read {}: HTTP status {}", key, status),
+            }));
+        }
+        Ok(Box::new(ObjectReader {
+            bucket: self.bucket.clone(),
+            size: head.content_length.unwrap_or_default().max(0) as u64,
+            key,
+            offset: 0,
+            range: io::Cursor::new(Vec::new()),
+        }))
+    }
+
+    /// Uploads all finished backups of the source that are not in the bucket yet. `dest` is
+    /// not used, the object keys are determined by the configured prefix and the client name.
+    /// Uploads stop between files when `options` are cancelled, the interrupted backup is
+    /// uploaded again by the next run.
+    fn clone_backups_to(
+        &self,
+        _dest: &Path,
+        _transfer_threads: &ThreadPool,
+        options: &CloneOptions,
+    ) -> Result<CloneSummary, Box<dyn Error>> {
+        if options.keep_last.is_some() || options.retention.is_some() {
+            log::warn!(
+                "Retention settings are not applied to backups of {} uploaded to S3",
+                self.name()
+            );
+        }
+        let mut summary = CloneSummary::default();
+        for backup in self.backups_sorted() {
+            if options.is_cancelled() {
+                log::warn!("Upload of {} interrupted", self.name());
+                return Ok(summary);
+            }
+            if !options.wants_backup(backup.id) || !options.in_date_range(backup) {
+                log::info!(
+                    "Skipping upload of {}, because it is filtered",
+                    backup.path().display()
+                );
+                summary.skipped += 1;
+                continue;
+            }
+            if !self.source.is_backup_finished(backup) {
+                log::info!(
+                    "Skipping upload of unfinished backup {}",
+                    backup.path().display()
+                );
+                summary.skipped += 1;
+                continue;
+            }
+            if self.is_uploaded(backup) {
+                log::debug!("Backup {} is already uploaded", backup.dir_name());
+                summary.skipped += 1;
+                continue;
+            }
+            match self.upload_backup(backup, options) {
+                Ok(size) => {
+                    summary.cloned += 1;
+                    summary.bytes_transferred += size;
+                }
+                Err(error) => {
+                    log::error!("Upload of {} failed: {}", backup.dir_name(), error);
+                    summary.failed += 1;
+                }
+            }
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::test::{create_source_backup, SpoolClient};
+    use crate::client::LocalClient;
+    use std::fs;
+    use std::io::{BufRead, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Minimal S3 endpoint with path-style addressing that keeps uploaded objects in memory and
+    /// answers range requests. Returns its URL and the objects by request path.
+    fn serve() -> (String, Objects) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let objects = Objects::default();
+        let stored = objects.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = io::BufReader::new(stream.unwrap());
+                let mut request_line = String::new();
+                stream.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                let mut range = None;
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).unwrap();
+                    match header.split_once(':') {
+                        Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+                            length = value.trim().parse().unwrap()
+                        }
+                        Some((name, value)) if name.eq_ignore_ascii_case("range") => {
+                            let (start, end) =
+                                value.trim()["bytes=".len()..].split_once('-').unwrap();
+                            range = Some((
+                                start.parse::<usize>().unwrap(),
+                                end.parse::<usize>().unwrap(),
+                            ));
+                        }
+                        Some(_) => (),
+                        None => break,
+                    }
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).unwrap();
+
+                let mut parts = request_line.split_whitespace();
+                let method = parts.next().unwrap();
+                let target = parts.next().unwrap();
+                let path = target.split('?').next().unwrap().to_string();
+                let mut objects = stored.lock().unwrap();
+                let (status, response) = match method {
+                    // small files are uploaded with a single PUT after aborting the multipart
+                    // upload
+                    "POST" => ("200 OK", b"<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key><UploadId>1</UploadId></InitiateMultipartUploadResult>".to_vec()),
+                    "DELETE" => ("204 No Content", Vec::new()),
+                    "PUT" => {
+                        objects.insert(path, body);
+                        ("200 OK", Vec::new())
+                    }
+                    _ => match (objects.get(&path), range) {
+                        (Some(object), Some((start, end))) => (
+                            "206 Partial Content",
+                            object[start..=end.min(object.len() - 1)].to_vec(),
+                        ),
+                        (Some(object), None) => ("200 OK", object.to_vec()),
+                        (None, _) => ("404 Not Found", Vec::new()),
+                    },
+                };
+                let mut stream = stream.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    response.len()
+                )
+                .unwrap();
+                if method != "HEAD" {
+                    stream.write_all(&response).unwrap();
+                }
+            }
+        });
+        (url, objects)
+    }
+
+    fn test_options(endpoint: String) -> S3Options {
+        S3Options {
+            endpoint,
+            region: "test".to_string(),
+            bucket: "bucket".to_string(),
+            prefix: "/dup/".to_string(),
+            access_key: "access".to_string(),
+            secret_key: "secret".to_string(),
+            path_style: true,
+        }
+    }
+
+    #[test]
+    fn upload_backups() {
+        let base = std::env::temp_dir().join(format!("bdup-s3-upload-{}", std::process::id()));
+        let backup = create_source_backup(
+            &base,
+            "0000001 2021-04-11 00:00:00",
+            &[("t/a", b"a"), ("t/b", b"b")],
+        );
+        let mut source = LocalClient::new("client");
+        source.find_backups(&base.to_string_lossy()).unwrap();
+
+        let (endpoint, objects) = serve();
+        let client = S3Client::new(Box::new(source), &test_options(endpoint)).unwrap();
+        let threads = ThreadPool::new(1);
+        let summary = client
+            .clone_backups_to(Path::new(""), &threads, &CloneOptions::default())
+            .unwrap();
+        assert_eq!(summary.cloned, 1);
+        let mut keys = objects.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        let prefix = "/bucket/dup/client/0000001%202021-04-11%2000%3A00%3A00";
+        assert_eq!(
+            keys,
+            [
+                "backup_stats",
+                "data/t/a",
+                "data/t/b",
+                "incexc",
+                "log.gz",
+                "manifest.gz",
+                "timestamp"
+            ]
+            .map(|name| format!("{}/{}", prefix, name))
+        );
+
+        let mut manifest = Vec::new();
+        client
+            .read_file(1, "manifest.gz")
+            .unwrap()
+            .read_to_end(&mut manifest)
+            .unwrap();
+        assert_eq!(
+            manifest,
+            fs::read(backup.path().join("manifest.gz")).unwrap()
+        );
+
+        // the manifest exists in the bucket, so the backup is not uploaded again
+        let summary = client
+            .clone_backups_to(Path::new(""), &threads, &CloneOptions::default())
+            .unwrap();
+        assert_eq!((summary.cloned, summary.skipped), (0, 1));
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn upload_remote_source() {
+        let base = std::env::temp_dir().join(format!("bdup-s3-remote-{}", std::process::id()));
+        create_source_backup(&base, "0000001 2021-04-11 00:00:00", &[("t/a", b"a")]);
+        let unfinished = create_source_backup(&base, "0000002 2021-04-12 00:00:00", &[]);
+        fs::write(unfinished.path().join(".bdup.partial"), b"").unwrap();
+        // the backups are not below the URL on the local filesystem
+        let mut source = SpoolClient::new(&base);
+        source.find_backups("spool://client").unwrap();
+
+        let (endpoint, objects) = serve();
+        let client = S3Client::new(Box::new(source), &test_options(endpoint)).unwrap();
+        let summary = client
+            .clone_backups_to(Path::new(""), &ThreadPool::new(1), &CloneOptions::default())
+            .unwrap();
+        assert_eq!((summary.cloned, summary.skipped), (1, 1));
+        let uploaded = objects.lock().unwrap();
+        assert!(uploaded
+            .keys()
+            .all(|key| key.contains("0000001%202021-04-11")));
+        assert!(uploaded.keys().any(|key| key.ends_with("/data/t/a")));
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn read_in_ranges() {
+        let base = std::env::temp_dir().join(format!("bdup-s3-ranges-{}", std::process::id()));
+        create_source_backup(&base, "0000001 2021-04-11 00:00:00", &[]);
+        let mut source = LocalClient::new("client");
+        source.find_backups(&base.to_string_lossy()).unwrap();
+        let (endpoint, objects) = serve();
+        let client = S3Client::new(Box::new(source), &test_options(endpoint)).unwrap();
+        let content = (0..READ_RANGE_SIZE + 10)
+            .map(|byte| byte as u8)
+            .collect::<Vec<u8>>();
+        objects.lock().unwrap().insert(
+            "/bucket/dup/client/0000001%202021-04-11%2000%3A00%3A00/data/t/a".to_string(),
+            content.clone(),
+        );
+
+        let mut read = Vec::new();
+        client
+            .read_file(1, "data/t/a")
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert!(read == content);
+        assert!(client.read_file(1, "data/t/b").is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn upload_interrupted() {
+        let base = std::env::temp_dir().join(format!("bdup-s3-cancel-{}", std::process::id()));
+        create_source_backup(&base, "0000001 2021-04-11 00:00:00", &[("t/a", b"a")]);
+        let mut source = LocalClient::new("client");
+        source.find_backups(&base.to_string_lossy()).unwrap();
+        let (endpoint, objects) = serve();
+        let client = S3Client::new(Box::new(source), &test_options(endpoint)).unwrap();
+        let options = CloneOptions::default();
+        options
+            .cancel
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        let summary = client
+            .clone_backups_to(Path::new(""), &ThreadPool::new(1), &options)
+            .unwrap();
+        assert_eq!(summary, CloneSummary::default());
+        assert!(objects.lock().unwrap().is_empty());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+//! Client for burp spools that are only reachable over SSH.
+//!
+//! Storage URLs look like `sftp://user@host[:port]/path/to/spool/client`. The host key must be
+//! listed in `~/.ssh/known_hosts`. Authentication uses the given private key file or, without
+//! one, the keys of a running ssh agent.
+use ssh2::{CheckResult, KnownHostFileKind, Session, Sftp};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+use crate::backup::Backup;
+use crate::client::Client;
+
+#[derive(Debug)]
+pub struct SftpError {
+    message: String,
+}
+
+impl fmt::Display for SftpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl Error for SftpError {}
+
+/// Parts of an `sftp://user@host[:port]/path` URL
+#[derive(Debug, PartialEq, Eq)]
+struct SftpUrl {
+    user: String,
+    host: String,
+    port: u16,
+    path: PathBuf,
+}
+
+impl SftpUrl {
+    fn parse(url: &str) -> Result<Self, SftpError> {
+        let invalid = |reason: &str| SftpError {
+            message: format!("Invalid sftp URL {:?}: {}", url, reason),
+        };
+        let rest = url
+            .strip_prefix("sftp://")
+            .ok_or_else(|| invalid("scheme is not sftp"))?;
+        let (authority, path) = rest
+            .find('/')
+            .map(|index| rest.split_at(index))
+            .ok_or_else(|| invalid("no path"))?;
+        let (user, host) = authority
+            .split_once('@')
+            .ok_or_else(|| invalid("no user name"))?;
+        let (host, port) = match host.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid("invalid port"))?),
+            None => (host, 22),
+        };
+        if user.is_empty() || host.is_empty() {
+            return Err(invalid("empty user or host name"));
+        }
+        Ok(Self {
+            user: user.to_string(),
+            host: host.to_string(),
+            port,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+pub struct SftpClient {
+    name: String,
+    backups: HashMap<u64, Backup>,
+    /// Kept alive for `sftp`, which uses its connection
+    _session: Session,
+    sftp: Sftp,
+}
+
+impl SftpClient {
+    /// Connects to the host of `url`. `key` is a private key file, without one the keys of the
+    /// ssh agent are tried.
+    pub fn connect(name: &str, url: &str, key: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let url = SftpUrl::parse(url)?;
+        log::debug!("Connecting to {}:{}", url.host, url.port);
+        let mut session = Session::new()?;
+        session.set_tcp_stream(TcpStream::connect((url.host.as_str(), url.port))?);
+        session.handshake()?;
+        Self::check_host_key(&session, &url)?;
+
+        match key {
+            Some(key) => session.userauth_pubkey_file(&url.user, None, key, None)?,
+            None => session.userauth_agent(&url.user)?,
+        }
+        if !session.authenticated() {
+            return Err(Box::new(SftpError {
+                message: format!("Authentication as {} failed", url.user),
+            }));
+        }
+
+        let sftp = session.sftp()?;
+        Ok(Self {
+            name: name.to_owned(),
+            backups: HashMap::new(),
+            _session: session,
+            sftp,
+        })
+    }
+
+    fn check_host_key(session: &Session, url: &SftpUrl) -> Result<(), Box<dyn Error>> {
+        let (key, _) = session.host_key().ok_or_else(|| SftpError {
+            message: format!("{} sent no host key", url.host),
+        })?;
+        let known_hosts_file = std::env::var_os("HOME")
+            .map(|home| Path::new(&home).join(".ssh/known_hosts"))
+            .ok_or_else(|| SftpError {
+                message: "Cannot locate known_hosts, HOME is not set".to_string(),
+            })?;
+        let mut known_hosts = session.known_hosts()?;
+        known_hosts.read_file(&known_hosts_file, KnownHostFileKind::OpenSSH)?;
+        match known_hosts.check_port(&url.host, url.port, key) {
+            CheckResult::Match => Ok(()),
+            result => Err(Box::new(SftpError {
+                message: format!(
+                    "Host key of {} is not trusted ({:?} in {})",
+                    url.host,
+                    result,
+                    known_hosts_file.display()
+                ),
+            })),
+        }
+    }
+}
+
+impl Client for SftpClient {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn backups(&self) -> &HashMap<u64, Backup> {
+        &self.backups
+    }
+
+    fn backups_mut(&mut self) -> &mut HashMap<u64, Backup> {
+        &mut self.backups
+    }
+
+    fn find_backups(&mut self, url: &str) -> Result<(), Box<dyn Error>> {
+        let path = SftpUrl::parse(url)?.path;
+        log::debug!("Listing backups in {}", path.display());
+
+        for (entry, stat) in self.sftp.readdir(&path)? {
+            if !stat.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().unwrap_or_default().to_string_lossy();
+            match Backup::new(url, &name, false) {
+                Ok(mut backup) => {
+                    backup.set_listing_metadata(None, stat.mtime.map(|mtime| mtime.to_string()));
+                    self.backups.insert(backup.id, backup);
+                }
+                Err(error) => log::debug!(
+                    "Skipping directory {:?} because it is not a backup: {:?}",
+                    name,
+                    error
+                ),
+            };
+        }
+        Ok(())
+    }
+
+    fn read_file(&self, backup: u64, name: &str) -> Result<Box<dyn io::Read>, Box<dyn Error>> {
+        let backup = self.backups.get(&backup).unwrap();
+        let path = SftpUrl::parse(&backup.path().to_string_lossy())?.path;
+        Ok(Box::new(self.sftp.open(path.join(name))?))
+    }
+
+    fn has_file(&self, id: u64, name: &str) -> bool {
+        let backup = self.backups.get(&id).unwrap();
+        SftpUrl::parse(&backup.path().to_string_lossy())
+            .is_ok_and(|url| self.sftp.stat(&url.path.join(name)).is_ok())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_url() {
+        assert_eq!(
+            SftpUrl::parse("sftp://burp@backup.example.com/var/spool/burp/client").unwrap(),
+            SftpUrl {
+                user: "burp".to_string(),
+                host: "backup.example.com".to_string(),
+                port: 22,
+                path: PathBuf::from("/var/spool/burp/client"),
+            }
+        );
+        assert_eq!(
+            SftpUrl::parse("sftp://burp@backup:2222/spool")
+                .unwrap()
+                .port,
+            2222
+        );
+        assert!(SftpUrl::parse("sftp://backup/spool").is_err());
+        assert!(SftpUrl::parse("sftp://burp@backup").is_err());
+        assert!(SftpUrl::parse("http://burp@backup/spool").is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! Volume operations for cloned backups.
+//!
+//! On btrfs, each backup is a subvolume and a new backup starts as snapshot of its base, so
+//! unchanged files share their extents. Other file systems use `ReflinkBackend`, which copies
+//! the base with `cp --reflink=always` where supported (e.g. xfs) and hardlinks its files
+//! otherwise. Hardlinked files are shared with the base, so they must be replaced instead of
+//! being written to, which is what `clone_from` does for changed files.
+
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+/// Storage backend selection for configuration files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    #[default]
+    Btrfs,
+    Reflink,
+}
+
+impl StorageKind {
+    /// Creates the selected backend. `btrfs` is the btrfs executable used by `BtrfsBackend`.
+    pub fn backend(&self, btrfs: &Path) -> Arc<dyn StorageBackend> {
+        match self {
+            StorageKind::Btrfs => Arc::new(BtrfsBackend::new(btrfs)),
+            StorageKind::Reflink => Arc::new(ReflinkBackend),
+        }
+    }
+}
+
+pub trait StorageBackend: fmt::Debug + Send + Sync {
+    /// Creates an empty volume at `path`
+    fn create(&self, path: &Path) -> Result<(), Box<dyn Error>>;
+    /// Creates a volume at `path` with the content of `base`
+    fn snapshot_from(&self, base: &Path, path: &Path) -> Result<(), Box<dyn Error>>;
+    /// Makes a finished backup read-only
+    fn finalize_readonly(&self, path: &Path) -> Result<(), Box<dyn Error>>;
+    /// Removes the volume at `path` with all its content
+    fn delete(&self, path: &Path) -> Result<(), Box<dyn Error>>;
+}
+
+#[derive(Debug)]
+pub struct StorageError {
+    message: String,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl Error for StorageError {}
+
+/// Runs `command` with stdin and stdout closed and fails if it does not exit successfully
+fn run(command: &mut Command) -> Result<(), Box<dyn Error>> {
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Box::new(StorageError {
+            message: format!("{:?} exited with {}", command, status),
+        }))
+    }
+}
+
+/// Backups are btrfs subvolumes, snapshots of their base and read-only when finished
+#[derive(Debug)]
+pub struct BtrfsBackend {
+    program: PathBuf,
+}
+
+impl BtrfsBackend {
+    /// Uses `program` as btrfs executable. It is looked up in `PATH` if it has no directory part.
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+        }
+    }
+}
+
+impl Default for BtrfsBackend {
+    fn default() -> Self {
+        Self::new("btrfs")
+    }
+}
+
+impl StorageBackend for BtrfsBackend {
+    fn create(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        run(Command::new(&self.program)
+            .arg("subvolume")
+            .arg("create")
+            .arg(path))
+    }
+
+    fn snapshot_from(&self, base: &Path, path: &Path) -> Result<(), Box<dyn Error>> {
+        run(Command::new(&self.program)
+            .arg("subvolume")
+            .arg("snapshot")
+            .arg(base)
+            .arg(path))
+    }
+
+    fn finalize_readonly(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        run(Command::new(&self.program)
+            .arg("property")
+            .arg("set")
+            .arg(path)
+            .arg("ro")
+            .arg("true"))
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        run(Command::new(&self.program)
+            .arg("subvolume")
+            .arg("delete")
+            .arg(path))
+    }
+}
+
+/// Backups are plain directories. Snapshots are reflink copies or hardlinks of the base's files,
+/// finished backups have all write permissions removed from their files.
+#[derive(Debug, Default)]
+pub struct ReflinkBackend;
+
+impl ReflinkBackend {
+    fn hardlink_tree(base: &Path, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::create_dir(path)?;
+        for entry in fs::read_dir(base)? {
+            let entry = entry?;
+            let target = path.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                Self::hardlink_tree(&entry.path(), &target)?;
+            } else if file_type.is_symlink() {
+                std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+            } else {
+                fs::hard_link(entry.path(), &target)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_write_permissions(path: &Path) -> Result<(), Box<dyn Error>> {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                Self::remove_write_permissions(&entry.path())?;
+            } else if file_type.is_file() {
+                let mut permissions = entry.metadata()?.permissions();
+                permissions.set_mode(permissions.mode() & !0o222);
+                fs::set_permissions(entry.path(), permissions)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for ReflinkBackend {
+    fn create(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::create_dir(path)?;
+        Ok(())
+    }
+
+    fn snapshot_from(&self, base: &Path, path: &Path) -> Result<(), Box<dyn Error>> {
+        let reflinked = Command::new("cp")
+            .arg("-a")
+            .arg("--reflink=always")
+            .arg(base)
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false);
+        if reflinked {
+            return Ok(());
+        }
+
+        log::debug!(
+            "Could not reflink {}, hardlinking its files instead",
+            base.display()
+        );
+        if path.exists() {
+            fs::remove_dir_all(path)?;
+        }
+        Self::hardlink_tree(base, path)
+    }
+
+    fn finalize_readonly(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        Self::remove_write_permissions(path)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::remove_dir_all(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reflink_snapshot() {
+        let dir = std::env::temp_dir().join(format!("bdup-storage-{}", std::process::id()));
+        let base = dir.join("base");
+        let snapshot = dir.join("snapshot");
+        fs::create_dir_all(base.join("data/t")).unwrap();
+        fs::write(base.join("data/t/a"), b"content").unwrap();
+        std::os::unix::fs::symlink("t/a", base.join("data/link")).unwrap();
+
+        let backend = ReflinkBackend;
+        backend.snapshot_from(&base, &snapshot).unwrap();
+        assert_eq!(fs::read(snapshot.join("data/t/a")).unwrap(), b"content");
+        assert_eq!(
+            fs::read_link(snapshot.join("data/link")).unwrap(),
+            Path::new("t/a")
+        );
+
+        backend.finalize_readonly(&snapshot).unwrap();
+        let mode = fs::metadata(snapshot.join("data/t/a"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o222, 0);
+
+        backend.delete(&snapshot).unwrap();
+        assert!(!snapshot.exists());
+        assert!(base.join("data/t/a").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn btrfs_program() {
+        let dir = std::env::temp_dir().join(format!("bdup-btrfs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("fake-btrfs");
+        let log = dir.join("calls");
+        fs::write(
+            &program,
+            format!("#!/bin/sh\necho \"$@\" >> {}\n", log.display()),
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let backend = BtrfsBackend::new(&program);
+        backend.create(Path::new("/backups/a")).unwrap();
+        backend.delete(Path::new("/backups/a")).unwrap();
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "subvolume create /backups/a\nsubvolume delete /backups/a\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
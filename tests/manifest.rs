@@ -1,12 +1,80 @@
 use burp::manifest;
+use std::ffi::OsString;
 use std::fs;
 use std::io;
-use std::path::PathBuf;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
 
 #[test]
 fn read_manifest_error() {
     let mut reader = io::Cursor::new("r0016unreadable stat string\n");
-    assert!(manifest::read_manifest(&mut reader, &mut |_| Ok(())).is_err());
+    assert!(manifest::read_manifest(&mut reader, &mut |_| Ok(ControlFlow::Continue(()))).is_err());
+}
+
+#[test]
+fn manifest_reader_iterator() {
+    let manifest = fs::File::open("tests/manifest").unwrap();
+    let directories = manifest::ManifestReader::new(io::BufReader::new(manifest))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type() == &manifest::FileType::Directory)
+        .map(|entry| entry.path)
+        .collect::<Vec<PathBuf>>();
+    assert_eq!(directories, vec![PathBuf::from("/some/directory/path")]);
+}
+
+#[test]
+fn manifest_reader_stops_after_error() {
+    let reader = io::Cursor::new("d0004/dir\nK0004oops\nd0004/dir\n");
+    let results = manifest::ManifestReader::new(reader).collect::<Vec<_>>();
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+}
+
+#[test]
+fn read_manifest_truncated_entry() {
+    let mut reader = io::Cursor::new("t0006t/file\nf0005/file\n");
+    let mut entries = 0;
+    let result = manifest::read_manifest(&mut reader, &mut |_| {
+        entries += 1;
+        Ok(ControlFlow::Continue(()))
+    });
+    assert!(result.is_err());
+    assert_eq!(entries, 0);
+}
+
+#[test]
+fn read_manifest_break() {
+    // the second entry is invalid, but never read
+    let mut reader = io::Cursor::new("d0004/dir\nK0004oops\n");
+    let mut entries = 0;
+    let result = manifest::read_manifest(&mut reader, &mut |_| {
+        entries += 1;
+        Ok(ControlFlow::Break(()))
+    });
+    assert!(result.is_ok());
+    assert_eq!(entries, 1);
+}
+
+#[test]
+fn read_manifest_xattrs() {
+    let mut reader =
+        io::Cursor::new("X0012user.comment\0hello\nA0009user::rw-\nd0009/some/dir\nd0006/other\n");
+    let mut entries: Vec<manifest::ManifestEntry> = Vec::new();
+    manifest::read_manifest(&mut reader, &mut |entry| {
+        entries.push(entry);
+        Ok(ControlFlow::Continue(()))
+    })
+    .unwrap();
+
+    assert_eq!(entries.len(), 2);
+    assert_eq!(
+        entries[0].xattrs,
+        vec![(OsString::from("user.comment"), b"hello".to_vec())]
+    );
+    assert_eq!(entries[0].acl, Some(b"user::rw-".to_vec()));
+    assert!(entries[1].xattrs.is_empty());
+    assert_eq!(entries[1].acl, None);
 }
 
 #[test]
@@ -17,27 +85,56 @@ fn read_manifest() {
     let mut entries: Vec<manifest::ManifestEntry> = Vec::new();
     let result = manifest::read_manifest(&mut reader, &mut |entry: manifest::ManifestEntry| {
         entries.push(entry);
-        Ok(())
+        Ok(ControlFlow::Continue(()))
     });
 
     assert!(result.is_ok());
     assert_eq!(entries.len(), 4); // regular file, hard link (ignored), directory, metadata, symlink
 
     let mut iter = entries.iter();
+    let entry = iter.next().unwrap();
+    assert_eq!(entry.path, PathBuf::from("/simple/file/path"));
+    assert_eq!(entry.file_type(), &manifest::FileType::Plain);
+    assert_eq!(entry.link_target(), None);
+
+    let entry = iter.next().unwrap();
+    assert_eq!(entry.path, PathBuf::from("/some/directory/path"));
+    assert_eq!(entry.file_type(), &manifest::FileType::Directory);
+
+    let entry = iter.next().unwrap();
+    assert_eq!(entry.path, PathBuf::from("/metadata/file/path"));
+    assert_eq!(entry.file_type(), &manifest::FileType::Metadata);
+
+    let entry = iter.next().unwrap();
     assert_eq!(
-        iter.next().unwrap().path,
-        PathBuf::from("/simple/file/path")
-    );
-    assert_eq!(
-        iter.next().unwrap().path,
-        PathBuf::from("/some/directory/path")
-    );
-    assert_eq!(
-        iter.next().unwrap().path,
-        PathBuf::from("/metadata/file/path")
+        entry.path,
+        PathBuf::from("/usr/lib/x86_64-linux-gnu/libEGL_mesa.so.0")
     );
+    assert_eq!(entry.file_type(), &manifest::FileType::SoftLink);
+    assert_eq!(entry.link_target(), Some(Path::new("libEGL_mesa.so.0.0.0")));
+}
+
+#[test]
+fn write_csv() {
+    let line = |kind: char, data: &str| format!("{}{:04x}{}\n", kind, data.len(), data);
+    let stat = [
+        0, 0, 0o100640, 1, 0, 0, 0, 2, 4096, 1, 0, 1600000000, 0, 0, 0, 0,
+    ]
+    .iter()
+    .map(|value| manifest::burp_encode_base64(*value))
+    .collect::<Vec<String>>()
+    .join(" ");
+    let manifest = line('d', "/dir")
+        + &line('r', &stat)
+        + &line('t', "t/a,\"b\"")
+        + &line('f', "/a,\"b\"")
+        + &line('x', "2:0123456789abcdef0123456789abcdef");
+    let mut out = Vec::new();
+    manifest::write_csv(&mut io::Cursor::new(manifest), &mut out).unwrap();
     assert_eq!(
-        iter.next().unwrap().path,
-        PathBuf::from("/usr/lib/x86_64-linux-gnu/libEGL_mesa.so.0")
+        String::from_utf8(out).unwrap(),
+        "path,type,size,md5,mode,mtime,permissions\n\
+         /dir,directory,,,,,\n\
+         \"/a,\"\"b\"\"\",file,2,0123456789abcdef0123456789abcdef,100640,1600000000,-rw-r-----\n"
     );
 }